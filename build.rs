@@ -1,5 +1,7 @@
 fn main() {
-    println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("xtensa") {
+        println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
+    }
 
     slint_build::compile_with_config(
         "ui/main.slint",
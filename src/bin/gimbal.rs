@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::f32::consts::PI;
+
+use esp_backtrace as _;
+use esp_hal::{
+    clock::CpuClock,
+    delay::Delay,
+    i2c::{self, master::I2c},
+    mcpwm::{McPwm, PeripheralClockConfig, operator::PwmPinConfig, timer::PwmWorkingMode},
+    time::Rate,
+    xtensa_lx_rt::entry,
+};
+use log::info;
+use playground::{
+    motor::{BLDC, MultiAxis, ThreePhasePwm},
+    planner::Planner,
+    sensor::VelocityEstimator,
+};
+use tap::Pipe;
+
+/// Two motors moving together, one on each MCPWM, e.g. the pan and tilt of a
+/// gimbal
+#[entry]
+fn main() -> ! {
+    esp_println::logger::init_logger_from_env();
+    esp_alloc::heap_allocator!(72 * 1024);
+    let peripherals: esp_hal::peripherals::Peripherals =
+        esp_hal::init(esp_hal::Config::default().with_cpu_clock(CpuClock::max()));
+
+    let clock_cfg = PeripheralClockConfig::with_frequency(Rate::from_mhz(16)).unwrap();
+    let timer_clock_cfg = clock_cfg
+        .timer_clock_with_frequency(799, PwmWorkingMode::Increase, Rate::from_khz(20))
+        .unwrap();
+
+    let mut mcpwm0 = McPwm::new(peripherals.MCPWM0, clock_cfg);
+    let pan_pwm = ThreePhasePwm {
+        a: mcpwm0
+            .operator0
+            .with_pin_a(peripherals.GPIO7, PwmPinConfig::UP_ACTIVE_HIGH),
+        b: mcpwm0
+            .operator1
+            .with_pin_a(peripherals.GPIO6, PwmPinConfig::UP_ACTIVE_HIGH),
+        c: mcpwm0
+            .operator2
+            .with_pin_a(peripherals.GPIO5, PwmPinConfig::UP_ACTIVE_HIGH),
+    };
+    mcpwm0.timer0.start(timer_clock_cfg);
+
+    let mut mcpwm1 = McPwm::new(peripherals.MCPWM1, clock_cfg);
+    let tilt_pwm = ThreePhasePwm {
+        a: mcpwm1
+            .operator0
+            .with_pin_a(peripherals.GPIO15, PwmPinConfig::UP_ACTIVE_HIGH),
+        b: mcpwm1
+            .operator1
+            .with_pin_a(peripherals.GPIO16, PwmPinConfig::UP_ACTIVE_HIGH),
+        c: mcpwm1
+            .operator2
+            .with_pin_a(peripherals.GPIO17, PwmPinConfig::UP_ACTIVE_HIGH),
+    };
+    mcpwm1.timer0.start(timer_clock_cfg);
+
+    let pan_encoder = I2c::new(peripherals.I2C0, i2c::master::Config::default())
+        .unwrap()
+        .with_scl(peripherals.GPIO12)
+        .with_sda(peripherals.GPIO11)
+        .pipe(as5600::As5600::new);
+    let tilt_encoder = I2c::new(peripherals.I2C1, i2c::master::Config::default())
+        .unwrap()
+        .with_scl(peripherals.GPIO9)
+        .with_sda(peripherals.GPIO8)
+        .pipe(as5600::As5600::new);
+
+    let mut delay = Delay::new();
+
+    let pan = BLDC::new::</* Pole Pair Number */ 7>(pan_pwm)
+        .with_voltage_power_supply(12.)
+        .with_sensor(pan_encoder)
+        .with_velocity_estimator(VelocityEstimator::Pll { bandwidth: 300. })
+        .aligned(&mut delay)
+        .unwrap()
+        .foc()
+        .with_planner(Planner::new(4. * PI, 8. * PI).with_jerk(80. * PI));
+
+    let tilt = BLDC::new::</* Pole Pair Number */ 7>(tilt_pwm)
+        .with_voltage_power_supply(12.)
+        .with_sensor(tilt_encoder)
+        .with_velocity_estimator(VelocityEstimator::Pll { bandwidth: 300. })
+        .aligned(&mut delay)
+        .unwrap()
+        .foc()
+        .with_planner(Planner::new(4. * PI, 8. * PI).with_jerk(80. * PI));
+
+    let home = [
+        pan.sensor().state().total_angle(),
+        tilt.sensor().state().total_angle(),
+    ];
+    let mut axes = MultiAxis::new((pan, tilt));
+
+    // Sweep between home and a corner, the tilt moves a quarter of the pan
+    // but both arrive together
+    let corner = [home[0] + PI, home[1] + PI / 4.];
+    let mut forward = true;
+    axes.move_to(&corner);
+
+    loop {
+        axes.tick().unwrap();
+
+        if axes.is_move_done() {
+            forward = !forward;
+            let duration = axes.move_to(if forward { &corner } else { &home });
+            info!("Moving for {duration:.2} s");
+        }
+    }
+}
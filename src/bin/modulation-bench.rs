@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::hint::black_box;
+
+use esp_backtrace as _;
+use esp_hal::{clock::CpuClock, time::Instant, xtensa_lx_rt::entry};
+use fixed::types::I16F16;
+use log::info;
+use playground::{motor::Modulation, util::sin_cos_lut};
+
+/// Calls per measurement, enough for the µs timer to resolve a few ns each
+const ITERATIONS: u32 = 100_000;
+
+/// Time per call of `f` fed with angles over a few turns, in ns
+fn bench(mut f: impl FnMut(I16F16)) -> f32 {
+    let step = I16F16::from_num(0.001);
+    let start = Instant::now();
+    let mut angle = I16F16::ZERO;
+    for _ in 0..ITERATIONS {
+        f(black_box(angle));
+        angle = (angle + step) % I16F16::TAU;
+    }
+    start.elapsed().as_micros() as f32 * 1e3 / ITERATIONS as f32
+}
+
+/// CPU cost of computing the phase voltages, with `cordic::sin_cos` against
+/// the sine table and for each modulation strategy
+#[entry]
+fn main() -> ! {
+    esp_println::logger::init_logger_from_env();
+    esp_alloc::heap_allocator!(72 * 1024);
+    let _peripherals: esp_hal::peripherals::Peripherals =
+        esp_hal::init(esp_hal::Config::default().with_cpu_clock(CpuClock::max()));
+
+    let overhead = bench(|angle| {
+        black_box(angle);
+    });
+    info!("Loop overhead: {overhead:.0} ns");
+
+    let cordic = bench(|angle| {
+        black_box(cordic::sin_cos(angle));
+    });
+    let table = bench(|angle| {
+        black_box(sin_cos_lut(angle));
+    });
+    info!("sin_cos: CORDIC {cordic:.0} ns, table {table:.0} ns");
+
+    let error = (0..4096)
+        .map(|i| I16F16::from_num(i) * I16F16::TAU / 4096)
+        .map(|angle| {
+            let (sin, cos) = cordic::sin_cos(angle);
+            let (sin_lut, cos_lut) = sin_cos_lut(angle);
+            (sin - sin_lut).abs().max((cos - cos_lut).abs())
+        })
+        .max()
+        .unwrap_or_default();
+    info!("Largest difference between the two: {error}");
+
+    let supply = I16F16::from_num(12);
+    let amplitude = I16F16::from_num(5);
+    for modulation in [
+        Modulation::Sine,
+        Modulation::SpaceVector,
+        Modulation::Discontinuous,
+        Modulation::Trapezoidal,
+    ] {
+        let with_cordic = bench(|angle| {
+            let (sin, cos) = cordic::sin_cos(angle);
+            black_box(modulation.phase_voltages(amplitude * cos, amplitude * sin, supply));
+        });
+        let with_table = bench(|angle| {
+            let (sin, cos) = sin_cos_lut(angle);
+            black_box(modulation.phase_voltages(amplitude * cos, amplitude * sin, supply));
+        });
+        info!("{modulation:?}: {with_cordic:.0} ns with CORDIC, {with_table:.0} ns with the table");
+    }
+
+    loop {}
+}
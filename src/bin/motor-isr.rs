@@ -0,0 +1,129 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use esp_backtrace as _;
+use esp_hal::{
+    Blocking,
+    clock::CpuClock,
+    delay::Delay,
+    handler,
+    i2c::{self, master::I2c},
+    mcpwm::{
+        McPwm, PeripheralClockConfig,
+        operator::{PwmPin, PwmPinConfig},
+        timer::PwmWorkingMode,
+    },
+    peripherals::MCPWM0,
+    time::{Duration, Rate},
+    timer::{PeriodicTimer, timg::TimerGroup},
+    xtensa_lx_rt::entry,
+};
+use log::info;
+use playground::{
+    motor::{BLDC, Command, CommandChannel, Foc, ThreePhasePwm},
+    sensor::VelocityEstimator,
+    util::Velocity,
+};
+use tap::Pipe;
+
+/// Period of the inner (voltage) loop. Reading the AS5600 over I2C takes
+/// about 100 µs, faster rates like 10 kHz need an SPI encoder.
+const LOOP_PERIOD: Duration = Duration::from_micros(250);
+
+/// Inner ticks per outer (velocity) tick, 1 kHz
+const OUTER_LOOP_DIVIDER: u16 = 4;
+
+type Phase<const OP: u8> = PwmPin<'static, MCPWM0, OP, true>;
+type Motor = Foc<BLDC<as5600::As5600<I2c<'static, Blocking>>, Phase<0>, Phase<1>, Phase<2>, 7>>;
+
+static MOTOR: Mutex<RefCell<Option<Motor>>> = Mutex::new(RefCell::new(None));
+static TIMER: Mutex<RefCell<Option<PeriodicTimer<'static, Blocking>>>> =
+    Mutex::new(RefCell::new(None));
+static COMMANDS: CommandChannel = CommandChannel::new();
+
+/// Runs the control loop at a fixed rate, whatever the main loop does
+#[handler]
+fn control_loop() {
+    critical_section::with(|cs| {
+        if let Some(timer) = TIMER.borrow_ref_mut(cs).as_mut() {
+            timer.clear_interrupt();
+        }
+        if let Some(motor) = MOTOR.borrow_ref_mut(cs).as_mut() {
+            motor.tick().unwrap();
+        }
+    });
+}
+
+#[entry]
+fn main() -> ! {
+    esp_println::logger::init_logger_from_env();
+    esp_alloc::heap_allocator!(72 * 1024);
+    let peripherals: esp_hal::peripherals::Peripherals =
+        esp_hal::init(esp_hal::Config::default().with_cpu_clock(CpuClock::max()));
+
+    let clock_cfg = PeripheralClockConfig::with_frequency(Rate::from_mhz(16)).unwrap();
+    let mut mcpwm = McPwm::new(peripherals.MCPWM0, clock_cfg);
+
+    let a = mcpwm
+        .operator0
+        .with_pin_a(peripherals.GPIO7, PwmPinConfig::UP_ACTIVE_HIGH);
+    let b = mcpwm
+        .operator1
+        .with_pin_a(peripherals.GPIO6, PwmPinConfig::UP_ACTIVE_HIGH);
+    let c = mcpwm
+        .operator2
+        .with_pin_a(peripherals.GPIO5, PwmPinConfig::UP_ACTIVE_HIGH);
+
+    let timer_clock_cfg = clock_cfg
+        .timer_clock_with_frequency(799, PwmWorkingMode::Increase, Rate::from_khz(20))
+        .unwrap();
+    mcpwm.timer0.start(timer_clock_cfg);
+
+    let encoder = I2c::new(peripherals.I2C0, i2c::master::Config::default())
+        .unwrap()
+        .with_scl(peripherals.GPIO12)
+        .with_sda(peripherals.GPIO11)
+        .pipe(as5600::As5600::new);
+
+    let motor = BLDC::new::</* Pole Pair Number */ 7>(ThreePhasePwm { a, b, c })
+        .with_voltage_power_supply(12.)
+        .with_sensor(encoder)
+        .with_velocity_estimator(VelocityEstimator::Pll { bandwidth: 300. })
+        .aligned(&mut Delay::new())
+        .unwrap()
+        .foc()
+        .with_outer_loop_divider(OUTER_LOOP_DIVIDER)
+        .with_commands(&COMMANDS)
+        .to_velocity(Velocity::ZERO);
+    critical_section::with(|cs| MOTOR.borrow_ref_mut(cs).replace(motor));
+
+    let timg0 = TimerGroup::new(peripherals.TIMG0);
+    let mut timer = PeriodicTimer::new(timg0.timer0);
+    timer.set_interrupt_handler(control_loop);
+    timer.start(LOOP_PERIOD).unwrap();
+    timer.listen();
+    critical_section::with(|cs| TIMER.borrow_ref_mut(cs).replace(timer));
+
+    // Logging here no longer disturbs the loop, only the setpoints go through
+    let delay = Delay::new();
+    let mut forward = true;
+    loop {
+        let target = if forward {
+            5 * Velocity::RPS
+        } else {
+            -5 * Velocity::RPS
+        };
+        COMMANDS.send(Command::Velocity(target));
+        forward = !forward;
+
+        for _ in 0..20 {
+            delay.delay_millis(100);
+            info!("{} ({:.2} rad)", COMMANDS.velocity(), COMMANDS.angle());
+        }
+    }
+}
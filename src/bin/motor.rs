@@ -7,20 +7,36 @@ use core::f32::consts::PI;
 
 use esp_backtrace as _;
 use esp_hal::{
+    analog::adc::{Adc, AdcCalCurve, AdcConfig, Attenuation},
     clock::CpuClock,
+    delay::Delay,
     gpio::{Input, Output, Pull},
     i2c::{self, master::I2c},
     mcpwm::{McPwm, PeripheralClockConfig, operator::PwmPinConfig, timer::PwmWorkingMode},
     time::{Instant, Rate},
     xtensa_lx_rt::entry,
 };
-use log::info;
+use esp_storage::FlashStorage;
+use log::{info, warn};
 use playground::{
-    motor::{BLDC, ThreePhasePwm},
-    util::Velocity,
+    motor::{
+        AdcBusVoltage, BLDC, BusLevel, BusMonitor, FlashRegion, Protection, ThreePhasePwm,
+        VoltageDivider,
+    },
+    sensor::VelocityEstimator,
+    util::{Duration, Velocity},
 };
 use tap::Pipe;
 
+/// Where the calibration is kept, the NVS partition of the default partition
+/// table, which nothing else in this firmware uses
+const CALIBRATION_OFFSET: u32 = 0x9000;
+
+/// Called from the control loop when the bus voltage crosses a threshold
+fn on_bus_level(level: BusLevel, voltage: f32) {
+    warn!("Bus {level:?} at {voltage:.1} V");
+}
+
 #[entry]
 fn main() -> ! {
     esp_println::logger::init_logger_from_env();
@@ -53,8 +69,9 @@ fn main() -> ! {
         .operator2
         .with_pin_a(in3, PwmPinConfig::UP_ACTIVE_HIGH);
 
+    // 20 kHz with the full 800 steps of duty the 16 MHz clock allows
     let timer_clock_cfg = clock_cfg
-        .timer_clock_with_frequency(99, PwmWorkingMode::Increase, Rate::from_khz(20))
+        .timer_clock_with_frequency(799, PwmWorkingMode::Increase, Rate::from_khz(20))
         .unwrap();
 
     mcpwm.timer0.start(timer_clock_cfg);
@@ -71,25 +88,83 @@ fn main() -> ! {
         .with_sda(peripherals.GPIO11)
         .pipe(as5600::As5600::new);
 
+    // Bus voltage through a 100 kΩ / 10 kΩ divider
+    let mut adc_config = AdcConfig::new();
+    let bus_pin = adc_config.enable_pin_with_cal::<_, AdcCalCurve<esp_hal::peripherals::ADC1>>(
+        peripherals.GPIO1,
+        Attenuation::_11dB,
+    );
+    let bus = AdcBusVoltage::new(
+        Adc::new(peripherals.ADC1, adc_config),
+        bus_pin,
+        VoltageDivider::new(100e3, 10e3),
+    );
+
     let mut drive = BLDC::new::</* Pole Pair Number */ 7>(ThreePhasePwm { a, b, c })
         .with_voltage_power_supply(12.)
-        .with_sensor(encoder)
         // .with_phase_inductance(0.86 * 1e-3) // 0.86mH
         // .with_phase_resistance(2.3) // 2.3 Ohm
         // .with_kv(220.) // 220 RPM/V
-        .aligned()
-        .unwrap()
+        // Or measure them with `identify` given a current sensor, they are stored with the
+        // calibration
+        .with_sensor(encoder)
+        // The AS5600 is 12 bit, a plain difference is mostly quantization noise at this
+        // loop rate
+        .with_velocity_estimator(VelocityEstimator::Pll { bandwidth: 300. })
+        // Give up on the encoder after 5 failed reads in a row, on a rotor that doesn't
+        // turn with more than half the supply applied, and outside of 9-16 V
+        .with_protection(
+            Protection::new()
+                .with_max_sensor_errors(5)
+                .with_stall(6., Velocity::RPS / 2., Duration::millis(500))
+                .with_voltage_range(9., 16.),
+        )
+        // Warn on a sagging or pumped up supply before the protection trips
+        .with_bus_monitor(
+            BusMonitor::new()
+                .with_thresholds(10.5, 14.)
+                .with_callback(on_bus_level),
+        );
+
+    // Skip alignment when a previous one was stored in flash
+    let mut storage = FlashRegion::new(FlashStorage::new(), CALIBRATION_OFFSET);
+    if let Err(e) = drive.load_calibration(&mut storage) {
+        info!("No usable calibration ({e:?}), aligning");
+        drive.align(&mut Delay::new()).unwrap();
+        drive.store_calibration(&mut storage).unwrap();
+    } else {
+        // Alignment checks the magnet, a stored calibration doesn't
+        match drive.sensor_mut().magnet_status() {
+            Ok(status) if !status.is_usable() => warn!("Magnet: {status:?}"),
+            Ok(_) => {}
+            Err(e) => warn!("Couldn't read the magnet status: {e:?}"),
+        }
+    }
+
+    let mut drive = drive
         .foc()
+        .with_bus_voltage_sensor(bus)
         // .open_loop(PI * 2.);
         // .to_torque(PI);
         // .to_angle(0.);
         .to_ratchet(5);
     // .to_velocity(10 * Velocity::RPS);
 
-    let mut last_sampling = (0., Instant::now());
+    // Measure the cogging once, it is stored along with the alignment
+    if drive.cogging_map().is_none() {
+        info!("No cogging map, sweeping the rotor");
+        let map = drive.calibrate_cogging(&mut Delay::new()).unwrap();
+        info!("Cogging up to {:.3}", map.peak());
+        drive.store_calibration(&mut storage).unwrap();
+    }
+
     let mut tick = 0;
     let mut button_cooldown_start = Instant::EPOCH;
 
+    // let mut telemetry = playground::telemetry::Telemetry::new(
+    //     esp_hal::usb_serial_jtag::UsbSerialJtag::new(peripherals.USB_DEVICE),
+    // );
+
     loop {
         // if button.is_high() && (now() - button_cooldown_start).to_millis() > 200 {
         //     log::info!("Button pressed, toggle motor");
@@ -102,15 +177,25 @@ fn main() -> ! {
         // }
 
         // if tick % 1000 == 0 {
-        //     let (last, time) = last_sampling;
-        //     let now = now();
-        //     let curr = drive.sensor().state().total_angle();
-        //     let vel = Velocity::rad(curr - last).per(now - time);
-        //     log::info!("{vel}");
-        //     last_sampling = (curr, now);
+        //     log::info!("{}", drive.sensor().state().velocity());
         // }
 
         tick += 1;
-        drive.tick().unwrap()
+        drive.tick().unwrap();
+
+        // The bridge is already in its safe state, also cut the driver so the motor
+        // coasts
+        if let Some(fault) = drive.fault() {
+            if en.is_set_high() {
+                warn!("Fault: {fault:?}, stopping");
+                en.set_low();
+            }
+        }
+
+        // Stream the control loop to the host, decode it with
+        // `tools/telemetry`. Writes block while nothing reads the port.
+        // if tick % 10 == 0 {
+        //     telemetry.send(&drive.telemetry()).unwrap();
+        // }
     }
 }
@@ -5,13 +5,19 @@
     const_float_methods,
     never_type
 )]
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(target_arch = "xtensa")]
 pub mod display;
+#[cfg(target_arch = "xtensa")]
 pub mod dma;
 pub mod motor;
 mod pid;
+pub mod planner;
 pub mod sensor;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod telemetry;
 pub mod util;
 
 use core::cell::Cell;
@@ -0,0 +1,66 @@
+use core::convert::Infallible;
+
+use esp_hal::{
+    Blocking,
+    analog::adc::{Adc, AdcCalScheme, AdcChannel, AdcPin, RegisterAccess},
+};
+
+use crate::motor::{BusVoltageSensor, VoltageDivider};
+
+/// Bus voltage through a resistor divider on an ADC pin
+///
+/// The pin should be enabled with a calibration scheme, so readings are in
+/// mV, and an attenuation that covers the divided voltage:
+///
+/// ```ignore
+/// let mut config = AdcConfig::new();
+/// let pin = config.enable_pin_with_cal::<_, AdcCalCurve<ADC1>>(
+///     peripherals.GPIO1,
+///     Attenuation::_11dB,
+/// );
+/// let adc = Adc::new(peripherals.ADC1, config);
+///
+/// // 100 kΩ / 10 kΩ, up to 33 V on the bus
+/// let bus = AdcBusVoltage::new(adc, pin, VoltageDivider::new(100e3, 10e3));
+/// let foc = motor.foc().with_bus_voltage_sensor(bus);
+/// ```
+pub struct AdcBusVoltage<'d, ADCI, PIN, CS> {
+    adc: Adc<'d, ADCI, Blocking>,
+    pin: AdcPin<PIN, ADCI, CS>,
+    divider: VoltageDivider,
+}
+
+impl<'d, ADCI, PIN, CS> AdcBusVoltage<'d, ADCI, PIN, CS> {
+    pub fn new(
+        adc: Adc<'d, ADCI, Blocking>,
+        pin: AdcPin<PIN, ADCI, CS>,
+        divider: VoltageDivider,
+    ) -> Self {
+        Self { adc, pin, divider }
+    }
+
+    pub fn into_inner(self) -> (Adc<'d, ADCI, Blocking>, AdcPin<PIN, ADCI, CS>) {
+        (self.adc, self.pin)
+    }
+}
+
+impl<'d, ADCI, PIN, CS> BusVoltageSensor for AdcBusVoltage<'d, ADCI, PIN, CS>
+where
+    ADCI: RegisterAccess + 'd,
+    PIN: AdcChannel,
+    CS: AdcCalScheme<ADCI>,
+{
+    type Error = Infallible;
+
+    /// Blocks for the conversion, a few µs
+    fn read_bus_voltage(&mut self) -> Result<f32, Self::Error> {
+        // A oneshot read only ever asks to be polled again
+        let millivolts = loop {
+            if let Ok(value) = self.adc.read_oneshot(&mut self.pin) {
+                break value;
+            }
+        };
+
+        Ok(self.divider.bus_voltage(millivolts as f32 * 1e-3))
+    }
+}
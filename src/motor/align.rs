@@ -0,0 +1,323 @@
+use core::f32::consts::PI;
+
+use embedded_hal::{delay::DelayNs, pwm::SetDutyCycle};
+
+use crate::{
+    motor::{BLDC, Modulation, normalize_angle},
+    sensor::{Direction, MagnetStatus, SensorHardware},
+    util::Clock,
+};
+
+/// Steps of each electrical revolution sweep
+const SWEEP_STEPS: u32 = 500;
+
+/// Electrical angle readings averaged into the zero electrical angle
+const ZERO_SAMPLES: u32 = 16;
+
+/// Smallest movement of a sweep, in rad, for the rotor to count as following
+/// the field
+const MIN_MOVEMENT: f32 = 2. * PI / 101.;
+
+/// Largest difference between what the rotor moved and what one electrical
+/// revolution should move it, in electrical rad
+const MAX_POLE_ERROR: f32 = 0.5;
+
+/// Largest difference between the forward and backward sweeps, in electrical
+/// rad. Looser than [`MAX_POLE_ERROR`] since the forward sweep starts from a
+/// reading at rest, which a hall sensor only knows to within a sector.
+const MAX_UNBALANCE: f32 = PI / 2.;
+
+#[derive(Debug)]
+pub enum AlignError<S, P> {
+    Sensor(S),
+    Pwm(P),
+
+    /// The sensor reports a problem with its magnet, readings can't be
+    /// trusted
+    Magnet(MagnetStatus),
+
+    /// The rotor did not follow the field, check the phases and the supply
+    NoMovement,
+
+    /// Forward and backward sweeps moved the rotor by different amounts (in
+    /// rad), the shaft is probably blocked or loaded
+    Unbalanced {
+        forward: f32,
+        backward: f32,
+    },
+
+    /// One electrical revolution moved the rotor by a different amount than
+    /// `POLE` pole pairs would
+    PolePairs {
+        expected: u8,
+        measured: f32,
+    },
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    H: SensorHardware,
+    A: SetDutyCycle,
+    B: SetDutyCycle<Error = A::Error>,
+    C: SetDutyCycle<Error = A::Error>,
+    K: Clock,
+{
+    /// Find the sensor direction and the zero electrical angle
+    ///
+    /// The rotor is dragged one electrical revolution forward and back with
+    /// the align voltage (see [`BLDC::with_align_voltage`]), which tells which
+    /// way the sensor counts and checks `POLE` against how far the rotor
+    /// moved. The rotor is then held on the d axis and the zero electrical
+    /// angle is averaged from several readings. The shaft must be free to
+    /// move, `delay` is used to wait for the rotor to settle.
+    ///
+    /// Nothing is driven if the sensor reports a bad magnet, see
+    /// [`SensorHardware::magnet_status`].
+    ///
+    /// An incremental encoder may find its index afterwards, the zero
+    /// electrical angle follows the jump of the angle (see
+    /// [`SensorState::is_referenced`]). It has to be found before storing the
+    /// calibration though.
+    ///
+    /// Runs with space vector modulation whatever [`BLDC::with_modulation`]
+    /// says, trapezoidal blocks would drag the rotor in 60° steps.
+    ///
+    /// [`SensorState::is_referenced`]: crate::sensor::SensorState::is_referenced
+    pub fn align<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), AlignError<H::Error, A::Error>> {
+        let modulation = core::mem::replace(&mut self.modulation, Modulation::SpaceVector);
+        let result = self.sweep_and_zero(delay);
+        self.modulation = modulation;
+
+        // Release the phases however it ends
+        let released = self.release();
+        result?;
+        released.map_err(AlignError::Pwm)
+    }
+
+    fn sweep_and_zero<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), AlignError<H::Error, A::Error>> {
+        let voltage = self.align_voltage;
+
+        let magnet = self.sensor.magnet_status().map_err(AlignError::Sensor)?;
+        if !magnet.is_usable() {
+            return Err(AlignError::Magnet(magnet));
+        }
+
+        self.zero_electrical_angle = None;
+        self.sensor.set_direction(Direction::Normal);
+        self.sensor.reset();
+
+        self.set_phase_voltage(0., voltage, 0.)
+            .map_err(AlignError::Pwm)?;
+        delay.delay_ms(500);
+        self.update_sensor().map_err(AlignError::Sensor)?;
+        let start = self.sensor.state().total_angle();
+
+        self.sweep(delay, voltage, 0., 2. * PI)?;
+        let mid = self.sensor.state().total_angle();
+
+        self.sweep(delay, voltage, 2. * PI, 0.)?;
+        let end = self.sensor.state().total_angle();
+
+        let forward = mid - start;
+        let backward = mid - end;
+
+        if forward.abs() < MIN_MOVEMENT || backward.abs() < MIN_MOVEMENT {
+            return Err(AlignError::NoMovement);
+        }
+
+        if (forward.abs() - backward.abs()).abs() * POLE as f32 > MAX_UNBALANCE {
+            return Err(AlignError::Unbalanced { forward, backward });
+        }
+
+        // Both ends of the backward sweep are reached by moving
+        if (backward.abs() * POLE as f32 - 2. * PI).abs() > MAX_POLE_ERROR {
+            return Err(AlignError::PolePairs {
+                expected: POLE,
+                measured: 2. * PI / backward.abs(),
+            });
+        }
+
+        self.sensor.set_direction(if backward > 0. {
+            Direction::Normal
+        } else {
+            Direction::Inverted
+        });
+        self.sensor.reset();
+
+        // Hold the rotor on the d axis, the electrical angle read there is the
+        // offset. Readings are averaged around the first one to avoid the wrap
+        // at 2π.
+        self.set_phase_voltage(0., voltage, 0.)
+            .map_err(AlignError::Pwm)?;
+        delay.delay_ms(700);
+
+        self.update_sensor().map_err(AlignError::Sensor)?;
+        let first = self.electrical_angle();
+        let mut offset = 0.;
+        for _ in 1..ZERO_SAMPLES {
+            delay.delay_ms(1);
+            self.update_sensor().map_err(AlignError::Sensor)?;
+            let diff = self.electrical_angle() - first;
+            offset += if diff > PI {
+                diff - 2. * PI
+            } else if diff < -PI {
+                diff + 2. * PI
+            } else {
+                diff
+            };
+        }
+        self.zero_electrical_angle = Some(normalize_angle(first + offset / ZERO_SAMPLES as f32));
+        self.sensor.reset();
+
+        Ok(())
+    }
+
+    pub fn aligned<D: DelayNs>(
+        mut self,
+        delay: &mut D,
+    ) -> Result<Self, AlignError<H::Error, A::Error>> {
+        self.align(delay)?;
+        Ok(self)
+    }
+
+    /// Drag the rotor from electrical angle `from` to `to`
+    fn sweep<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+        voltage: f32,
+        from: f32,
+        to: f32,
+    ) -> Result<(), AlignError<H::Error, A::Error>> {
+        for step in 1..=SWEEP_STEPS {
+            let angle = from + (to - from) * step as f32 / SWEEP_STEPS as f32;
+            self.set_phase_voltage(0., voltage, angle)
+                .map_err(AlignError::Pwm)?;
+            delay.delay_ms(2);
+            self.update_sensor().map_err(AlignError::Sensor)?;
+        }
+
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), A::Error> {
+        self.set_phase_voltage(0., 0., 0.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+    use crate::sim::{SimEncoder, SimParams, Simulator};
+
+    /// Simulated encoder reporting a given state of its magnet
+    struct Magnet<'a>(SimEncoder<'a>, MagnetStatus);
+
+    impl SensorHardware for Magnet<'_> {
+        type Error = Infallible;
+
+        fn read_angle(&mut self) -> Result<f32, Self::Error> {
+            self.0.read_angle()
+        }
+
+        fn magnet_status(&mut self) -> Result<MagnetStatus, Self::Error> {
+            Ok(self.1)
+        }
+    }
+
+    /// Simulated encoder mounted to count the other way
+    struct Inverted<'a>(SimEncoder<'a>);
+
+    impl SensorHardware for Inverted<'_> {
+        type Error = Infallible;
+
+        fn read_angle(&mut self) -> Result<f32, Self::Error> {
+            Ok(normalize_angle(-self.0.read_angle()?))
+        }
+    }
+
+    #[test]
+    fn direction() {
+        let sim = Simulator::new(SimParams::default());
+        let motor = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .aligned(&mut sim.clock())
+            .unwrap();
+        assert_eq!(motor.sensor().direction(), Direction::Normal);
+
+        let sim = Simulator::new(SimParams::default());
+        let motor = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(Inverted(sim.encoder()))
+            .aligned(&mut sim.clock())
+            .unwrap();
+        assert_eq!(motor.sensor().direction(), Direction::Inverted);
+    }
+
+    #[test]
+    fn pole_pairs() {
+        let sim = Simulator::new(SimParams::default());
+        let result = BLDC::new::<5>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .aligned(&mut sim.clock());
+        let Err(AlignError::PolePairs { expected, measured }) = result else {
+            panic!("{:?}", result.err());
+        };
+        assert_eq!(expected, 5);
+        assert!((measured - 7.).abs() < 0.5, "{measured}");
+    }
+
+    #[test]
+    fn stuck() {
+        // Far more friction than the align voltage can overcome
+        let sim = Simulator::new(SimParams {
+            coulomb_friction: 1.,
+            ..SimParams::default()
+        });
+        let result = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .aligned(&mut sim.clock());
+        assert!(
+            matches!(result, Err(AlignError::NoMovement)),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    #[test]
+    fn bad_magnet() {
+        for status in [MagnetStatus::TooWeak, MagnetStatus::TooStrong] {
+            let sim = Simulator::new(SimParams::default());
+            let result = BLDC::new::<7>(sim.pwm())
+                .with_clock(sim.clock())
+                .with_sensor(Magnet(sim.encoder(), status))
+                .aligned(&mut sim.clock());
+            assert!(
+                matches!(result, Err(AlignError::Magnet(magnet)) if magnet == status),
+                "{status:?}"
+            );
+
+            // Given up before driving anything
+            assert_eq!(sim.now().ticks(), 0);
+            assert_eq!(sim.angle(), 0.);
+        }
+
+        // Unknown is fine
+        let sim = Simulator::new(SimParams::default());
+        let motor = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(Magnet(sim.encoder(), MagnetStatus::Unknown))
+            .aligned(&mut sim.clock());
+        assert!(motor.is_ok());
+    }
+}
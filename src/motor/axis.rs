@@ -0,0 +1,338 @@
+use embedded_hal::pwm::SetDutyCycle;
+
+use crate::{
+    motor::{BLDC, BusVoltageSensor, CurrentSensor, Foc},
+    sensor::SensorHardware,
+    util::Clock,
+};
+
+/// One motor of a [`MultiAxis`] controller
+///
+/// Object safe, so axes with different pins, sensors or pole pairs can be
+/// driven together as long as their PWM errors match.
+pub trait Axis {
+    type Error;
+
+    /// Run one iteration of the control loop
+    fn tick(&mut self) -> Result<(), Self::Error>;
+
+    /// See [`Foc::move_duration`]
+    fn move_duration(&self, target: f32) -> Option<f32>;
+
+    /// See [`Foc::move_to`]
+    fn move_to(&mut self, target: f32, duration: f32);
+
+    /// See [`Foc::is_move_done`]
+    fn is_move_done(&self) -> bool;
+}
+
+impl<H, A, B, C, I, V, const POLE: u8, K> Axis for Foc<BLDC<H, A, B, C, POLE, K>, I, V>
+where
+    H: SensorHardware,
+    A: SetDutyCycle,
+    B: SetDutyCycle<Error = A::Error>,
+    C: SetDutyCycle<Error = A::Error>,
+    I: CurrentSensor,
+    V: BusVoltageSensor,
+    K: Clock,
+{
+    type Error = A::Error;
+
+    fn tick(&mut self) -> Result<(), Self::Error> {
+        Foc::tick(self)
+    }
+
+    fn move_duration(&self, target: f32) -> Option<f32> {
+        Foc::move_duration(self, target)
+    }
+
+    fn move_to(&mut self, target: f32, duration: f32) {
+        Foc::move_to(self, target, duration)
+    }
+
+    fn is_move_done(&self) -> bool {
+        Foc::is_move_done(self)
+    }
+}
+
+/// A set of axes, an array of the same kind or a tuple of up to 4 different
+/// ones
+pub trait Axes {
+    type Error;
+
+    /// Number of axes
+    const COUNT: usize;
+
+    fn axis(&self, index: usize) -> &dyn Axis<Error = Self::Error>;
+
+    fn axis_mut(&mut self, index: usize) -> &mut dyn Axis<Error = Self::Error>;
+}
+
+impl<X: Axis, const N: usize> Axes for [X; N] {
+    type Error = X::Error;
+
+    const COUNT: usize = N;
+
+    fn axis(&self, index: usize) -> &dyn Axis<Error = Self::Error> {
+        &self[index]
+    }
+
+    fn axis_mut(&mut self, index: usize) -> &mut dyn Axis<Error = Self::Error> {
+        &mut self[index]
+    }
+}
+
+macro_rules! axes {
+    ($count:literal; $first:ident $(, $rest:ident)*; $($index:tt),+) => {
+        impl<$first: Axis, $($rest: Axis<Error = $first::Error>),*> Axes for ($first, $($rest),*) {
+            type Error = $first::Error;
+
+            const COUNT: usize = $count;
+
+            fn axis(&self, index: usize) -> &dyn Axis<Error = Self::Error> {
+                match index {
+                    $($index => &self.$index,)+
+                    _ => panic!("No axis {index}"),
+                }
+            }
+
+            fn axis_mut(&mut self, index: usize) -> &mut dyn Axis<Error = Self::Error> {
+                match index {
+                    $($index => &mut self.$index,)+
+                    _ => panic!("No axis {index}"),
+                }
+            }
+        }
+    };
+}
+
+axes!(2; X0, X1; 0, 1);
+axes!(3; X0, X1, X2; 0, 1, 2);
+axes!(4; X0, X1, X2, X3; 0, 1, 2, 3);
+
+/// Several motors driven from one loop, e.g. two motors on `MCPWM0` and
+/// `MCPWM1`
+///
+/// [`MultiAxis::tick`] runs every axis once per call, starting with a
+/// different one each time so that none of them always waits for the
+/// others. When all of them don't fit in one loop period,
+/// [`MultiAxis::tick_next`] runs them one at a time instead.
+///
+/// [`MultiAxis::move_to`] moves all axes together, stretching the shorter
+/// moves so that every axis arrives at the same time. That needs a planner on
+/// each axis, see [`Foc::with_planner`].
+pub struct MultiAxis<T> {
+    axes: T,
+
+    /// Axis to tick first
+    next: usize,
+}
+
+impl<T: Axes> MultiAxis<T> {
+    pub fn new(axes: T) -> Self {
+        assert!(T::COUNT > 0);
+        Self { axes, next: 0 }
+    }
+
+    pub fn axes(&self) -> &T {
+        &self.axes
+    }
+
+    pub fn axes_mut(&mut self) -> &mut T {
+        &mut self.axes
+    }
+
+    pub fn into_inner(self) -> T {
+        self.axes
+    }
+
+    /// Tick every axis once, round robin
+    ///
+    /// Stops at the first error, the axes after it are ticked first next
+    /// time.
+    pub fn tick(&mut self) -> Result<(), T::Error> {
+        for _ in 0..T::COUNT {
+            self.tick_next()?;
+        }
+        self.next = (self.next + 1) % T::COUNT;
+
+        Ok(())
+    }
+
+    /// Tick the next axis only, returns its index
+    pub fn tick_next(&mut self) -> Result<usize, T::Error> {
+        let index = self.next;
+        self.next = (index + 1) % T::COUNT;
+        self.axes.axis_mut(index).tick()?;
+
+        Ok(index)
+    }
+
+    /// Move each axis to its target (total angle in rad), all arriving
+    /// together, returns how long the move takes in s
+    ///
+    /// The slowest axis runs at its planner's limits, the others are slowed
+    /// down to match. Axes without a planner jump to their target.
+    ///
+    /// # Panics
+    ///
+    /// If there isn't one target per axis.
+    pub fn move_to(&mut self, targets: &[f32]) -> f32 {
+        assert_eq!(targets.len(), T::COUNT);
+
+        let duration = targets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &target)| self.axes.axis(index).move_duration(target))
+            .fold(0., f32::max);
+
+        for (index, &target) in targets.iter().enumerate() {
+            self.axes.axis_mut(index).move_to(target, duration);
+        }
+
+        duration
+    }
+
+    /// Whether every axis is done with its last move
+    pub fn is_move_done(&self) -> bool {
+        (0..T::COUNT).all(|index| self.axes.axis(index).is_move_done())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{cell::RefCell, convert::Infallible};
+    use std::{vec, vec::Vec};
+
+    use super::*;
+    use crate::{
+        planner::Planner,
+        sim::{SimClock, SimEncoder, SimParams, SimPhase, Simulator},
+        util::Duration,
+    };
+
+    type SimFoc<'a> =
+        Foc<BLDC<SimEncoder<'a>, SimPhase<'a>, SimPhase<'a>, SimPhase<'a>, 7, SimClock<'a>>>;
+
+    /// Axis logging its index when ticked, failing while `fail` is set
+    struct Logged<'a> {
+        index: usize,
+        log: &'a RefCell<Vec<usize>>,
+        fail: bool,
+    }
+
+    impl Axis for Logged<'_> {
+        type Error = usize;
+
+        fn tick(&mut self) -> Result<(), Self::Error> {
+            self.log.borrow_mut().push(self.index);
+            match self.fail {
+                true => Err(self.index),
+                false => Ok(()),
+            }
+        }
+
+        fn move_duration(&self, _target: f32) -> Option<f32> {
+            None
+        }
+
+        fn move_to(&mut self, _target: f32, _duration: f32) {}
+
+        fn is_move_done(&self) -> bool {
+            true
+        }
+    }
+
+    fn logged(log: &RefCell<Vec<usize>>) -> MultiAxis<[Logged<'_>; 3]> {
+        MultiAxis::new([0, 1, 2].map(|index| Logged {
+            index,
+            log,
+            fail: false,
+        }))
+    }
+
+    #[test]
+    fn round_robin() {
+        let log = RefCell::new(Vec::new());
+        let mut axes = logged(&log);
+
+        for _ in 0..3 {
+            axes.tick().unwrap();
+        }
+        assert_eq!(log.take(), vec![0, 1, 2, 1, 2, 0, 2, 0, 1]);
+
+        for _ in 0..4 {
+            axes.tick_next().unwrap();
+        }
+        assert_eq!(log.take(), vec![0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn error() {
+        let log = RefCell::new(Vec::new());
+        let mut axes = logged(&log);
+
+        axes.axes_mut()[1].fail = true;
+        assert_eq!(axes.tick(), Err(1));
+        assert_eq!(log.take(), vec![0, 1]);
+
+        axes.axes_mut()[1].fail = false;
+        axes.tick().unwrap();
+        assert_eq!(log.take(), vec![2, 0, 1]);
+    }
+
+    /// Tick at 10 kHz for `millis` ms
+    fn run<T: Axes<Error = Infallible>>(sims: &[Simulator], axes: &mut MultiAxis<T>, millis: u64) {
+        for _ in 0..millis * 10 {
+            for sim in sims {
+                sim.advance(Duration::micros(100));
+            }
+            axes.tick().unwrap();
+        }
+    }
+
+    /// Total angle of each axis
+    fn angles<'a>((first, second): &(SimFoc<'a>, SimFoc<'a>)) -> [f32; 2] {
+        [first, second].map(|foc| foc.sensor().state().total_angle())
+    }
+
+    #[test]
+    fn move_together() {
+        let sims = [0, 1].map(|_| Simulator::new(SimParams::default()));
+        let [first, second] = sims.each_ref().map(|sim| {
+            BLDC::new::<7>(sim.pwm())
+                .with_clock(sim.clock())
+                .with_sensor(sim.encoder())
+                .aligned(&mut sim.clock())
+                .unwrap()
+                .foc()
+                .with_planner(Planner::new(20., 200.))
+        });
+        let mut axes = MultiAxis::new((first, second));
+        run(&sims, &mut axes, 10);
+
+        // The longer move sets the pace
+        let start = angles(axes.axes());
+        let targets = [start[0] + 2., start[1] - 4.];
+        let slowest = axes.axes().1.move_duration(targets[1]).unwrap();
+        assert!(axes.axes().0.move_duration(targets[0]).unwrap() < slowest);
+        let duration = axes.move_to(&targets);
+        assert_eq!(duration, slowest);
+
+        // Fraction of its move covered by each axis
+        let progress =
+            |angles: [f32; 2]| [0, 1].map(|i| (angles[i] - start[i]) / (targets[i] - start[i]));
+
+        // Halfway there together
+        run(&sims, &mut axes, (duration * 500.) as u64);
+        let [first, second] = progress(angles(axes.axes()));
+        assert!((first - second).abs() < 0.1, "{first} {second}");
+        assert!(!axes.is_move_done());
+
+        run(&sims, &mut axes, (duration * 500.) as u64 + 500);
+        let [first, second] = progress(angles(axes.axes()));
+        assert!((first - 1.).abs() < 0.02, "{first}");
+        assert!((second - 1.).abs() < 0.02, "{second}");
+        assert!(axes.is_move_done());
+    }
+}
@@ -0,0 +1,214 @@
+use core::{convert::Infallible, fmt::Debug};
+
+use crate::{
+    motor::BLDC,
+    util::{Clock, Instant},
+};
+
+/// Default time constant of the bus voltage filter, in s
+const DEFAULT_TIME_CONSTANT: f32 = 0.01;
+
+/// Bus voltage readings in V
+pub trait BusVoltageSensor {
+    type Error: Debug;
+
+    /// Whether the sensor measures anything at all
+    ///
+    /// `()` is used as a placeholder when the bus voltage isn't measured, in
+    /// which case the supply stays at [`BLDC::with_voltage_power_supply`].
+    const PRESENT: bool = true;
+
+    /// Reads the voltage of the DC bus, in V
+    fn read_bus_voltage(&mut self) -> Result<f32, Self::Error>;
+}
+
+impl BusVoltageSensor for () {
+    type Error = Infallible;
+
+    const PRESENT: bool = false;
+
+    fn read_bus_voltage(&mut self) -> Result<f32, Self::Error> {
+        Ok(0.)
+    }
+}
+
+/// Resistor divider bringing the bus voltage down to the range of an ADC pin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoltageDivider {
+    ratio: f32,
+}
+
+impl VoltageDivider {
+    /// `top` between the bus and the pin, `bottom` between the pin and
+    /// ground, in Ω
+    pub fn new(top: f32, bottom: f32) -> Self {
+        Self {
+            ratio: (top + bottom) / bottom,
+        }
+    }
+
+    /// Bus voltage for `volt` measured at the pin, both in V
+    pub fn bus_voltage(&self, volt: f32) -> f32 {
+        volt * self.ratio
+    }
+}
+
+/// Where the filtered bus voltage is relative to the thresholds of a
+/// [`BusMonitor`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BusLevel {
+    #[default]
+    Normal,
+
+    /// Below the under-voltage threshold, e.g. a sagging battery
+    Brownout,
+
+    /// Above the over-voltage threshold, e.g. energy braked back into the
+    /// bus
+    OverVoltage,
+}
+
+/// How [`BLDC`] filters and watches the reported bus voltage
+///
+/// The filtered voltage replaces [`BLDC::with_voltage_power_supply`] for the
+/// duty cycles, so the voltages applied to the motor stay right while the
+/// supply sags. Crossing a threshold calls the callback with the new level
+/// and the voltage, it doesn't stop the motor, which is what
+/// [`Protection::with_voltage_range`] is for. Thresholds between the two give
+/// the application a chance to shed load or save its state first.
+///
+/// [`Protection::with_voltage_range`]: super::Protection::with_voltage_range
+#[derive(Clone, Copy, Debug)]
+pub struct BusMonitor {
+    time_constant: f32,
+    thresholds: Option<(f32, f32)>,
+    callback: Option<fn(BusLevel, f32)>,
+}
+
+impl Default for BusMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusMonitor {
+    pub const fn new() -> Self {
+        Self {
+            time_constant: DEFAULT_TIME_CONSTANT,
+            thresholds: None,
+            callback: None,
+        }
+    }
+
+    /// Time constant of the first-order low-pass filter, in s
+    pub fn with_time_constant(mut self, time_constant: f32) -> Self {
+        self.time_constant = time_constant;
+        self
+    }
+
+    /// Brownout below `under` and over-voltage above `over` (in V)
+    pub fn with_thresholds(mut self, under: f32, over: f32) -> Self {
+        assert!(under < over);
+        self.thresholds = Some((under, over));
+        self
+    }
+
+    /// Called from the control loop when the level changes, keep it short
+    pub fn with_callback(mut self, callback: fn(BusLevel, f32)) -> Self {
+        self.callback = Some(callback);
+        self
+    }
+
+    fn level(&self, voltage: f32) -> BusLevel {
+        match self.thresholds {
+            Some((under, _)) if voltage < under => BusLevel::Brownout,
+            Some((_, over)) if voltage > over => BusLevel::OverVoltage,
+            _ => BusLevel::Normal,
+        }
+    }
+}
+
+/// State of the bus voltage filter
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Bus {
+    voltage: Option<f32>,
+
+    /// Last reading, unfiltered
+    raw: Option<f32>,
+
+    level: BusLevel,
+    updated: Option<Instant>,
+}
+
+impl Bus {
+    /// Filtered bus voltage, if any was reported
+    pub(crate) fn voltage(&self) -> Option<f32> {
+        self.voltage
+    }
+
+    /// Last reported bus voltage, unfiltered
+    pub(crate) fn raw(&self) -> Option<f32> {
+        self.raw
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K> {
+    pub fn with_bus_monitor(mut self, monitor: BusMonitor) -> Self {
+        self.bus_monitor = monitor;
+        self
+    }
+
+    pub fn bus_monitor(&self) -> BusMonitor {
+        self.bus_monitor
+    }
+
+    /// Filtered bus voltage in V, if any was reported
+    pub fn bus_voltage(&self) -> Option<f32> {
+        self.bus.voltage()
+    }
+
+    pub fn bus_level(&self) -> BusLevel {
+        self.bus.level
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K: Clock> BLDC<H, A, B, C, POLE, K> {
+    /// Feed a bus voltage reading, in V
+    ///
+    /// The filtered value becomes the supply the duty cycles are computed
+    /// against, and is checked against the [`BusMonitor`] thresholds. The
+    /// reading itself is checked against [`Protection::with_voltage_range`],
+    /// so a spike trips before the filter catches up. [`Foc`] does it on each
+    /// tick given a [`BusVoltageSensor`].
+    ///
+    /// [`Foc`]: super::Foc
+    /// [`Protection::with_voltage_range`]: super::Protection::with_voltage_range
+    pub fn report_bus_voltage(&mut self, voltage: f32) {
+        let now = self.sensor.now();
+        let filtered = match (self.bus.voltage, self.bus.updated) {
+            (Some(filtered), Some(updated)) => {
+                let dt = (now - updated).to_micros() as f32 * 1e-6;
+                let alpha = dt / (self.bus_monitor.time_constant + dt);
+                filtered + alpha * (voltage - filtered)
+            }
+            _ => voltage,
+        };
+        self.bus.voltage = Some(filtered);
+        self.bus.raw = Some(voltage);
+        self.bus.updated = Some(now);
+
+        // A dead reading would divide the duty cycles by zero, the protection
+        // deals with it
+        if filtered > 0. {
+            self.voltage_power_supply = filtered;
+        }
+
+        let level = self.bus_monitor.level(filtered);
+        if level != self.bus.level {
+            self.bus.level = level;
+            if let Some(callback) = self.bus_monitor.callback {
+                callback(level, filtered);
+            }
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use core::fmt::Debug;
+
+use embedded_storage::Storage;
+
+use crate::{
+    motor::{BLDC, CoggingMap, MotorParams},
+    sensor::{Direction, SensorHardware},
+    util::{Clock, crc32},
+};
+
+/// Marks the start of a calibration record, erased flash reads as `0xFF`
+const MAGIC: [u8; 4] = *b"FOCC";
+
+/// Bumped whenever the layout of the record changes
+const VERSION: u8 = 2;
+
+/// Set in the flags when the record holds a [`CoggingMap`]
+const FLAG_COGGING: u8 = 1;
+
+/// Where the CRC of a version 1 record starts, they are read as well
+const V1_CRC: usize = 25;
+
+/// Everything [`BLDC::align`], [`BLDC::identify`] and
+/// [`Foc::calibrate_cogging`] find out about a motor
+///
+/// Stored as a versioned, checksummed record in a [`CalibrationStorage`] so
+/// the driver can skip alignment on later boots, see
+/// [`BLDC::load_calibration`].
+///
+/// [`Foc::calibrate_cogging`]: super::Foc::calibrate_cogging
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    pub pole_pairs: u8,
+
+    /// Electrical angle of the d axis at sensor angle 0, in rad
+    pub zero_electrical_angle: f32,
+
+    pub direction: Direction,
+
+    /// Measured electrical parameters, if the motor was identified
+    pub params: Option<MotorParams>,
+
+    /// Cogging compensation, if it was measured
+    pub cogging: Option<CoggingMap>,
+}
+
+#[derive(Debug)]
+pub enum CalibrationError<E> {
+    Storage(E),
+
+    /// Nothing stored yet, or something other than a calibration record
+    Empty,
+
+    /// Stored by a firmware with a different record layout
+    Version(u8),
+
+    /// The record is corrupted
+    Checksum,
+
+    /// Stored for a motor with a different number of pole pairs than the
+    /// `POLE` the driver was built with
+    PolePairs {
+        expected: u8,
+        stored: u8,
+    },
+
+    /// The driver isn't aligned, there's nothing to store
+    NotAligned,
+
+    /// The sensor hasn't found its index yet, so the alignment only holds
+    /// until the next power on
+    NotReferenced,
+}
+
+impl Calibration {
+    /// Length of the serialized record
+    pub const SIZE: usize = 25 + CoggingMap::SIZE + 4;
+
+    /// Serialize into a record
+    ///
+    /// Layout, little endian: magic (4), version (1), pole pairs (1),
+    /// direction (1), flags (1), zero electrical angle (4), [`MotorParams`]
+    /// (13, zeroed when unknown), [`CoggingMap`] (2048, zeroed when unknown)
+    /// and the CRC-32 of everything before it (4).
+    ///
+    /// Version 1 records, without the flags and the cogging map, are still
+    /// read.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let crc_start = Self::SIZE - 4;
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        bytes[5] = self.pole_pairs;
+        bytes[6] = match self.direction {
+            Direction::Normal => 0,
+            Direction::Inverted => 1,
+        };
+        bytes[8..12].copy_from_slice(&self.zero_electrical_angle.to_le_bytes());
+        if let Some(params) = self.params {
+            bytes[12..25].copy_from_slice(&params.to_bytes());
+        }
+        if let Some(cogging) = &self.cogging {
+            bytes[7] |= FLAG_COGGING;
+            bytes[25..crc_start].copy_from_slice(&cogging.to_bytes());
+        }
+        let crc = crc32(&bytes[..crc_start]);
+        bytes[crc_start..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Reverse of [`Calibration::to_bytes`]
+    pub fn from_bytes<E>(bytes: &[u8; Self::SIZE]) -> Result<Self, CalibrationError<E>> {
+        if bytes[0..4] != MAGIC {
+            return Err(CalibrationError::Empty);
+        }
+
+        let crc_start = match bytes[4] {
+            1 => V1_CRC,
+            VERSION => Self::SIZE - 4,
+            version => return Err(CalibrationError::Version(version)),
+        };
+
+        let crc = &bytes[crc_start..crc_start + 4];
+        if crc32(&bytes[..crc_start]).to_le_bytes() != crc {
+            return Err(CalibrationError::Checksum);
+        }
+
+        let direction = match bytes[6] {
+            0 => Direction::Normal,
+            1 => Direction::Inverted,
+            _ => return Err(CalibrationError::Checksum),
+        };
+        let zero_electrical_angle = f32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+        if !zero_electrical_angle.is_finite() {
+            return Err(CalibrationError::Checksum);
+        }
+
+        let mut params = [0; MotorParams::SIZE];
+        params.copy_from_slice(&bytes[12..25]);
+
+        let cogging = match bytes[4] == VERSION && bytes[7] & FLAG_COGGING != 0 {
+            true => {
+                let mut cogging = [0; CoggingMap::SIZE];
+                cogging.copy_from_slice(&bytes[25..crc_start]);
+                Some(CoggingMap::from_bytes(&cogging))
+            }
+            false => None,
+        };
+
+        Ok(Self {
+            pole_pairs: bytes[5],
+            zero_electrical_angle,
+            direction,
+            params: MotorParams::from_bytes(&params),
+            cogging,
+        })
+    }
+
+    pub fn load<S: CalibrationStorage>(
+        storage: &mut S,
+    ) -> Result<Self, CalibrationError<S::Error>> {
+        let mut bytes = [0; Self::SIZE];
+        storage
+            .read(&mut bytes)
+            .map_err(CalibrationError::Storage)?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn store<S: CalibrationStorage>(
+        &self,
+        storage: &mut S,
+    ) -> Result<(), CalibrationError<S::Error>> {
+        storage
+            .write(&self.to_bytes())
+            .map_err(CalibrationError::Storage)
+    }
+}
+
+/// Where calibration records are kept
+///
+/// Implemented by [`FlashRegion`] for anything implementing
+/// [`embedded_storage::Storage`], e.g. the SPI flash of the chip through
+/// `esp-storage`, and by [`MemoryStorage`] for running on the host.
+pub trait CalibrationStorage {
+    type Error: Debug;
+
+    /// Fill `bytes` from the start of the storage
+    fn read(&mut self, bytes: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Write `bytes` at the start of the storage
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A region of flash starting at `offset`
+///
+/// The region must not be used by anything else, e.g. the NVS partition of
+/// the default partition table when the firmware doesn't use NVS itself.
+#[derive(Debug)]
+pub struct FlashRegion<S> {
+    storage: S,
+    offset: u32,
+}
+
+impl<S> FlashRegion<S> {
+    pub fn new(storage: S, offset: u32) -> Self {
+        Self { storage, offset }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+}
+
+impl<S: Storage<Error: Debug>> CalibrationStorage for FlashRegion<S> {
+    type Error = S::Error;
+
+    fn read(&mut self, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.storage.read(self.offset, bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.storage.write(self.offset, bytes)
+    }
+}
+
+/// Storage in RAM, starts out erased like fresh flash, one flash sector by
+/// default
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryStorage<const N: usize = 4096> {
+    bytes: [u8; N],
+}
+
+/// Access past the end of a [`MemoryStorage`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfBounds;
+
+impl<const N: usize> MemoryStorage<N> {
+    pub fn new() -> Self {
+        Self { bytes: [0xFF; N] }
+    }
+
+    pub fn bytes(&self) -> &[u8; N] {
+        &self.bytes
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8; N] {
+        &mut self.bytes
+    }
+}
+
+impl<const N: usize> Default for MemoryStorage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> CalibrationStorage for MemoryStorage<N> {
+    type Error = OutOfBounds;
+
+    fn read(&mut self, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        bytes.copy_from_slice(self.bytes.get(..bytes.len()).ok_or(OutOfBounds)?);
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.bytes
+            .get_mut(..bytes.len())
+            .ok_or(OutOfBounds)?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K> {
+    /// Calibration of the driver, `None` until it is aligned
+    pub fn calibration(&self) -> Option<Calibration> {
+        Some(Calibration {
+            pole_pairs: POLE,
+            zero_electrical_angle: self.zero_electrical_angle?,
+            direction: self.sensor.direction(),
+            params: self.params(),
+            cogging: self.cogging,
+        })
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    H: SensorHardware,
+{
+    /// Save the calibration, see [`BLDC::load_calibration`]
+    pub fn store_calibration<S: CalibrationStorage>(
+        &self,
+        storage: &mut S,
+    ) -> Result<(), CalibrationError<S::Error>> {
+        if !self.sensor.hardware().is_referenced() {
+            return Err(CalibrationError::NotReferenced);
+        }
+
+        self.calibration()
+            .ok_or(CalibrationError::NotAligned)?
+            .store(storage)
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    H: SensorHardware,
+    K: Clock,
+{
+    /// Use a previous calibration instead of aligning the motor
+    ///
+    /// `calibration` must come from a motor with `POLE` pole pairs and the
+    /// same sensor mounting. An incremental encoder must have found its index
+    /// already.
+    pub fn with_calibration(mut self, calibration: Calibration) -> Self {
+        self.set_calibration(calibration);
+        self
+    }
+
+    /// Restore the calibration from `storage`, which leaves the driver
+    /// aligned
+    ///
+    /// On error nothing changes, and the driver should be aligned (and the
+    /// result stored) instead.
+    pub fn load_calibration<S: CalibrationStorage>(
+        &mut self,
+        storage: &mut S,
+    ) -> Result<Calibration, CalibrationError<S::Error>> {
+        if !self.sensor.hardware().is_referenced() {
+            return Err(CalibrationError::NotReferenced);
+        }
+
+        let calibration = Calibration::load(storage)?;
+
+        if calibration.pole_pairs != POLE {
+            return Err(CalibrationError::PolePairs {
+                expected: POLE,
+                stored: calibration.pole_pairs,
+            });
+        }
+
+        self.set_calibration(calibration);
+
+        Ok(calibration)
+    }
+
+    fn set_calibration(&mut self, calibration: Calibration) {
+        debug_assert_eq!(calibration.pole_pairs, POLE);
+        self.zero_electrical_angle = Some(calibration.zero_electrical_angle);
+        self.sensor.set_direction(calibration.direction);
+        self.sensor.reset();
+        if let Some(params) = calibration.params {
+            self.set_params(params);
+        }
+        self.cogging = calibration.cogging;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        motor::COGGING_BINS,
+        sim::{SimParams, Simulator},
+    };
+
+    fn calibration() -> Calibration {
+        let mut torque = [0.; COGGING_BINS];
+        for (bin, torque) in torque.iter_mut().enumerate() {
+            *torque = (bin as f32 * 0.1).sin() * 0.05;
+        }
+
+        Calibration {
+            pole_pairs: 7,
+            zero_electrical_angle: 1.25,
+            direction: Direction::Inverted,
+            params: Some(MotorParams {
+                pole_pairs: 7,
+                phase_resistance: 2.3,
+                phase_inductance: 0.86e-3,
+                kv: 220.,
+            }),
+            cogging: Some(CoggingMap::new(&torque)),
+        }
+    }
+
+    fn stored(calibration: &Calibration) -> MemoryStorage {
+        let mut storage = MemoryStorage::new();
+        calibration.store(&mut storage).unwrap();
+        storage
+    }
+
+    #[test]
+    fn round_trip() {
+        let calibration = calibration();
+        let loaded = Calibration::load(&mut stored(&calibration)).unwrap();
+        assert_eq!(loaded, calibration);
+
+        let bare = Calibration {
+            params: None,
+            cogging: None,
+            ..calibration
+        };
+        let loaded = Calibration::load(&mut stored(&bare)).unwrap();
+        assert_eq!(loaded, bare);
+    }
+
+    #[test]
+    fn empty() {
+        let result = Calibration::load(&mut MemoryStorage::<4096>::new());
+        assert!(matches!(result, Err(CalibrationError::Empty)));
+
+        let mut storage = stored(&calibration());
+        storage.bytes_mut()[0] = b'X';
+        let result = Calibration::load(&mut storage);
+        assert!(matches!(result, Err(CalibrationError::Empty)));
+    }
+
+    #[test]
+    fn corrupted() {
+        for byte in [5, 10, 20, 1000, Calibration::SIZE - 1] {
+            let mut storage = stored(&calibration());
+            storage.bytes_mut()[byte] ^= 0x10;
+            let result = Calibration::load(&mut storage);
+            assert!(matches!(result, Err(CalibrationError::Checksum)), "{byte}");
+        }
+    }
+
+    #[test]
+    fn version() {
+        let mut storage = stored(&calibration());
+        storage.bytes_mut()[4] = VERSION + 1;
+        let result = Calibration::load(&mut storage);
+        assert!(matches!(result, Err(CalibrationError::Version(v)) if v == VERSION + 1));
+    }
+
+    #[test]
+    fn version_1() {
+        let calibration = calibration();
+        let mut storage = MemoryStorage::<4096>::new();
+        let bytes = &mut storage.bytes_mut()[..V1_CRC + 4];
+        bytes[..V1_CRC].copy_from_slice(&calibration.to_bytes()[..V1_CRC]);
+        bytes[4] = 1;
+        bytes[7] = 0;
+        let crc = crc32(&bytes[..V1_CRC]);
+        bytes[V1_CRC..].copy_from_slice(&crc.to_le_bytes());
+
+        let loaded = Calibration::load(&mut storage).unwrap();
+        assert_eq!(
+            loaded,
+            Calibration {
+                cogging: None,
+                ..calibration
+            }
+        );
+    }
+
+    #[test]
+    fn too_small() {
+        let result = calibration().store(&mut MemoryStorage::<64>::new());
+        assert!(matches!(
+            result,
+            Err(CalibrationError::Storage(OutOfBounds))
+        ));
+    }
+
+    #[test]
+    fn driver() {
+        let sim = Simulator::new(SimParams::default());
+        let mut motor = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder());
+        let mut storage = MemoryStorage::<4096>::new();
+
+        let result = motor.store_calibration(&mut storage);
+        assert!(matches!(result, Err(CalibrationError::NotAligned)));
+
+        // Another motor's calibration leaves the driver as it was
+        let other = Calibration {
+            pole_pairs: 11,
+            ..calibration()
+        };
+        other.store(&mut storage).unwrap();
+        let result = motor.load_calibration(&mut storage);
+        assert!(matches!(
+            result,
+            Err(CalibrationError::PolePairs {
+                expected: 7,
+                stored: 11
+            })
+        ));
+        assert_eq!(motor.zero_electrical_angle(), None);
+
+        calibration().store(&mut storage).unwrap();
+        assert_eq!(motor.load_calibration(&mut storage).unwrap(), calibration());
+        assert_eq!(motor.zero_electrical_angle(), Some(1.25));
+        assert_eq!(motor.sensor().direction(), Direction::Inverted);
+        assert_eq!(motor.calibration(), Some(calibration()));
+    }
+}
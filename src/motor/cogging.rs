@@ -0,0 +1,338 @@
+use core::f32::consts::TAU;
+
+use num_traits::Float;
+
+use crate::motor::{BLDC, normalize_angle};
+
+/// Angle bins of a [`CoggingMap`] over one mechanical revolution
+///
+/// A 12 slot 14 pole gimbal motor cogs 84 times per revolution, which leaves
+/// about 12 bins per period.
+pub const COGGING_BINS: usize = 1024;
+
+/// Resolution of the stored torques, in A or V
+const TORQUE_STEP: f32 = 1e-3;
+
+/// Holding torque against the rotor angle, fed forward by [`Foc`] to cancel
+/// the cogging of the motor
+///
+/// Measured by [`Foc::calibrate_cogging`], in the same unit as the torque
+/// targets at the time: Iq in A with a current sensor, q voltage in V without
+/// one. The angle is the mechanical one of the sensor, so the map only holds
+/// for the same sensor mounting, and is stored with the rest of the
+/// [`Calibration`].
+///
+/// [`Foc`]: super::Foc
+/// [`Foc::calibrate_cogging`]: super::Foc::calibrate_cogging
+/// [`Calibration`]: super::Calibration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoggingMap {
+    /// In steps of `TORQUE_STEP`
+    torque: [i16; COGGING_BINS],
+}
+
+#[derive(Debug)]
+pub enum CoggingError<S, P> {
+    Sensor(S),
+    Pwm(P),
+
+    /// The driver isn't aligned, it can't hold the rotor
+    NotAligned,
+
+    /// The sensor hasn't found its index yet, the angles would only hold
+    /// until the next power on
+    NotReferenced,
+
+    /// The rotor didn't follow the field over most of the revolution, the
+    /// shaft is probably blocked
+    NoMovement,
+}
+
+impl CoggingMap {
+    /// Length of the serialized map
+    pub const SIZE: usize = 2 * COGGING_BINS;
+
+    /// Map with `torque` at the middle of each bin
+    pub fn new(torque: &[f32; COGGING_BINS]) -> Self {
+        Self {
+            torque: torque.map(|torque| Float::round(torque / TORQUE_STEP) as i16),
+        }
+    }
+
+    /// Holding torque at the mechanical `angle` (in rad, any range),
+    /// interpolated between the bins
+    pub fn torque(&self, angle: f32) -> f32 {
+        // Each bin holds the average over its width, so the values sit in the
+        // middle of the bins. Shifted by a turn to stay positive.
+        let position = (normalize_angle(angle) / TAU + 1.) * COGGING_BINS as f32 - 0.5;
+        let fraction = position - (position as usize) as f32;
+        let index = position as usize % COGGING_BINS;
+
+        let from = self.torque[index] as f32;
+        let to = self.torque[(index + 1) % COGGING_BINS] as f32;
+        (from + (to - from) * fraction) * TORQUE_STEP
+    }
+
+    /// Largest holding torque over the revolution
+    pub fn peak(&self) -> f32 {
+        self.torque
+            .iter()
+            .map(|torque| torque.unsigned_abs())
+            .max()
+            .unwrap_or_default() as f32
+            * TORQUE_STEP
+    }
+
+    /// Serialize as little endian steps of 1 mA or 1 mV, one per bin
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        for (chunk, torque) in bytes.chunks_exact_mut(2).zip(self.torque) {
+            chunk.copy_from_slice(&torque.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reverse of [`CoggingMap::to_bytes`]
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut torque = [0; COGGING_BINS];
+        for (torque, chunk) in torque.iter_mut().zip(bytes.chunks_exact(2)) {
+            *torque = i16::from_le_bytes([chunk[0], chunk[1]]);
+        }
+        Self { torque }
+    }
+}
+
+/// Torque samples of a cogging sweep, averaged per bin and direction
+///
+/// Averaging both directions cancels the dry friction, which opposes the
+/// motion and flips sign with it.
+pub(crate) struct CoggingSweep {
+    sum: [[f32; COGGING_BINS]; 2],
+    count: [[u16; COGGING_BINS]; 2],
+}
+
+impl CoggingSweep {
+    pub(crate) fn new() -> Self {
+        Self {
+            sum: [[0.; COGGING_BINS]; 2],
+            count: [[0; COGGING_BINS]; 2],
+        }
+    }
+
+    /// Add the `torque` held at the mechanical `angle` (in rad, within a
+    /// revolution), moving `forward` or back
+    pub(crate) fn record(&mut self, forward: bool, angle: f32, torque: f32) {
+        let bin = (normalize_angle(angle) / TAU * COGGING_BINS as f32) as usize % COGGING_BINS;
+        let direction = forward as usize;
+        if self.count[direction][bin] < u16::MAX {
+            self.sum[direction][bin] += torque;
+            self.count[direction][bin] += 1;
+        }
+    }
+
+    /// Map of the averages, bins missed in either direction (a sensor
+    /// coarser than the bins) are interpolated from their neighbours
+    ///
+    /// `None` if more than half of them were missed.
+    pub(crate) fn finish(&self) -> Option<CoggingMap> {
+        let mean = |bin: usize| match (self.count[0][bin], self.count[1][bin]) {
+            (0, _) | (_, 0) => None,
+            (backward, forward) => {
+                Some((self.sum[0][bin] / backward as f32 + self.sum[1][bin] / forward as f32) / 2.)
+            }
+        };
+
+        let measured = (0..COGGING_BINS).filter(|&bin| mean(bin).is_some()).count();
+        if measured < COGGING_BINS / 2 {
+            return None;
+        }
+
+        let mut torque = [0.; COGGING_BINS];
+        for (bin, torque) in torque.iter_mut().enumerate() {
+            *torque = match mean(bin) {
+                Some(mean) => mean,
+                None => {
+                    let (before, from) = (1..COGGING_BINS).find_map(|i| {
+                        mean((bin + COGGING_BINS - i) % COGGING_BINS).map(|m| (i, m))
+                    })?;
+                    let (after, to) = (1..COGGING_BINS)
+                        .find_map(|i| mean((bin + i) % COGGING_BINS).map(|m| (i, m)))?;
+                    from + (to - from) * before as f32 / (before + after) as f32
+                }
+            };
+        }
+
+        Some(CoggingMap::new(&torque))
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K> {
+    /// Cancel the cogging with a previously measured map, see
+    /// [`Foc::calibrate_cogging`]
+    ///
+    /// [`Foc::calibrate_cogging`]: super::Foc::calibrate_cogging
+    pub fn with_cogging_map(mut self, map: CoggingMap) -> Self {
+        self.cogging = Some(map);
+        self
+    }
+
+    pub fn cogging_map(&self) -> Option<&CoggingMap> {
+        self.cogging.as_ref()
+    }
+
+    pub fn set_cogging_map(&mut self, map: Option<CoggingMap>) {
+        self.cogging = map;
+    }
+
+    /// Feed-forward torque at the mechanical `angle`, zero without a map
+    pub(crate) fn cogging_torque(&self, angle: f32) -> f32 {
+        self.cogging
+            .as_ref()
+            .map(|map| map.torque(angle))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimParams, Simulator};
+
+    /// Angle in the middle of `bin`
+    fn middle(bin: usize) -> f32 {
+        (bin as f32 + 0.5) * TAU / COGGING_BINS as f32
+    }
+
+    /// Amplitude of the sine and cosine of `periods` per revolution in `map`
+    fn fourier(map: &CoggingMap, periods: f32) -> (f32, f32) {
+        (0..COGGING_BINS).fold((0., 0.), |(sin, cos), bin| {
+            let angle = middle(bin);
+            let torque = map.torque(angle) * 2. / COGGING_BINS as f32;
+            (
+                sin + torque * Float::sin(periods * angle),
+                cos + torque * Float::cos(periods * angle),
+            )
+        })
+    }
+
+    #[test]
+    fn calibrate() {
+        let params = SimParams {
+            cogging_torque: 5e-3,
+            ..SimParams::default()
+        };
+        let sim = Simulator::new(params);
+        let mut foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .aligned(&mut sim.clock())
+            .unwrap()
+            .foc()
+            .with_current_sensor(sim.current_sensor());
+
+        let map = foc.calibrate_cogging(&mut sim.clock()).unwrap();
+        assert_eq!(foc.cogging_map(), Some(&map));
+
+        // The rotor is held against `amplitude·sin(periods·angle)`, in A
+        let periods = params.cogging_periods as f32;
+        let amplitude = params.cogging_torque / params.torque_constant();
+        let (sin, cos) = fourier(&map, periods);
+        assert!(
+            (sin - amplitude).abs() < 0.1 * amplitude,
+            "{sin} != {amplitude}"
+        );
+        assert!(cos.abs() < 0.1 * amplitude, "{cos}");
+
+        // At that period only
+        for other in [
+            1.,
+            7.,
+            periods / 2.,
+            periods - 1.,
+            periods + 1.,
+            2. * periods,
+        ] {
+            let (sin, cos) = fourier(&map, other);
+            let other_amplitude = Float::sqrt(sin * sin + cos * cos);
+            assert!(
+                other_amplitude < 0.2 * amplitude,
+                "{other}: {other_amplitude}"
+            );
+        }
+    }
+
+    #[test]
+    fn sweep() {
+        // Every other bin, both ways, with the friction flipping sign
+        let mut sweep = CoggingSweep::new();
+        for bin in (0..COGGING_BINS).step_by(2) {
+            let torque = bin as f32 * 1e-3;
+            sweep.record(true, middle(bin), torque + 0.1);
+            sweep.record(false, middle(bin), torque - 0.1);
+        }
+        let map = sweep.finish().unwrap();
+
+        assert!((map.torque(middle(10)) - 0.010).abs() < 1e-6);
+        assert!((map.torque(middle(11)) - 0.011).abs() < 1e-6);
+
+        // The last bin is between the one before and the first one
+        let last = map.torque(middle(COGGING_BINS - 1));
+        assert!((last - 1.022 / 2.).abs() < 1e-6, "{last}");
+    }
+
+    #[test]
+    fn sweep_coverage() {
+        let mut sweep = CoggingSweep::new();
+        for bin in 0..COGGING_BINS / 2 - 1 {
+            sweep.record(true, middle(bin), 1.);
+            sweep.record(false, middle(bin), 1.);
+        }
+        // One way only doesn't count
+        sweep.record(true, middle(COGGING_BINS - 1), 1.);
+        assert!(sweep.finish().is_none());
+
+        sweep.record(false, middle(COGGING_BINS - 1), 1.);
+        assert!(sweep.finish().is_some());
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut torque = [0.; COGGING_BINS];
+        torque[0] = 0.2;
+        torque[1] = 0.4;
+        torque[COGGING_BINS - 1] = -0.2;
+        let map = CoggingMap::new(&torque);
+
+        let bin = TAU / COGGING_BINS as f32;
+        assert!((map.torque(middle(0)) - 0.2).abs() < 1e-6);
+        assert!((map.torque(bin) - 0.3).abs() < 1e-6);
+
+        // Across the wrap from the last bin to the first, either way round
+        for angle in [0., TAU, -TAU] {
+            assert!(map.torque(angle).abs() < 1e-6, "{}", map.torque(angle));
+        }
+        for angle in [0.25 * bin, TAU + 0.25 * bin, -0.25 * bin] {
+            let expected = if angle.rem_euclid(TAU) < TAU / 2. {
+                0.1
+            } else {
+                -0.1
+            };
+            let torque = map.torque(angle);
+            assert!((torque - expected).abs() < 1e-4, "{angle}: {torque}");
+        }
+    }
+
+    #[test]
+    fn bytes() {
+        let mut torque = [0.; COGGING_BINS];
+        for (bin, torque) in torque.iter_mut().enumerate() {
+            *torque = (bin as f32 - 512.) * 0.05;
+        }
+        let map = CoggingMap::new(&torque);
+
+        let bytes = map.to_bytes();
+        assert_eq!(bytes[..2], (-25600i16).to_le_bytes());
+        assert_eq!(CoggingMap::from_bytes(&bytes), map);
+        assert_eq!(map.peak(), 25.6);
+    }
+}
@@ -0,0 +1,179 @@
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering, fence};
+
+use crate::{sensor::SensorState, util::Velocity};
+
+/// Setpoint sent to a [`Foc`] through a [`CommandChannel`]
+///
+/// [`Foc`]: crate::motor::Foc
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// See [`Foc::to_velocity`](crate::motor::Foc::to_velocity)
+    Velocity(Velocity),
+
+    /// See [`Foc::to_angle`](crate::motor::Foc::to_angle)
+    Angle(f32),
+
+    /// See [`Foc::to_torque`](crate::motor::Foc::to_torque)
+    Torque(f32),
+}
+
+const VELOCITY: u8 = 0;
+const ANGLE: u8 = 1;
+const TORQUE: u8 = 2;
+
+/// Lock-free mailbox between the application and a control loop running in
+/// an interrupt, see [`Foc::with_commands`]
+///
+/// The application [`CommandChannel::send`]s setpoints, the loop picks up
+/// the latest one on its next outer tick and publishes the angle and velocity
+/// back. Neither side ever waits: a command sent while the loop reads is
+/// simply picked up one tick later. Commands should be sent from a single
+/// context.
+///
+/// ```ignore
+/// static COMMANDS: CommandChannel = CommandChannel::new();
+///
+/// let foc = motor.foc().with_commands(&COMMANDS);
+/// // Move `foc` into the timer interrupt, then from the main loop:
+/// COMMANDS.send(Command::Velocity(10 * Velocity::RPS));
+/// ```
+///
+/// [`Foc::with_commands`]: crate::motor::Foc::with_commands
+pub struct CommandChannel {
+    /// Odd while a command is being written, bumped twice per command
+    sequence: AtomicU32,
+    kind: AtomicU8,
+    value: AtomicU32,
+
+    angle: AtomicU32,
+    velocity: AtomicU32,
+}
+
+impl CommandChannel {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+            kind: AtomicU8::new(VELOCITY),
+            value: AtomicU32::new(0),
+            angle: AtomicU32::new(0),
+            velocity: AtomicU32::new(0),
+        }
+    }
+
+    /// Replace the pending command, if any
+    pub fn send(&self, command: Command) {
+        let (kind, value) = match command {
+            Command::Velocity(velocity) => (VELOCITY, velocity.as_secs()),
+            Command::Angle(angle) => (ANGLE, angle),
+            Command::Torque(torque) => (TORQUE, torque),
+        };
+
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.kind.store(kind, Ordering::Relaxed);
+        self.value.store(value.to_bits(), Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Command sent since the one at `seen`, which is updated
+    pub(crate) fn receive(&self, seen: &mut u32) -> Option<Command> {
+        let sequence = self.sequence.load(Ordering::Acquire);
+        if sequence == *seen || !sequence.is_multiple_of(2) {
+            return None;
+        }
+
+        let kind = self.kind.load(Ordering::Relaxed);
+        let value = f32::from_bits(self.value.load(Ordering::Relaxed));
+        fence(Ordering::Acquire);
+
+        // Torn by a send in the middle, try again next time
+        if self.sequence.load(Ordering::Relaxed) != sequence {
+            return None;
+        }
+        *seen = sequence;
+
+        Some(match kind {
+            VELOCITY => Command::Velocity(Velocity::per_sec(value)),
+            ANGLE => Command::Angle(value),
+            _ => Command::Torque(value),
+        })
+    }
+
+    pub(crate) fn publish(&self, state: &SensorState) {
+        self.angle
+            .store(state.total_angle().to_bits(), Ordering::Relaxed);
+        self.velocity
+            .store(state.velocity().as_secs().to_bits(), Ordering::Relaxed);
+    }
+
+    /// Total angle in rad as of the last outer tick
+    pub fn angle(&self) -> f32 {
+        f32::from_bits(self.angle.load(Ordering::Relaxed))
+    }
+
+    /// Velocity as of the last outer tick
+    pub fn velocity(&self) -> Velocity {
+        Velocity::per_sec(f32::from_bits(self.velocity.load(Ordering::Relaxed)))
+    }
+}
+
+impl Default for CommandChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let channel = CommandChannel::new();
+        let mut seen = 0;
+        assert_eq!(channel.receive(&mut seen), None);
+
+        for command in [
+            Command::Velocity(10 * Velocity::RPS),
+            Command::Angle(-3.5),
+            Command::Torque(0.25),
+        ] {
+            channel.send(command);
+            assert_eq!(channel.receive(&mut seen), Some(command));
+            assert_eq!(channel.receive(&mut seen), None);
+        }
+        assert_eq!(seen, 6);
+    }
+
+    #[test]
+    fn latest() {
+        let channel = CommandChannel::new();
+        let mut seen = 0;
+        channel.send(Command::Angle(1.));
+        channel.send(Command::Torque(2.));
+        assert_eq!(channel.receive(&mut seen), Some(Command::Torque(2.)));
+        assert_eq!(channel.receive(&mut seen), None);
+
+        // Another receiver still gets it
+        assert_eq!(channel.receive(&mut 0), Some(Command::Torque(2.)));
+    }
+
+    #[test]
+    fn in_progress() {
+        let channel = CommandChannel::new();
+        let mut seen = 0;
+        channel.send(Command::Angle(1.));
+
+        // Halfway through the next send
+        channel.sequence.store(3, Ordering::Relaxed);
+        assert_eq!(channel.receive(&mut seen), None);
+        assert_eq!(seen, 0);
+
+        channel.sequence.store(4, Ordering::Relaxed);
+        assert_eq!(channel.receive(&mut seen), Some(Command::Angle(1.)));
+        assert_eq!(seen, 4);
+    }
+}
@@ -0,0 +1,312 @@
+use core::{
+    convert::Infallible,
+    f32::consts::{FRAC_1_SQRT_3, SQRT_3},
+    fmt::Debug,
+};
+
+use cordic::sin_cos;
+use num_traits::Float;
+
+use crate::f;
+
+/// Phase current readings in A
+pub trait CurrentSensor {
+    type Error: Debug;
+
+    /// Whether the sensor measures anything at all
+    ///
+    /// `()` is used as a placeholder when the driver has no current sensing,
+    /// in which case [`Foc`](super::Foc) falls back to voltage control.
+    const PRESENT: bool = true;
+
+    /// Reads the current flowing through each phase, in A
+    fn read_currents(&mut self) -> Result<PhaseCurrents, Self::Error>;
+}
+
+impl CurrentSensor for () {
+    type Error = Infallible;
+
+    const PRESENT: bool = false;
+
+    fn read_currents(&mut self) -> Result<PhaseCurrents, Self::Error> {
+        Ok(PhaseCurrents::default())
+    }
+}
+
+/// Current of each phase in A, positive when flowing into the motor
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PhaseCurrents {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+}
+
+impl PhaseCurrents {
+    /// Build the currents from two measured phases, the third one is derived
+    /// from Kirchhoff's current law
+    pub fn from_two_phases(a: f32, b: f32) -> Self {
+        Self { a, b, c: -a - b }
+    }
+
+    /// Clarke transform into the stationary frame (amplitude invariant)
+    pub fn clarke(self) -> AlphaBeta {
+        AlphaBeta {
+            alpha: (2. * self.a - self.b - self.c) / 3.,
+            beta: (self.b - self.c) * FRAC_1_SQRT_3,
+        }
+    }
+}
+
+/// Quantity in the stationary α-β frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AlphaBeta {
+    pub alpha: f32,
+    pub beta: f32,
+}
+
+impl AlphaBeta {
+    /// Length of the vector, the amplitude of the phase quantities
+    pub fn magnitude(self) -> f32 {
+        Float::hypot(self.alpha, self.beta)
+    }
+
+    /// Park transform into the rotor frame at `angle` (electrical, in rad)
+    pub fn park(self, angle: f32) -> DQ {
+        let (sin, cos) = sin_cos(f!(angle));
+        let (sin, cos) = (sin.to_num::<f32>(), cos.to_num::<f32>());
+
+        DQ {
+            d: self.alpha * cos + self.beta * sin,
+            q: self.beta * cos - self.alpha * sin,
+        }
+    }
+
+    /// Inverse Clarke transform back into phase quantities
+    pub fn inverse_clarke(self) -> PhaseCurrents {
+        PhaseCurrents {
+            a: self.alpha,
+            b: -0.5 * self.alpha + 0.5 * SQRT_3 * self.beta,
+            c: -0.5 * self.alpha - 0.5 * SQRT_3 * self.beta,
+        }
+    }
+}
+
+/// Quantity in the rotating d-q frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DQ {
+    pub d: f32,
+    pub q: f32,
+}
+
+impl DQ {
+    /// Inverse Park transform into the stationary frame at `angle`
+    /// (electrical, in rad)
+    pub fn inverse_park(self, angle: f32) -> AlphaBeta {
+        let (sin, cos) = sin_cos(f!(angle));
+        let (sin, cos) = (sin.to_num::<f32>(), cos.to_num::<f32>());
+
+        AlphaBeta {
+            alpha: self.d * cos - self.q * sin,
+            beta: self.d * sin + self.q * cos,
+        }
+    }
+}
+
+/// Converts shunt amplifier output voltages into phase currents
+///
+/// Works for both inline and low-side shunts. Low-side shunts see the
+/// current flowing out of the phase, so they are usually `inverted`, and
+/// they should only be sampled while the low-side switches are on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShuntAmplifier {
+    /// Shunt resistance in Ω
+    resistance: f32,
+
+    /// Amplifier gain in V/V
+    gain: f32,
+
+    /// Amplifier output at zero current in V, per phase
+    offsets: [f32; 3],
+
+    inverted: bool,
+}
+
+impl ShuntAmplifier {
+    pub fn new(resistance: f32, gain: f32) -> Self {
+        Self {
+            resistance,
+            gain,
+            offsets: [0.; 3],
+            inverted: false,
+        }
+    }
+
+    pub fn inverted(mut self) -> Self {
+        self.inverted = true;
+        self
+    }
+
+    /// Amplifier output at zero current, usually half of the ADC reference
+    pub fn with_offset(mut self, offset: f32) -> Self {
+        self.offsets = [offset; 3];
+        self
+    }
+
+    /// Record the amplifier outputs as the zero current offsets, to be called
+    /// while the bridge is off
+    pub fn calibrate(&mut self, volts: [f32; 3]) {
+        self.offsets = volts;
+    }
+
+    /// Current through a single shunt given the amplifier output in V
+    pub fn current(&self, phase: usize, volt: f32) -> f32 {
+        let current = (volt - self.offsets[phase]) / (self.resistance * self.gain);
+        if self.inverted { -current } else { current }
+    }
+
+    /// Phase currents from the amplifier outputs in V
+    ///
+    /// When only two phases are sampled, pass `None` for `c`.
+    pub fn currents(&self, a: f32, b: f32, c: Option<f32>) -> PhaseCurrents {
+        let a = self.current(0, a);
+        let b = self.current(1, b);
+
+        match c {
+            Some(c) => PhaseCurrents {
+                a,
+                b,
+                c: self.current(2, c),
+            },
+            None => PhaseCurrents::from_two_phases(a, b),
+        }
+    }
+}
+
+/// Current sensor returning whatever it has been told to, for exercising the
+/// current loop without an ADC
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MockCurrentSensor {
+    currents: PhaseCurrents,
+}
+
+impl MockCurrentSensor {
+    pub fn new(currents: PhaseCurrents) -> Self {
+        Self { currents }
+    }
+
+    pub fn set(&mut self, currents: PhaseCurrents) {
+        self.currents = currents;
+    }
+
+    /// Set the phase currents that correspond to `dq` at electrical `angle`
+    pub fn set_dq(&mut self, dq: DQ, angle: f32) {
+        self.currents = dq.inverse_park(angle).inverse_clarke();
+    }
+}
+
+impl CurrentSensor for MockCurrentSensor {
+    type Error = Infallible;
+
+    fn read_currents(&mut self) -> Result<PhaseCurrents, Self::Error> {
+        Ok(self.currents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f32::consts::TAU;
+
+    use super::*;
+    use crate::{
+        motor::{BLDC, normalize_angle},
+        sensor::SensorHardware,
+        sim::{SimParams, Simulator},
+        util::Duration,
+    };
+
+    /// Rotor held at a fixed mechanical angle
+    struct Locked(f32);
+
+    impl SensorHardware for Locked {
+        type Error = Infallible;
+
+        fn read_angle(&mut self) -> Result<f32, Infallible> {
+            Ok(self.0)
+        }
+    }
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!((a - b).abs() < tolerance, "{a} != {b}");
+    }
+
+    #[test]
+    fn clarke_round_trip() {
+        let currents = PhaseCurrents::from_two_phases(1.2, -0.5);
+        let back = currents.clarke().inverse_clarke();
+        assert_close(back.a, currents.a, 1e-6);
+        assert_close(back.b, currents.b, 1e-6);
+        assert_close(back.c, currents.c, 1e-6);
+
+        // Amplitude invariant: balanced phases of 2 A make a 2 A vector
+        for step in 0..16 {
+            let angle = step as f32 * TAU / 16.;
+            let phase = |shift: f32| 2. * Float::cos(angle - shift);
+            let currents = PhaseCurrents {
+                a: phase(0.),
+                b: phase(TAU / 3.),
+                c: phase(2. * TAU / 3.),
+            };
+            assert_close(currents.clarke().magnitude(), 2., 1e-5);
+        }
+    }
+
+    #[test]
+    fn park_round_trip() {
+        let dq = DQ { d: 0.3, q: -1.2 };
+        for step in 0..32 {
+            let angle = step as f32 * TAU / 32.;
+            let back = dq.inverse_park(angle).park(angle);
+            assert_close(back.d, dq.d, 1e-3);
+            assert_close(back.q, dq.q, 1e-3);
+        }
+
+        // At 0 the d axis lies on phase a
+        let currents = DQ { d: 1., q: 0. }.inverse_park(0.).inverse_clarke();
+        assert_close(currents.a, 1., 1e-4);
+        assert_close(currents.b, -0.5, 1e-4);
+    }
+
+    #[test]
+    fn current_loop_converges() {
+        // A locked rotor is a plain RL load in the rotor frame
+        const RESISTANCE: f32 = 5.;
+        const INDUCTANCE: f32 = 2e-3;
+        const PERIOD: u64 = 100;
+
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(Locked(0.3))
+            .foc()
+            .with_current_sensor(MockCurrentSensor::default())
+            .to_torque(0.5);
+        let angle = normalize_angle(0.3 * 7.);
+
+        // Start off with some Id for the loop to remove
+        let mut current = DQ { d: 0.4, q: 0. };
+        for _ in 0..2000 {
+            foc.current_sensor_mut().set_dq(current, angle);
+            sim.advance(Duration::micros(PERIOD));
+            foc.tick().unwrap();
+
+            let voltage = foc.voltage();
+            let dt = PERIOD as f32 * 1e-6;
+            current.d += (voltage.d - RESISTANCE * current.d) / INDUCTANCE * dt;
+            current.q += (voltage.q - RESISTANCE * current.q) / INDUCTANCE * dt;
+        }
+
+        assert_close(foc.current().q, 0.5, 1e-2);
+        assert_close(foc.current().d, 0., 1e-2);
+        assert_close(foc.voltage().q, 0.5 * RESISTANCE, 5e-2);
+    }
+}
@@ -0,0 +1,456 @@
+use embedded_hal::pwm::SetDutyCycle;
+
+use crate::{
+    motor::{BLDC, PhaseEnable},
+    sensor::SensorHardware,
+    util::{Clock, Duration, Velocity},
+};
+
+/// Default number of failed sensor reads in a row before tripping
+const DEFAULT_MAX_SENSOR_ERRORS: u8 = 5;
+
+/// Why the bridge was shut down, latched until [`BLDC::clear_fault`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// The angle sensor failed too many reads in a row
+    SensorLoss,
+
+    /// The current sensor failed a read
+    CurrentSensor,
+
+    /// The bus voltage sensor failed too many reads in a row
+    BusVoltageSensor,
+
+    /// Phase current amplitude, in A
+    OverCurrent(f32),
+
+    /// Bus voltage, in V
+    OverVoltage(f32),
+
+    /// Bus voltage, in V
+    UnderVoltage(f32),
+
+    /// The motor didn't turn despite a high torque demand
+    Stall,
+
+    /// Temperature, in °C
+    OverTemperature(f32),
+}
+
+/// Limits watched by [`BLDC`], all off by default except the sensor check
+///
+/// Bus voltage and temperature are whatever was last reported with
+/// [`BLDC::report_bus_voltage`] (unfiltered) and [`BLDC::report_temperature`].
+/// Over-current needs a current sensor, and stall detection runs in [`Foc`].
+///
+/// [`Foc`]: super::Foc
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Protection {
+    max_sensor_errors: u8,
+    max_current: Option<f32>,
+    voltage_range: Option<(f32, f32)>,
+    stall: Option<StallLimit>,
+    max_temperature: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct StallLimit {
+    torque: f32,
+    velocity: Velocity,
+    time: Duration,
+}
+
+impl Default for Protection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Protection {
+    pub const fn new() -> Self {
+        Self {
+            max_sensor_errors: DEFAULT_MAX_SENSOR_ERRORS,
+            max_current: None,
+            voltage_range: None,
+            stall: None,
+            max_temperature: None,
+        }
+    }
+
+    /// Trip after `count` failed sensor reads in a row, ticks in between
+    /// keep the last output
+    ///
+    /// Counted separately for the angle and the bus voltage sensor, the
+    /// latter keeps the last voltage in between.
+    pub fn with_max_sensor_errors(mut self, count: u8) -> Self {
+        self.max_sensor_errors = count;
+        self
+    }
+
+    /// Trip when the phase current amplitude exceeds `current` (in A)
+    pub fn with_max_current(mut self, current: f32) -> Self {
+        self.max_current = Some(current);
+        self
+    }
+
+    pub(crate) fn max_current(&self) -> Option<f32> {
+        self.max_current
+    }
+
+    /// Trip when the bus voltage leaves `min..=max` (in V)
+    pub fn with_voltage_range(mut self, min: f32, max: f32) -> Self {
+        assert!(min < max);
+        self.voltage_range = Some((min, max));
+        self
+    }
+
+    /// Trip when the torque demand stays above `torque` (in A, or V without
+    /// a current sensor) while the motor turns slower than `velocity`, for
+    /// longer than `time`
+    pub fn with_stall(mut self, torque: f32, velocity: Velocity, time: Duration) -> Self {
+        self.stall = Some(StallLimit {
+            torque,
+            velocity,
+            time,
+        });
+        self
+    }
+
+    /// Trip when the temperature exceeds `temperature` (in °C)
+    pub fn with_max_temperature(mut self, temperature: f32) -> Self {
+        self.max_temperature = Some(temperature);
+        self
+    }
+}
+
+/// State of the protection
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Guard {
+    fault: Option<Fault>,
+    sensor_errors: u8,
+    bus_errors: u8,
+    stalled_for: Option<Duration>,
+    temperature: Option<f32>,
+
+    /// The phases were switched off by the safe state
+    floating: bool,
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K> {
+    pub fn with_protection(mut self, protection: Protection) -> Self {
+        self.protection = protection;
+        self
+    }
+
+    pub fn protection(&self) -> Protection {
+        self.protection
+    }
+
+    /// Fault that shut the bridge down, if any
+    pub fn fault(&self) -> Option<Fault> {
+        self.guard.fault
+    }
+
+    /// Resume after a fault, it trips again if the cause is still there
+    pub fn clear_fault(&mut self) {
+        self.guard.fault = None;
+        self.guard.sensor_errors = 0;
+        self.guard.bus_errors = 0;
+        self.guard.stalled_for = None;
+    }
+
+    /// Latest temperature of the motor or the driver, in °C, checked against
+    /// [`Protection::with_max_temperature`]
+    pub fn report_temperature(&mut self, temperature: f32) {
+        self.guard.temperature = Some(temperature);
+    }
+
+    /// Latch `fault`, keeping the first one
+    pub(crate) fn latch(&mut self, fault: Fault) {
+        self.guard.fault.get_or_insert(fault);
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    A: SetDutyCycle,
+    B: SetDutyCycle<Error = A::Error>,
+    C: SetDutyCycle<Error = A::Error>,
+{
+    /// Drive all phases low, no voltage across the motor, and switch them off
+    /// with [`BLDC::with_floating_safe_state`]
+    ///
+    /// With complementary outputs all low sides are on, which brakes the
+    /// motor on its back-EMF. Switched off phases let it coast.
+    pub fn safe_state(&mut self) -> Result<(), A::Error> {
+        self.pwm.set_duty((0, 0, 0))?;
+        if let Some(switch) = self.phase_switch {
+            // A phase that doesn't switch off stays low, which is safe as well
+            switch(&mut self.pwm, false);
+            self.guard.floating = true;
+        }
+        Ok(())
+    }
+
+    /// Latch `fault` and put the bridge in the safe state
+    pub(crate) fn trip(&mut self, fault: Fault) -> Result<(), A::Error> {
+        self.latch(fault);
+        self.safe_state()
+    }
+
+    /// Check the reported bus voltage and temperature, and the phase current
+    /// amplitude if measured, tripping on a limit
+    ///
+    /// Returns whether the control loop can run.
+    pub(crate) fn check_limits(&mut self, current: Option<f32>) -> Result<bool, A::Error> {
+        let protection = self.protection;
+        let guard = self.guard;
+
+        if guard.fault.is_some() {
+            self.safe_state()?;
+            return Ok(false);
+        }
+
+        let fault = match (protection.voltage_range, self.bus.raw()) {
+            (Some((min, _)), Some(voltage)) if voltage < min => Some(Fault::UnderVoltage(voltage)),
+            (Some((_, max)), Some(voltage)) if voltage > max => Some(Fault::OverVoltage(voltage)),
+            _ => None,
+        }
+        .or(match (protection.max_temperature, guard.temperature) {
+            (Some(max), Some(temperature)) if temperature > max => {
+                Some(Fault::OverTemperature(temperature))
+            }
+            _ => None,
+        })
+        .or(match (protection.max_current, current) {
+            (Some(max), Some(current)) if current > max => Some(Fault::OverCurrent(current)),
+            _ => None,
+        });
+
+        match fault {
+            Some(fault) => self.trip(fault).map(|_| false),
+            None if guard.floating => {
+                // Back from a fault, the loop runs once the phases are on again
+                let switched = self
+                    .phase_switch
+                    .is_some_and(|switch| switch(&mut self.pwm, true));
+                self.guard.floating = !switched;
+                Ok(switched)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Count the stall time of a `torque` demand at `velocity` over `dt`,
+    /// tripping once it lasts too long
+    pub(crate) fn check_stall(
+        &mut self,
+        torque: f32,
+        velocity: Velocity,
+        dt: Duration,
+    ) -> Result<bool, A::Error> {
+        let Some(limit) = self.protection.stall else {
+            return Ok(true);
+        };
+
+        let stalled =
+            torque.abs() > limit.torque && velocity.as_secs().abs() < limit.velocity.as_secs();
+        self.guard.stalled_for = match (stalled, self.guard.stalled_for) {
+            (true, Some(time)) => Some(time + dt),
+            (true, None) => Some(dt),
+            (false, _) => None,
+        };
+
+        match self.guard.stalled_for {
+            Some(time) if time > limit.time => self.trip(Fault::Stall).map(|_| false),
+            _ => Ok(true),
+        }
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    A: PhaseEnable,
+    B: PhaseEnable<Error = A::Error>,
+    C: PhaseEnable<Error = A::Error>,
+{
+    /// Switch the phases off in the safe state instead of only driving them
+    /// low, so the motor coasts rather than brakes on complementary outputs
+    ///
+    /// They are switched on again on the first tick after
+    /// [`BLDC::clear_fault`].
+    pub fn with_floating_safe_state(mut self) -> Self {
+        self.phase_switch = Some(|pwm, enabled| pwm.set_enabled([enabled; 3]).is_ok());
+        self
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    H: SensorHardware,
+    A: SetDutyCycle,
+    B: SetDutyCycle<Error = A::Error>,
+    C: SetDutyCycle<Error = A::Error>,
+    K: Clock,
+{
+    /// Update the sensor, counting failed reads instead of giving up on the
+    /// first one
+    ///
+    /// Returns whether the reading is fresh. A failed read leaves the output
+    /// as it was, unless the sensor failed too often in a row and tripped.
+    pub(crate) fn update_sensor_guarded(&mut self) -> Result<bool, A::Error> {
+        match self.update_sensor() {
+            Ok(()) => {
+                self.guard.sensor_errors = 0;
+                Ok(true)
+            }
+            Err(_) => {
+                self.guard.sensor_errors = self.guard.sensor_errors.saturating_add(1);
+                if self.guard.sensor_errors >= self.protection.max_sensor_errors {
+                    self.trip(Fault::SensorLoss)?;
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K: Clock> BLDC<H, A, B, C, POLE, K> {
+    /// Report a bus voltage `reading`, counting failed reads instead of
+    /// giving up on the first one
+    ///
+    /// Too many failures in a row latch a fault, the next check of the limits
+    /// trips it.
+    pub(crate) fn report_bus_voltage_guarded<E>(&mut self, reading: Result<f32, E>) {
+        match reading {
+            Ok(voltage) => {
+                self.guard.bus_errors = 0;
+                self.report_bus_voltage(voltage);
+            }
+            Err(_) => {
+                self.guard.bus_errors = self.guard.bus_errors.saturating_add(1);
+                if self.guard.bus_errors >= self.protection.max_sensor_errors {
+                    self.latch(Fault::BusVoltageSensor);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embedded_hal::pwm::ErrorType;
+
+    use super::*;
+    use crate::{motor::ThreePhasePwm, util::Instant};
+
+    #[derive(Clone, Copy)]
+    struct Phase {
+        duty: u16,
+        enabled: bool,
+    }
+
+    impl ErrorType for Phase {
+        type Error = Infallible;
+    }
+
+    impl SetDutyCycle for Phase {
+        fn max_duty_cycle(&self) -> u16 {
+            100
+        }
+
+        fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+            self.duty = duty;
+            Ok(())
+        }
+    }
+
+    impl PhaseEnable for Phase {
+        fn set_enabled(&mut self, enabled: bool) -> Result<(), Self::Error> {
+            self.enabled = enabled;
+            Ok(())
+        }
+    }
+
+    /// Time stands still, the bus voltage filter keeps its first value
+    struct Frozen;
+
+    impl Clock for Frozen {
+        fn now(&self) -> Instant {
+            Instant::from_ticks(0)
+        }
+    }
+
+    fn motor() -> BLDC<(), Phase, Phase, Phase, 7> {
+        let phase = || Phase {
+            duty: 50,
+            enabled: true,
+        };
+        BLDC::new::<7>(ThreePhasePwm {
+            a: phase(),
+            b: phase(),
+            c: phase(),
+        })
+    }
+
+    fn phases(motor: &BLDC<(), Phase, Phase, Phase, 7>) -> [(u16, bool); 3] {
+        let pwm = &motor.pwm;
+        [pwm.a, pwm.b, pwm.c].map(|phase| (phase.duty, phase.enabled))
+    }
+
+    #[test]
+    fn low_safe_state() {
+        let mut motor = motor();
+        motor.trip(Fault::Stall).unwrap();
+        assert_eq!(phases(&motor), [(0, true); 3]);
+    }
+
+    #[test]
+    fn floating_safe_state() {
+        let mut motor = motor().with_floating_safe_state();
+        motor.trip(Fault::Stall).unwrap();
+        assert_eq!(phases(&motor), [(0, false); 3]);
+
+        // Off until the fault is cleared
+        assert!(!motor.check_limits(None).unwrap());
+        assert_eq!(phases(&motor), [(0, false); 3]);
+
+        motor.clear_fault();
+        assert!(motor.check_limits(None).unwrap());
+        assert_eq!(phases(&motor), [(0, true); 3]);
+    }
+
+    #[test]
+    fn bus_voltage_errors() {
+        let mut motor = motor().with_clock(Frozen);
+        let fail = |motor: &mut BLDC<_, _, _, _, 7, _>, count| {
+            for _ in 0..count {
+                motor.report_bus_voltage_guarded(Err(()));
+            }
+        };
+
+        fail(&mut motor, DEFAULT_MAX_SENSOR_ERRORS - 1);
+        motor.report_bus_voltage_guarded(Ok::<_, ()>(12.));
+        fail(&mut motor, DEFAULT_MAX_SENSOR_ERRORS - 1);
+        assert_eq!(motor.fault(), None);
+        assert_eq!(motor.bus_voltage(), Some(12.));
+
+        fail(&mut motor, 1);
+        assert_eq!(motor.fault(), Some(Fault::BusVoltageSensor));
+    }
+
+    #[test]
+    fn voltage_spike() {
+        let mut motor = motor()
+            .with_clock(Frozen)
+            .with_protection(Protection::new().with_voltage_range(10., 20.));
+        motor.report_bus_voltage(12.);
+        motor.report_bus_voltage(30.);
+
+        assert_eq!(motor.bus_voltage(), Some(12.));
+        assert!(!motor.check_limits(None).unwrap());
+        assert_eq!(motor.fault(), Some(Fault::OverVoltage(30.)));
+    }
+}
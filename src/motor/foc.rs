@@ -1,48 +1,122 @@
 use core::{
-    f32::consts::{PI, SQRT_3},
+    f32::consts::{SQRT_3, TAU},
     ops::{Deref, DerefMut},
 };
 
-use embedded_hal::pwm::SetDutyCycle;
-use num_traits::float::FloatCore;
+use embedded_hal::{delay::DelayNs, pwm::SetDutyCycle};
+use num_traits::Float;
 use tap::Pipe;
 
 use crate::{
     RPM_TO_RADS, f,
-    motor::BLDC,
+    motor::{
+        AlphaBeta, BLDC, BusVoltageSensor, COGGING_BINS, CoggingError, CoggingMap, CoggingSweep,
+        Command, CommandChannel, CurrentSensor, DQ, DetentEvent, Fault, FieldWeakening, Haptic,
+        HapticProfile, Modulation, Mtpa, Startup, normalize_angle,
+    },
     pid::{PIDController, VelocityPID},
-    sensor::SensorHardware,
-    util::Velocity,
+    planner::Planner,
+    sensor::{SensorHardware, SensorState},
+    telemetry::{Channel, Sample},
+    util::{Clock, Duration, Velocity},
 };
 
-pub struct Foc<M> {
+pub struct Foc<M, I = (), V = ()> {
     motor: M,
     motion_control: MotionControl,
     velocity_pid: VelocityPID,
     angle_pid: PIDController,
+
+    /// Stiffness and damping of the end stops of [`MotionControl::LimitPos`]
+    endstop: (f32, f32),
+
+    /// Angle and velocity PIDs of the other modes, while
+    /// [`MotionControl::LimitPos`] runs them as the end stop spring
+    saved_pids: Option<(PIDController, VelocityPID)>,
+
+    /// Profile and detent crossings of [`MotionControl::Haptic`]
+    haptic: Option<Haptic>,
+
+    planner: Option<Planner>,
+    startup: Option<Startup>,
+    field_weakening: Option<FieldWeakening>,
+    mtpa: Option<Mtpa>,
+    current_sensor: I,
+    iq_pid: PIDController,
+    id_pid: PIDController,
+    current: DQ,
+    voltage: DQ,
+
+    /// Last measured currents in the stationary frame
+    phase_current: AlphaBeta,
+
+    /// Last applied voltages in the stationary frame
+    applied: AlphaBeta,
+
+    /// Inner ticks per outer tick, and where in that count the loop is
+    outer_divider: u16,
+    outer_count: u16,
+
+    /// Time since the last outer tick
+    outer_elapsed: Duration,
+
+    /// Output of the last outer tick
+    demand: Demand,
+
+    commands: Option<&'static CommandChannel>,
+    command_sequence: u32,
+
+    bus_voltage_sensor: V,
+}
+
+/// What the outer loop asks of the inner one
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Demand {
+    /// Torque, as Iq in A with a current sensor, or as q voltage in V without
+    /// one
+    Torque(Velocity),
+
+    /// Apply no voltage
+    Coast,
+
+    /// Keep the voltages applied last
+    Hold,
 }
 
 pub enum MotionControl {
     /// Target velocity in rad/μs
     Velocity(Velocity),
 
-    /// Target angle in rad
+    /// Target angle in rad, reached through the planner if there is one (see
+    /// [`Foc::with_planner`])
     Angle(f32),
 
-    /// Target torque
+    /// Target torque, as Iq in A with a current sensor, or as q voltage in V
+    /// without one
     Torque(f32),
 
-    /// Number of ratchet steps
-    Ratchet(RatchetState),
+    /// Haptic knob, see [`Foc::to_haptic`]
+    Haptic,
 
-    /// Limit the position
+    /// Spin freely between the low and high bound in rad, with soft end stops
+    /// outside of them
     LimitPos(f32, f32),
 }
 
-pub struct RatchetState {
-    steps: u8,
-    rad_per_step: f32,
-}
+/// How close (in rad) the angle has to be to its target without a planner
+const ANGLE_TOLERANCE: f32 = 3e-2;
+
+/// Default end stop stiffness, in V per rad past the bound
+const DEFAULT_ENDSTOP_STIFFNESS: f32 = 4.;
+
+/// Default end stop damping, in V per rad/s
+const DEFAULT_ENDSTOP_DAMPING: f32 = 0.03;
+
+/// Steps of the cogging sweep per bin of the [`CoggingMap`], each way
+const COGGING_SWEEP_OVERSAMPLING: usize = 8;
+
+/// Time per step of the cogging sweep, in μs, about 16 s per revolution
+const COGGING_SWEEP_PERIOD: u32 = 2000;
 
 impl<M> Foc<M> {
     pub(crate) fn new(motor: M) -> Self {
@@ -56,53 +130,243 @@ impl<M> Foc<M> {
                 .limit(12.)
                 .pipe(VelocityPID::new),
             angle_pid: PIDController::new().p(10.).limit(10.),
+            endstop: (DEFAULT_ENDSTOP_STIFFNESS, DEFAULT_ENDSTOP_DAMPING),
+            saved_pids: None,
+            haptic: None,
+            planner: None,
+            startup: None,
+            field_weakening: None,
+            mtpa: None,
+            current_sensor: (),
+            iq_pid: PIDController::new().p(3.).i(300.).limit(12.),
+            id_pid: PIDController::new().p(3.).i(300.).limit(12.),
+            current: DQ::default(),
+            voltage: DQ::default(),
+            phase_current: AlphaBeta::default(),
+            applied: AlphaBeta::default(),
+            outer_divider: 1,
+            outer_count: 0,
+            outer_elapsed: Duration::from_ticks(0),
+            demand: Demand::Coast,
+            commands: None,
+            command_sequence: 0,
+            bus_voltage_sensor: (),
         }
     }
+}
 
+impl<M, I, V> Foc<M, I, V> {
     /// Set the target velocity
     pub fn to_velocity(mut self, target: Velocity) -> Self {
-        self.motion_control = MotionControl::Velocity(target);
+        self.set_command(Command::Velocity(target));
         self
     }
 
+    /// Set the target angle
+    ///
+    /// With a planner, a move already underway is retargeted smoothly, coming
+    /// from another mode starts over from where the shaft is.
     pub fn to_angle(mut self, target: f32) -> Self {
-        self.motion_control = MotionControl::Angle(target);
+        self.set_command(Command::Angle(target));
         self
     }
 
     pub fn to_torque(mut self, target: f32) -> Self {
-        self.motion_control = MotionControl::Torque(target);
+        self.set_command(Command::Torque(target));
         self
     }
 
-    pub fn to_ratchet(mut self, num_step: u8) -> Self {
-        self.motion_control = MotionControl::Ratchet(RatchetState {
-            steps: num_step,
-            rad_per_step: 2. * PI / num_step as f32,
-        });
-        self.angle_pid = self.angle_pid.p(240.).limit(240.);
-        self.velocity_pid = self.velocity_pid.update(|pid| pid.p(0.03).i(0.));
+    /// Switch to the velocity, angle or torque control of `command` while
+    /// running, see [`Foc::to_velocity`], [`Foc::to_angle`] and
+    /// [`Foc::to_torque`]
+    pub fn set_command(&mut self, command: Command) {
+        self.restore_pids();
+        self.motion_control = match command {
+            Command::Velocity(target) => MotionControl::Velocity(target),
+            Command::Angle(target) => {
+                if !matches!(self.motion_control, MotionControl::Angle(_)) {
+                    if let Some(planner) = &mut self.planner {
+                        planner.clear();
+                    }
+                }
+                MotionControl::Angle(target)
+            }
+            Command::Torque(target) => MotionControl::Torque(target),
+        };
+    }
+
+    /// Run the motion control (angle, velocity, haptics, ...) only every
+    /// `divider` ticks, the current or voltage loop still runs every tick
+    ///
+    /// With [`Foc::tick`] called at a fixed rate, e.g. from a timer
+    /// interrupt at 10 kHz, a divider of 10 runs the outer loop at 1 kHz.
+    /// Its PIDs see the time since the last outer tick.
+    pub fn with_outer_loop_divider(mut self, divider: u16) -> Self {
+        assert!(divider > 0);
+        self.outer_divider = divider;
+        self.outer_count = 0;
         self
     }
 
-    pub fn to_limit_pos(mut self, low: f32, high: f32) -> Self {
-        assert!(low < high);
-        self.motion_control = MotionControl::LimitPos(low, high);
+    /// Take setpoints from `commands` on every outer tick, and publish the
+    /// angle and velocity there, for a loop running in an interrupt
+    pub fn with_commands(mut self, commands: &'static CommandChannel) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Haptic knob with `num_step` evenly spaced detents per revolution
+    pub fn to_ratchet(self, num_step: u8) -> Self {
+        self.to_haptic(HapticProfile::new().with_even_detents(num_step as u16))
+    }
+
+    /// Haptic knob that feels like `profile`
+    pub fn to_haptic(mut self, profile: HapticProfile) -> Self {
+        self.set_haptic_profile(profile);
+        self
+    }
+
+    /// Switch the haptic profile while running, keeping track of the detents
+    /// crossed so far
+    pub fn set_haptic_profile(&mut self, profile: HapticProfile) {
+        self.restore_pids();
+        match (&self.motion_control, &mut self.haptic) {
+            (MotionControl::Haptic, Some(haptic)) => haptic.set_profile(profile),
+            _ => {
+                self.haptic = Some(Haptic::new(profile));
+                self.motion_control = MotionControl::Haptic;
+            }
+        }
+    }
+
+    /// Oldest detent crossing not polled yet, in haptic mode
+    pub fn poll_haptic_event(&mut self) -> Option<DetentEvent> {
+        match self.motion_control {
+            MotionControl::Haptic => self.haptic.as_mut()?.poll_event(),
+            _ => None,
+        }
+    }
+
+    /// Put back the angle and velocity PIDs of the other modes when leaving
+    /// [`MotionControl::LimitPos`]
+    fn restore_pids(&mut self) {
+        if let Some((angle_pid, velocity_pid)) = self.saved_pids.take() {
+            self.angle_pid = angle_pid;
+            self.velocity_pid = velocity_pid;
+            self.angle_pid.reset();
+            self.velocity_pid.reset();
+        }
+    }
+
+    /// Follow a trapezoidal or S-curve trajectory to angle targets instead of
+    /// jumping to them
+    ///
+    /// The angle PID then tracks the planned position, and the planned
+    /// velocity is fed forward to the velocity PID.
+    pub fn with_planner(mut self, planner: Planner) -> Self {
+        self.planner = Some(planner);
         self
     }
 
+    pub fn planner(&self) -> Option<&Planner> {
+        self.planner.as_ref()
+    }
+
+    /// Spin up open loop before handing over to the motion control, for
+    /// sensorless motors, see [`BLDC::sensorless`]
+    ///
+    /// The ramp turns towards the target velocity, and starts over whenever
+    /// the motor falls below the minimum speed of the [`Startup`], so targets
+    /// should stay above it.
+    pub fn with_startup(mut self, startup: Startup) -> Self {
+        self.startup = Some(startup);
+        self
+    }
+
+    pub fn startup(&self) -> Option<&Startup> {
+        self.startup.as_ref()
+    }
+
+    /// Takes effect once out of [`MotionControl::LimitPos`], if in it
     pub fn with_velocity_pid(mut self, controller: VelocityPID) -> Self {
-        self.velocity_pid = controller;
+        match &mut self.saved_pids {
+            Some((_, velocity_pid)) => *velocity_pid = controller,
+            None => self.velocity_pid = controller,
+        }
         self
     }
 
+    /// Takes effect once out of [`MotionControl::LimitPos`], if in it
     pub fn with_angle_pid(mut self, controller: PIDController) -> Self {
-        self.angle_pid = controller;
+        match &mut self.saved_pids {
+            Some((angle_pid, _)) => *angle_pid = controller,
+            None => self.angle_pid = controller,
+        }
+        self
+    }
+
+    /// Enable the current loop, turning torque targets into Iq in A
+    pub fn with_current_sensor<N: CurrentSensor>(self, sensor: N) -> Foc<M, N, V> {
+        Foc {
+            current_sensor: sensor,
+            ..self
+        }
+    }
+
+    pub fn current_sensor_mut(&mut self) -> &mut I {
+        &mut self.current_sensor
+    }
+
+    /// Measure the bus voltage on each tick and compute the duty cycles
+    /// against it, see [`BLDC::report_bus_voltage`]
+    pub fn with_bus_voltage_sensor<N: BusVoltageSensor>(self, sensor: N) -> Foc<M, I, N> {
+        Foc {
+            bus_voltage_sensor: sensor,
+            ..self
+        }
+    }
+
+    /// Drive a negative Id past the base speed, see [`FieldWeakening`]. Only
+    /// with a current sensor, Id isn't controlled without one.
+    pub fn with_field_weakening(mut self, field_weakening: FieldWeakening) -> Self {
+        self.field_weakening = Some(field_weakening);
+        self
+    }
+
+    pub fn field_weakening(&self) -> Option<&FieldWeakening> {
+        self.field_weakening.as_ref()
+    }
+
+    /// Split the current between Iq and Id for the most torque on a salient
+    /// motor, see [`Mtpa`]. Needs the Kv and a current sensor.
+    pub fn with_mtpa(mut self, mtpa: Mtpa) -> Self {
+        self.mtpa = Some(mtpa);
+        self
+    }
+
+    pub fn with_iq_pid(mut self, controller: PIDController) -> Self {
+        self.iq_pid = controller;
         self
     }
+
+    pub fn with_id_pid(mut self, controller: PIDController) -> Self {
+        self.id_pid = controller;
+        self
+    }
+
+    /// Last measured currents in the rotor frame, zero without a current
+    /// sensor
+    pub fn current(&self) -> DQ {
+        self.current
+    }
+
+    /// Last applied voltages in the rotor frame
+    pub fn voltage(&self) -> DQ {
+        self.voltage
+    }
 }
 
-impl<M> Deref for Foc<M> {
+impl<M, I, V> Deref for Foc<M, I, V> {
     type Target = M;
 
     fn deref(&self) -> &Self::Target {
@@ -110,20 +374,261 @@ impl<M> Deref for Foc<M> {
     }
 }
 
-impl<M> DerefMut for Foc<M> {
+impl<M, I, V> DerefMut for Foc<M, I, V> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.motor
     }
 }
 
-impl<H, A, B, C, const POLE: u8> Foc<BLDC<H, A, B, C, POLE>>
+impl<H, A, B, C, I, V, const POLE: u8, K> Foc<BLDC<H, A, B, C, POLE, K>, I, V>
 where
     H: SensorHardware,
     A: SetDutyCycle,
     B: SetDutyCycle<Error = A::Error>,
     C: SetDutyCycle<Error = A::Error>,
+    I: CurrentSensor,
+    V: BusVoltageSensor,
+    K: Clock,
 {
-    fn calculate_qd(&self, target: Velocity) -> (f32, f32) {
+    /// Let the motor spin freely between `low` and `high` (total angle in rad)
+    ///
+    /// Once the shaft leaves the range, a spring-like torque pushes it back to
+    /// the nearest bound. The spring is made of the angle and velocity PID,
+    /// see [`Foc::with_endstop`] for tuning it.
+    pub fn to_limit_pos(mut self, low: f32, high: f32) -> Self {
+        assert!(low < high);
+        self.motion_control = MotionControl::LimitPos(low, high);
+        self.apply_endstop();
+        self
+    }
+
+    /// Set the feel of the end stops used by [`MotionControl::LimitPos`]
+    ///
+    /// Past a bound the motor is driven with a torque of `stiffness * depth +
+    /// damping * velocity`, up to what the motor takes: the max current of
+    /// its [`Protection`] (or the voltage limit over the phase resistance)
+    /// for current targets, the voltage limit for voltage ones. This is done
+    /// by chaining the angle and velocity PIDs as P controllers: the velocity
+    /// PID gets `damping` as its P gain, and the angle PID gets `stiffness /
+    /// damping`. Their own gains are saved, and back once the mode changes.
+    ///
+    /// [`Protection`]: super::Protection
+    pub fn with_endstop(mut self, stiffness: f32, damping: f32) -> Self {
+        assert!(damping > 0.);
+        self.endstop = (stiffness, damping);
+        if matches!(self.motion_control, MotionControl::LimitPos(..)) {
+            self.apply_endstop();
+        }
+        self
+    }
+
+    /// Turn the angle and velocity PIDs into the end stop spring, saving
+    /// their gains for the other modes
+    fn apply_endstop(&mut self) {
+        let (stiffness, damping) = self.endstop;
+        let (angle_pid, velocity_pid) = *self
+            .saved_pids
+            .get_or_insert((self.angle_pid, self.velocity_pid));
+
+        self.angle_pid = angle_pid.p(stiffness / damping).i(0.).d(0.).limit(f32::MAX);
+        self.velocity_pid = velocity_pid.update(|pid| pid.p(damping).i(0.).d(0.));
+        self.angle_pid.reset();
+        self.velocity_pid.reset();
+    }
+
+    /// Largest torque demand the motor can follow, in the unit of the torque
+    /// targets
+    fn torque_limit(&self) -> f32 {
+        let voltage_limit = self.motor.voltage_limit;
+        match (I::PRESENT, self.motor.phase_resistance) {
+            (false, None) => voltage_limit,
+            (_, resistance) => self
+                .motor
+                .protection
+                .max_current()
+                .or(resistance.map(|r| voltage_limit / r))
+                .unwrap_or(f32::MAX),
+        }
+    }
+
+    /// How long a move to `target` (total angle in rad) would take with the
+    /// planner at its limits, in s, or `None` without a planner
+    pub fn move_duration(&self, target: f32) -> Option<f32> {
+        let mut planner = self.planner?;
+        let now = self.motor.now();
+
+        if !planner.is_planned() || !matches!(self.motion_control, MotionControl::Angle(_)) {
+            let state = self.motor.sensor.state();
+            let total = state.total_angle_f64() as f32;
+            planner.reset(total, state.velocity().as_secs(), now);
+        }
+
+        Some(planner.duration_to(target, now))
+    }
+
+    /// Move to `target` (total angle in rad), arriving after `duration` s or
+    /// as soon as the planner can if that's later
+    ///
+    /// Without a planner, this is [`Foc::to_angle`].
+    pub fn move_to(&mut self, target: f32, duration: f32) {
+        let now = self.motor.now();
+        let state = self.motor.sensor.state();
+        let angle_control = matches!(self.motion_control, MotionControl::Angle(_));
+
+        if let Some(planner) = &mut self.planner {
+            if !planner.is_planned() || !angle_control {
+                let total = state.total_angle_f64() as f32;
+                planner.reset(total, state.velocity().as_secs(), now);
+            }
+            planner.plan_with_duration(target, duration, now);
+        }
+        self.restore_pids();
+        self.motion_control = MotionControl::Angle(target);
+    }
+
+    /// Whether the last move is over, always for modes other than angle
+    pub fn is_move_done(&self) -> bool {
+        match (&self.motion_control, &self.planner) {
+            (MotionControl::Angle(_), Some(planner)) => planner.is_done(self.motor.now()),
+            (MotionControl::Angle(target), None) => {
+                self.motor.sensor.state().distance_to(*target).abs() < ANGLE_TOLERANCE
+            }
+            _ => true,
+        }
+    }
+
+    /// Measure the cogging of the motor and cancel it from then on
+    ///
+    /// The rotor is swept through one mechanical revolution and back in small
+    /// steps, held in position by the field at the align voltage (see
+    /// [`BLDC::with_align_voltage`]), which is stiffer than the PIDs could
+    /// be. How far it lags behind the field tells the torque holding it
+    /// there. Averaged per angle bin over both directions, which cancels the
+    /// friction, it becomes the [`CoggingMap`] fed forward to every torque
+    /// demand. It is stored with the rest of the calibration (see
+    /// [`BLDC::store_calibration`]).
+    ///
+    /// The map is in the unit of the torque targets: Iq measured by the
+    /// current sensor, or estimated from the phase resistance, and q voltage
+    /// when neither is known. Changing either means measuring it again.
+    ///
+    /// The shaft must be free to move and unloaded, anything holding it
+    /// against gravity ends up in the map. Takes about 35 s, `delay` paces
+    /// the steps. On failure, the previous map stays in use.
+    pub fn calibrate_cogging<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<CoggingMap, CoggingError<H::Error, A::Error>> {
+        let Some(zero) = self.motor.zero_electrical_angle else {
+            return Err(CoggingError::NotAligned);
+        };
+        if !self.motor.sensor.hardware().is_referenced() {
+            return Err(CoggingError::NotReferenced);
+        }
+
+        // The previous map would hide the cogging, and trapezoidal blocks
+        // would hold the rotor in 60° steps. Both are back if the sweep fails.
+        let previous = self.motor.cogging.take();
+        let modulation = core::mem::replace(&mut self.motor.modulation, Modulation::SpaceVector);
+        let result = self.sweep_cogging(delay, zero);
+        self.motor.modulation = modulation;
+
+        let released = self.motor.set_phase_voltage(0., 0., 0.);
+        self.halt();
+        let result = result.and_then(|map| released.map_err(CoggingError::Pwm).map(|()| map));
+
+        self.motor.cogging = result.as_ref().ok().copied().or(previous);
+        result
+    }
+
+    fn sweep_cogging<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+        zero: f32,
+    ) -> Result<CoggingMap, CoggingError<H::Error, A::Error>> {
+        let voltage = self.motor.align_voltage;
+        let steps = COGGING_BINS * COGGING_SWEEP_OVERSAMPLING;
+
+        self.motor.update_sensor().map_err(CoggingError::Sensor)?;
+        let start = self.motor.sensor.state().total_angle();
+        self.motor
+            .set_phase_voltage(0., voltage, normalize_angle(start * POLE as f32 - zero))
+            .map_err(CoggingError::Pwm)?;
+        delay.delay_ms(500);
+
+        let mut sweep = CoggingSweep::new();
+        for (forward, from, to) in [(true, start, start + TAU), (false, start + TAU, start)] {
+            for step in 1..=steps {
+                let setpoint = from + (to - from) * step as f32 / steps as f32;
+                let field = normalize_angle(setpoint * POLE as f32 - zero);
+                self.motor
+                    .set_phase_voltage(0., voltage, field)
+                    .map_err(CoggingError::Pwm)?;
+                delay.delay_us(COGGING_SWEEP_PERIOD);
+                self.motor.update_sensor().map_err(CoggingError::Sensor)?;
+
+                let angle = self.motor.electrical_angle();
+                if let Some(torque) = self.holding_torque(voltage, field, angle) {
+                    sweep.record(forward, self.motor.sensor.state().angle(), torque);
+                }
+            }
+        }
+
+        sweep.finish().ok_or(CoggingError::NoMovement)
+    }
+
+    /// Torque demand holding the rotor at the electrical `angle` against the
+    /// field of `voltage` on the d axis at `field`, in the unit of the torque
+    /// targets
+    ///
+    /// `None` when the current sensor fails, the sample is skipped.
+    fn holding_torque(&mut self, voltage: f32, field: f32, angle: f32) -> Option<f32> {
+        if I::PRESENT {
+            let currents = self.current_sensor.read_currents().ok()?;
+            return Some(currents.clarke().park(angle).q);
+        }
+
+        let q = voltage * Float::sin(field - angle);
+        match self.motor.phase_resistance {
+            Some(resistance) => Some(q / resistance),
+            None => Some(q),
+        }
+    }
+
+    /// Snapshot of the control loop for [`Telemetry`]
+    ///
+    /// [`Telemetry`]: crate::telemetry::Telemetry
+    pub fn telemetry(&self) -> Sample {
+        let state = self.motor.sensor.state();
+        let (p, i, d) = self.velocity_pid.terms();
+
+        let mut sample = Sample::new(self.motor.now())
+            .with(Channel::Angle, state.total_angle())
+            .with(Channel::Velocity, state.velocity().as_secs())
+            .with(Channel::VoltageQ, self.voltage.q)
+            .with(Channel::VoltageD, self.voltage.d)
+            .with(Channel::PidP, p)
+            .with(Channel::PidI, i)
+            .with(Channel::PidD, d)
+            .with(Channel::Dt, state.last_dt().to_micros() as f32 * 1e-6);
+
+        if I::PRESENT {
+            sample.set(Channel::CurrentQ, self.current.q);
+            sample.set(Channel::CurrentD, self.current.d);
+        }
+
+        match self.motion_control {
+            MotionControl::Velocity(target) => sample.set(Channel::Target, target.as_secs()),
+            MotionControl::Angle(target) | MotionControl::Torque(target) => {
+                sample.set(Channel::Target, target)
+            }
+            MotionControl::Haptic | MotionControl::LimitPos(..) => {}
+        }
+
+        sample
+    }
+
+    fn calculate_qd(&mut self, target: Velocity) -> (f32, f32) {
         let state = self.motor.sensor.state();
         let voltage_limit = self.motor.voltage_limit;
 
@@ -136,6 +641,11 @@ where
             .map(|kv| current / (kv * SQRT_3) / RPM_TO_RADS)
             .unwrap_or_default();
 
+        if I::PRESENT {
+            let angle = self.motor.electrical_angle();
+            return self.calculate_qd_current(target, voltage_bemf, angle);
+        }
+
         let q = self
             .phase_resistance
             .map(|r| target * r + voltage_bemf)
@@ -152,70 +662,361 @@ where
         (q, d)
     }
 
+    /// Current loop: `target` is Iq in A, Id is kept at zero unless field
+    /// weakening or MTPA ask otherwise, in the rotor frame at the electrical
+    /// `angle`
+    fn calculate_qd_current(&mut self, target: f32, voltage_bemf: f32, angle: f32) -> (f32, f32) {
+        let elapsed = self.motor.sensor.state().last_dt();
+
+        self.current = self.phase_current.park(angle);
+        let id = self.flux_current(target);
+
+        let q = self.iq_pid.compute(target, self.current.q, elapsed) + voltage_bemf;
+        let d = self.id_pid.compute(id, self.current.d, elapsed);
+
+        self.limit_voltage(q, d, voltage_bemf)
+    }
+
+    /// Id target in A for `iq` (in A), from MTPA and field weakening
+    fn flux_current(&self, iq: f32) -> f32 {
+        let mtpa = match (self.mtpa, self.motor.flux_linkage()) {
+            (Some(mtpa), Some(flux_linkage)) => mtpa.current(iq, flux_linkage),
+            _ => 0.,
+        };
+
+        match self.field_weakening {
+            Some(weakening) => (mtpa + weakening.current()).max(-weakening.max_current()),
+            None => mtpa,
+        }
+    }
+
+    /// Clamp `q` and `d` (in V) to the voltage limit
+    ///
+    /// With field weakening the voltage vector is kept within what the
+    /// modulation can put out, `d` first, and how far it went past feeds the
+    /// weakening along with the back-EMF `bemf`.
+    fn limit_voltage(&mut self, q: f32, d: f32, bemf: f32) -> (f32, f32) {
+        let voltage_limit = self.motor.voltage_limit;
+        let Some(weakening) = self.field_weakening.as_mut() else {
+            return (
+                q.clamp(-voltage_limit, voltage_limit),
+                d.clamp(-voltage_limit, voltage_limit),
+            );
+        };
+
+        let available = self
+            .motor
+            .modulation
+            .max_voltage(self.motor.voltage_power_supply)
+            .min(voltage_limit);
+        let dt = self.motor.sensor.state().last_dt().to_micros() as f32 * 1e-6;
+        weakening.update(Float::hypot(q, d), bemf, available, dt);
+
+        let d = d.clamp(-available, available);
+        let q_max = Float::sqrt(available * available - d * d);
+        (q.clamp(-q_max, q_max), d)
+    }
+
+    /// Apply `q` and `d` (in V) at the electrical `angle`
+    fn apply(&mut self, q: f32, d: f32, angle: f32) -> Result<(), A::Error> {
+        self.voltage = DQ { d, q };
+        self.applied = self.voltage.inverse_park(angle);
+        let v = self.motor.phase_voltage(f!(q), f!(d), angle);
+
+        self.motor
+            .pwm
+            .set_voltage(v, f!(self.motor.voltage_power_supply))
+    }
+
+    /// Run one iteration of the control loop
+    ///
+    /// Sensor failures and the limits of the [`Protection`] trip a fault
+    /// instead of panicking, the bridge then stays in the safe state until
+    /// [`BLDC::clear_fault`].
+    ///
+    /// [`Protection`]: super::Protection
     pub fn tick(&mut self) -> Result<(), A::Error> {
-        self.motor.sensor.update().expect("Failed to update sensor");
+        let mut amplitude = None;
+        if I::PRESENT && self.motor.fault().is_none() {
+            match self.current_sensor.read_currents() {
+                Ok(currents) => {
+                    self.phase_current = currents.clarke();
+                    amplitude = Some(self.phase_current.magnitude());
+                    self.motor.sensor.observe(self.applied, self.phase_current);
+                }
+                Err(_) => self.motor.latch(Fault::CurrentSensor),
+            }
+        }
+        // Keeps reading while tripped, so a fault can be cleared once the
+        // voltage is back
+        if V::PRESENT {
+            let reading = self.bus_voltage_sensor.read_bus_voltage();
+            self.motor.report_bus_voltage_guarded(reading);
+        }
+
+        if !self.motor.check_limits(amplitude)? {
+            self.halt();
+            return Ok(());
+        }
+        if !self.motor.update_sensor_guarded()? {
+            if self.motor.fault().is_some() {
+                self.halt();
+            }
+            return Ok(());
+        }
 
         let state = self.motor.sensor.state();
         let electrical_angle = self.motor.electrical_angle();
         let elapsed = state.last_dt();
 
-        let velocity_limit = self.motor.velocity_limit;
+        if let Some(startup) = &mut self.startup {
+            let direction = match self.motion_control {
+                MotionControl::Velocity(target) if target.as_secs() < 0. => -1.,
+                _ => 1.,
+            };
+            let dt = elapsed.to_micros() as f32 * 1e-6;
+            let current = startup.current();
+
+            if let Some((angle, _)) =
+                startup.update(dt, direction, state.velocity(), electrical_angle, POLE)
+            {
+                // Hand over without a kick once the ramp is done
+                self.velocity_pid.reset();
+                self.angle_pid.reset();
+
+                let (q, d) = match I::PRESENT {
+                    true => self.calculate_qd_current(current, 0., angle),
+                    false => (current, 0.),
+                };
+                return self.apply(q, d, angle);
+            }
+        }
+
+        self.outer_elapsed += elapsed;
+        if self.outer_count == 0 {
+            if let Some(commands) = self.commands {
+                if let Some(command) = commands.receive(&mut self.command_sequence) {
+                    self.set_command(command);
+                }
+                commands.publish(&state);
+            }
+
+            let elapsed = core::mem::replace(&mut self.outer_elapsed, Duration::from_ticks(0));
+            self.demand = self.outer_tick(state, elapsed);
+        }
+        self.outer_count = (self.outer_count + 1) % self.outer_divider;
+
+        if let Demand::Torque(torque) = self.demand {
+            let torque = torque.as_secs();
+            if !self.motor.check_stall(torque, state.velocity(), elapsed)? {
+                self.halt();
+                return Ok(());
+            }
+        }
+
+        let (q, d) = match self.demand {
+            Demand::Torque(torque) => {
+                let cogging = self.motor.cogging_torque(state.angle());
+                self.calculate_qd(torque + Velocity::per_sec(cogging))
+            }
+            Demand::Coast => (0., 0.),
+            Demand::Hold => return Ok(()),
+        };
 
-        let (q, d) = match self.motion_control {
+        self.apply(q, d, electrical_angle)
+    }
+
+    /// Forget the state of the controllers after a trip, so they start over
+    /// once the fault is cleared
+    fn halt(&mut self) {
+        self.velocity_pid.reset();
+        self.angle_pid.reset();
+        self.iq_pid.reset();
+        self.id_pid.reset();
+        if let Some(weakening) = self.field_weakening.as_mut() {
+            weakening.reset();
+        }
+        self.demand = Demand::Coast;
+        self.voltage = DQ::default();
+        self.applied = AlphaBeta::default();
+    }
+
+    /// Outer loop: turn the motion control into a torque demand, with
+    /// `elapsed` since the last outer tick
+    fn outer_tick(&mut self, state: SensorState, elapsed: Duration) -> Demand {
+        match self.motion_control {
             MotionControl::LimitPos(low, high) => {
-                todo!()
+                let total = state.total_angle_f64();
+                let (low, high) = (low as f64, high as f64);
+
+                // Free spinning inside the range, keep the controllers fresh for the next
+                // time the end stop is hit
+                if (low..=high).contains(&total) {
+                    self.angle_pid.reset();
+                    self.velocity_pid.reset();
+                    Demand::Coast
+                } else {
+                    // Limited to what the motor takes as it is now, the current
+                    // sensor or protection may have come after the mode
+                    let limit = self.torque_limit();
+                    self.velocity_pid = self.velocity_pid.update(|pid| pid.limit(limit));
+
+                    // The PIDs only see the error, taken at full resolution
+                    let velocity_target = self
+                        .angle_pid
+                        .compute((total.clamp(low, high) - total) as f32, 0., elapsed)
+                        .pipe(Velocity::per_sec);
+
+                    self.velocity_pid
+                        .compute(velocity_target, state.velocity(), elapsed)
+                        .pipe(Demand::Torque)
+                }
+            }
+            MotionControl::Torque(target) => Demand::Torque(Velocity::per_sec(target)),
+            MotionControl::Angle(target) if self.planner.is_some() => {
+                let now = self.motor.now();
+                let total = state.total_angle_f64() as f32;
+                let planner = self.planner.as_mut().unwrap();
+
+                if !planner.is_planned() {
+                    planner.reset(total, state.velocity().as_secs(), now);
+                }
+                if planner.target() != Some(target) {
+                    planner.plan(target, now);
+                }
+                let setpoint = planner.sample(now);
+
+                let velocity_target = self
+                    .angle_pid
+                    .compute(state.distance_to(setpoint.position), 0., elapsed)
+                    .pipe(|v| Velocity::per_sec(v + setpoint.velocity));
+
+                self.velocity_pid
+                    .compute(velocity_target, state.velocity(), elapsed)
+                    .pipe(Demand::Torque)
             }
-            MotionControl::Torque(target) => self.calculate_qd(Velocity::per_sec(target)),
             MotionControl::Angle(target) => {
-                if (target - state.total_angle()).abs() < 3e-2 {
-                    return Ok(());
+                let error = state.distance_to(target);
+                if error.abs() < ANGLE_TOLERANCE {
+                    return Demand::Hold;
                 }
 
                 let velocity_target = self
                     .angle_pid
-                    .compute(target, state.total_angle(), elapsed)
+                    .compute(error, 0., elapsed)
                     .pipe(Velocity::per_sec);
 
-                let torque = self
-                    .velocity_pid
-                    .compute(velocity_target, state.velocity(), elapsed);
-
-                self.calculate_qd(torque)
+                self.velocity_pid
+                    .compute(velocity_target, state.velocity(), elapsed)
+                    .pipe(Demand::Torque)
             }
             MotionControl::Velocity(target) => {
                 let velocity = self.velocity_pid.compute(target, state.velocity(), elapsed);
                 // log::info!("{} --({velocity})--> {target}", state.velocity());
-                self.calculate_qd(velocity)
-                // self.calculate_qd(target)
+                Demand::Torque(velocity)
             }
-            MotionControl::Ratchet(ref mut ratchet_state) => {
-                let step = ratchet_state.rad_per_step;
-                let total = state.total_angle();
+            MotionControl::Haptic => {
+                let Some(haptic) = self.haptic.as_mut() else {
+                    return Demand::Coast;
+                };
+                let total = state.total_angle_f64() as f32;
+                let torque = haptic.update(total, state.velocity().as_secs());
+                Demand::Torque(Velocity::per_sec(torque))
+            }
+        }
+    }
+}
 
-                // Find the nearest step
-                let target = (total / step).round() * step;
-                if (target - total).abs() < 1e-2 {
-                    return Ok(());
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+
+    use super::*;
+    use crate::{
+        motor::Protection,
+        sim::{SimParams, Simulator},
+    };
+
+    /// Most negative torque demand of `tick`, run at 10 kHz for 20 ms
+    fn peak_demand(sim: &Simulator, mut tick: impl FnMut() -> Demand) -> f32 {
+        (0..200)
+            .map(|_| {
+                sim.advance(Duration::micros(100));
+                match tick() {
+                    Demand::Torque(torque) => torque.as_secs(),
+                    _ => 0.,
                 }
+            })
+            .fold(0., f32::min)
+    }
 
-                let velocity_target = self
-                    .angle_pid
-                    .compute(target, total, elapsed)
-                    .pipe(Velocity::per_sec);
-
-                let velocity = self
-                    .velocity_pid
-                    .compute(velocity_target, state.velocity(), elapsed)
-                    .clamp(-velocity_limit, velocity_limit);
+    #[test]
+    fn endstop_limit() {
+        // Far past the bound, the spring alone would ask for tens of volts
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .with_voltage_limit(2.)
+            .aligned(&mut sim.clock())
+            .unwrap()
+            .foc()
+            .to_limit_pos(-10., -9.);
+        let peak = peak_demand(&sim, || {
+            foc.tick().unwrap();
+            foc.demand
+        });
+        assert_eq!(peak, -2.);
+
+        // Current targets, up to the max current of the protection
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .with_phase_resistance(2.3)
+            .with_protection(Protection::new().with_max_current(2.))
+            .aligned(&mut sim.clock())
+            .unwrap()
+            .foc()
+            .to_limit_pos(-10., -9.)
+            .with_current_sensor(sim.current_sensor());
+        let peak = peak_demand(&sim, || {
+            foc.tick().unwrap();
+            foc.demand
+        });
+        assert_eq!(peak, -2.);
+        assert_eq!(foc.fault(), None);
+    }
 
-                self.calculate_qd(velocity)
+    #[test]
+    fn outer_loop_divider() {
+        let sim = Simulator::new(SimParams::default());
+        let commands = Box::leak(Box::new(CommandChannel::new()));
+        let mut foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .aligned(&mut sim.clock())
+            .unwrap()
+            .foc()
+            .to_torque(1.)
+            .with_outer_loop_divider(10)
+            .with_commands(commands);
+
+        for tick in 0..50 {
+            sim.advance(Duration::micros(100));
+            foc.tick().unwrap();
+            if tick == 0 {
+                commands.send(Command::Torque(-1.));
             }
-        };
 
-        let v = self.motor.phase_voltage(f!(q), f!(d), electrical_angle);
-
-        self.motor
-            .pwm
-            .set_voltage(v, f!(self.motor.voltage_power_supply))
+            // The time since the last outer tick, which picks up the command
+            let since = Duration::micros(100 * (tick % 10));
+            assert_eq!(foc.outer_elapsed, since, "{tick}");
+            let torque = match foc.demand {
+                Demand::Torque(torque) => torque.as_secs(),
+                _ => 0.,
+            };
+            assert_eq!(torque, if tick < 10 { 1. } else { -1. }, "{tick}");
+        }
     }
 }
@@ -0,0 +1,426 @@
+use core::f32::consts::PI;
+
+use heapless::{Deque, Vec};
+use num_traits::Float;
+
+/// Most explicit detent positions a profile can hold
+pub const MAX_DETENTS: usize = 32;
+
+/// Most snap points a profile can hold
+pub const MAX_SNAP_POINTS: usize = 8;
+
+/// Detent crossings kept until the application polls them, older ones are
+/// dropped first
+const EVENT_QUEUE: usize = 8;
+
+/// Default peak torque of a detent
+const DEFAULT_DETENT_STRENGTH: f32 = 1.5;
+
+/// Default end stop stiffness, per rad past the bound
+const DEFAULT_ENDSTOP_STIFFNESS: f32 = 4.;
+
+/// Default damping, per rad/s
+const DEFAULT_DAMPING: f32 = 0.01;
+
+/// How a haptic knob feels
+///
+/// Torques are in the unit of [`MotionControl::Torque`]: Iq in A with a
+/// current sensor, q voltage in V without one. Angles are total angles in rad,
+/// so profiles can span several revolutions.
+///
+/// [`MotionControl::Torque`]: crate::motor::MotionControl::Torque
+#[derive(Clone, Debug, PartialEq)]
+pub struct HapticProfile {
+    detents: Detents,
+    detent_strength: f32,
+    free_spin: Option<(f32, f32)>,
+    endstops: Option<(f32, f32)>,
+    endstop_stiffness: f32,
+    snap_points: Vec<SnapPoint, MAX_SNAP_POINTS>,
+    damping: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Detents {
+    None,
+
+    /// Evenly spaced detents, this many per revolution, one of them at 0
+    Even(u16),
+
+    /// Detents at these angles, sorted
+    At(Vec<f32, MAX_DETENTS>),
+}
+
+/// A position the knob is pulled into from up to `range` rad away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SnapPoint {
+    pub position: f32,
+
+    /// Peak torque
+    pub strength: f32,
+
+    pub range: f32,
+}
+
+/// The knob settled into another detent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DetentEvent {
+    /// Which detent: the step number for [`Detents::Even`], counting full
+    /// revolutions too, or the position in the list for [`Detents::At`]
+    pub index: i32,
+
+    /// Angle of the detent in rad
+    pub position: f32,
+}
+
+impl HapticProfile {
+    /// Spins freely, without detents or end stops
+    pub fn new() -> Self {
+        Self {
+            detents: Detents::None,
+            detent_strength: DEFAULT_DETENT_STRENGTH,
+            free_spin: None,
+            endstops: None,
+            endstop_stiffness: DEFAULT_ENDSTOP_STIFFNESS,
+            snap_points: Vec::new(),
+            damping: DEFAULT_DAMPING,
+        }
+    }
+
+    /// An empty list of positions is the same as [`Detents::None`]
+    pub fn with_detents(mut self, detents: Detents) -> Self {
+        self.detents = match detents {
+            Detents::At(positions) if positions.is_empty() => Detents::None,
+            Detents::At(mut positions) => {
+                positions.sort_unstable_by(f32::total_cmp);
+                Detents::At(positions)
+            }
+            detents => detents,
+        };
+        self
+    }
+
+    /// `count` evenly spaced detents per revolution
+    pub fn with_even_detents(self, count: u16) -> Self {
+        self.with_detents(Detents::Even(count))
+    }
+
+    /// Detents at `positions`, in rad
+    ///
+    /// # Panics
+    ///
+    /// If there are more than [`MAX_DETENTS`] positions.
+    pub fn with_detents_at(self, positions: &[f32]) -> Self {
+        let positions = Vec::from_slice(positions).expect("Too many detents");
+        self.with_detents(Detents::At(positions))
+    }
+
+    /// Peak torque pulling the knob into the nearest detent
+    pub fn with_detent_strength(mut self, strength: f32) -> Self {
+        self.detent_strength = strength;
+        self
+    }
+
+    /// No detents between `low` and `high`
+    pub fn with_free_spin(mut self, low: f32, high: f32) -> Self {
+        assert!(low < high);
+        self.free_spin = Some((low, high));
+        self
+    }
+
+    /// Walls at `low` and `high`, pushing back with `stiffness` per rad past
+    /// them
+    pub fn with_endstops(mut self, low: f32, high: f32, stiffness: f32) -> Self {
+        assert!(low < high);
+        self.endstops = Some((low, high));
+        self.endstop_stiffness = stiffness;
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If there are more than [`MAX_SNAP_POINTS`] snap points.
+    pub fn with_snap_point(mut self, position: f32, strength: f32, range: f32) -> Self {
+        assert!(range > 0.);
+        self.snap_points
+            .push(SnapPoint {
+                position,
+                strength,
+                range,
+            })
+            .expect("Too many snap points");
+        self
+    }
+
+    /// Torque opposing the motion, per rad/s
+    pub fn with_damping(mut self, damping: f32) -> Self {
+        self.damping = damping;
+        self
+    }
+
+    pub fn detents(&self) -> &Detents {
+        &self.detents
+    }
+
+    /// Torque at `angle` (in rad) moving at `velocity` (in rad/s)
+    pub fn torque(&self, angle: f32, velocity: f32) -> f32 {
+        let mut torque = -self.damping * velocity;
+
+        if let Some((low, high)) = self.endstops {
+            if angle < low || angle > high {
+                // Nothing but the wall outside of the range
+                return torque - self.endstop_stiffness * (angle - angle.clamp(low, high));
+            }
+        }
+
+        if let Some((detent, half)) = self.nearest(angle) {
+            torque += pull(angle - detent.position, half, self.detent_strength);
+        }
+
+        for snap in &self.snap_points {
+            torque += pull(angle - snap.position, snap.range, snap.strength);
+        }
+
+        torque
+    }
+
+    /// Detent the knob at `angle` settles into, if any
+    pub fn nearest_detent(&self, angle: f32) -> Option<DetentEvent> {
+        self.nearest(angle).map(|(detent, _)| detent)
+    }
+
+    /// Nearest detent and half the distance to the neighbour on the side of
+    /// `angle`, past which it stops pulling
+    fn nearest(&self, angle: f32) -> Option<(DetentEvent, f32)> {
+        if let Some((low, high)) = self.free_spin {
+            if (low..=high).contains(&angle) {
+                return None;
+            }
+        }
+
+        let detent = |index: usize, positions: &[f32]| DetentEvent {
+            index: index as i32,
+            position: positions[index],
+        };
+
+        match self.detents {
+            Detents::None | Detents::Even(0) => None,
+            Detents::Even(count) => {
+                let step = 2. * PI / count as f32;
+                let index = Float::round(angle / step);
+                let detent = DetentEvent {
+                    index: index as i32,
+                    position: index * step,
+                };
+                Some((detent, step / 2.))
+            }
+            Detents::At(ref positions) if positions.len() == 1 => Some((detent(0, positions), PI)),
+            Detents::At(ref positions) => {
+                // Past either end, the outermost detent pulls as far as it does
+                // towards its neighbour
+                let last = positions.len() - 1;
+                let (lower, upper) = match positions.iter().position(|p| *p > angle) {
+                    Some(0) => (0, 1),
+                    Some(upper) => (upper - 1, upper),
+                    None => (last - 1, last),
+                };
+
+                let half = (positions[upper] - positions[lower]) / 2.;
+                let nearest = if angle < positions[lower] + half {
+                    lower
+                } else {
+                    upper
+                };
+                Some((detent(nearest, positions), half))
+            }
+        }
+    }
+}
+
+impl Default for HapticProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Torque pulling towards a point `offset` rad away, peaking at `strength`
+/// halfway through `range` and fading out at both ends
+fn pull(offset: f32, range: f32, strength: f32) -> f32 {
+    if range <= 0. || offset.abs() >= range {
+        return 0.;
+    }
+
+    -strength * Float::sin(PI * offset / range)
+}
+
+/// Runs a [`HapticProfile`] and keeps track of detent crossings
+#[derive(Clone, Debug)]
+pub struct Haptic {
+    profile: HapticProfile,
+    detent: Option<DetentEvent>,
+    events: Deque<DetentEvent, EVENT_QUEUE>,
+}
+
+impl Haptic {
+    pub fn new(profile: HapticProfile) -> Self {
+        Self {
+            profile,
+            detent: None,
+            events: Deque::new(),
+        }
+    }
+
+    pub fn profile(&self) -> &HapticProfile {
+        &self.profile
+    }
+
+    /// Switch to another profile, keeping the crossings not polled yet
+    ///
+    /// Crossings of the new profile count from the detent the knob is in on
+    /// the next update, not from one of the old profile.
+    pub fn set_profile(&mut self, profile: HapticProfile) {
+        self.profile = profile;
+        self.detent = None;
+    }
+
+    /// Oldest detent crossing not polled yet
+    pub fn poll_event(&mut self) -> Option<DetentEvent> {
+        self.events.pop_front()
+    }
+
+    /// Detent the knob was last in
+    pub fn detent(&self) -> Option<DetentEvent> {
+        self.detent
+    }
+
+    /// Torque to apply at `angle` (in rad) moving at `velocity` (in rad/s),
+    /// recording detent crossings
+    pub fn update(&mut self, angle: f32, velocity: f32) -> f32 {
+        let detent = self.profile.nearest_detent(angle);
+
+        if let Some(detent) = detent {
+            if self.detent.is_some_and(|prev| prev != detent) {
+                if self.events.is_full() {
+                    self.events.pop_front();
+                }
+                let _ = self.events.push_back(detent);
+            }
+            self.detent = Some(detent);
+        }
+
+        self.profile.torque(angle, velocity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_detents() {
+        let profile = HapticProfile::new().with_detents_at(&[]);
+        assert_eq!(profile.detents(), &Detents::None);
+        assert_eq!(profile.nearest_detent(1.), None);
+        assert_eq!(profile.torque(1., 0.), 0.);
+    }
+
+    #[test]
+    fn detents_at() {
+        let profile = HapticProfile::new().with_detents_at(&[1., -1.]);
+        assert_eq!(profile.nearest_detent(-3.).unwrap().position, -1.);
+        assert_eq!(profile.nearest_detent(0.1).unwrap().index, 1);
+        assert!(profile.torque(0.5, 0.) > 0.);
+        assert!(profile.torque(1.5, 0.) < 0.);
+    }
+
+    #[test]
+    fn set_profile() {
+        let mut haptic = Haptic::new(HapticProfile::new().with_even_detents(4));
+        haptic.update(0.1, 0.);
+        assert_eq!(haptic.detent().unwrap().index, 0);
+
+        // Detent 0 of the new profile isn't a crossing from detent 0 of the old one
+        haptic.set_profile(HapticProfile::new().with_detents_at(&[1.]));
+        assert_eq!(haptic.detent(), None);
+        haptic.update(0.1, 0.);
+        assert_eq!(haptic.poll_event(), None);
+        assert_eq!(haptic.detent().unwrap().position, 1.);
+
+        haptic.set_profile(HapticProfile::new());
+        haptic.update(0.1, 0.);
+        assert_eq!(haptic.detent(), None);
+    }
+
+    #[test]
+    fn events() {
+        let step = PI / 4.;
+        let mut haptic = Haptic::new(HapticProfile::new().with_even_detents(8));
+
+        // Into the first detent, then across three more
+        haptic.update(0.1, 0.);
+        assert_eq!(haptic.poll_event(), None);
+        for index in 1..=3 {
+            haptic.update(index as f32 * step + 0.1, 0.);
+            haptic.update(index as f32 * step - 0.1, 0.);
+        }
+        for index in 1..=3 {
+            let event = haptic.poll_event().unwrap();
+            assert_eq!(event.index, index);
+            assert!((event.position - index as f32 * step).abs() < 1e-6);
+        }
+        assert_eq!(haptic.poll_event(), None);
+
+        // Back past 0, the indices count on through the revolution
+        for index in (-2..=2).rev() {
+            haptic.update(index as f32 * step, 0.);
+        }
+        for index in (-2..=2).rev() {
+            assert_eq!(haptic.poll_event().unwrap().index, index);
+        }
+        assert_eq!(haptic.detent().unwrap().index, -2);
+    }
+
+    #[test]
+    fn event_queue() {
+        let step = PI / 4.;
+        let mut haptic = Haptic::new(HapticProfile::new().with_even_detents(8));
+
+        for index in 0..=10 {
+            haptic.update(index as f32 * step, 0.);
+        }
+
+        // The oldest two crossings made room for the last two
+        for index in 3..=10 {
+            assert_eq!(haptic.poll_event().unwrap().index, index);
+        }
+        assert_eq!(haptic.poll_event(), None);
+    }
+
+    #[test]
+    fn torque() {
+        // Pulled into the snap point from either side, within its range
+        let profile = HapticProfile::new().with_snap_point(1., 0.5, 0.4);
+        assert!(profile.torque(0.8, 0.) > 0.);
+        assert!(profile.torque(1.2, 0.) < 0.);
+        assert_eq!(profile.torque(1., 0.), 0.);
+        assert_eq!(profile.torque(1.5, 0.), 0.);
+
+        // Detents pull towards the nearest one, except in the free spin range
+        let profile = HapticProfile::new()
+            .with_even_detents(8)
+            .with_free_spin(-1., 1.);
+        assert_eq!(profile.torque(0.2, 0.), 0.);
+        assert_eq!(profile.nearest_detent(0.2), None);
+        assert!(profile.torque(2. * PI / 8. * 2. + 0.2, 0.) < 0.);
+        assert!(profile.torque(-2. * PI / 8. * 2. - 0.2, 0.) > 0.);
+
+        // Walls push back, and damping opposes the motion
+        let profile = HapticProfile::new()
+            .with_even_detents(8)
+            .with_endstops(-1., 1., 4.);
+        assert_eq!(profile.torque(1.5, 0.), -2.);
+        assert_eq!(profile.torque(-1.25, 0.), 1.);
+        assert!((profile.torque(0., 10.) + 0.1).abs() < 1e-6);
+        assert!((profile.torque(1.5, -10.) + 1.9).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,388 @@
+use core::f32::consts::{PI, SQRT_3};
+
+use embedded_hal::{
+    delay::DelayNs,
+    pwm::{ErrorType, SetDutyCycle},
+};
+use num_traits::Float;
+
+use crate::{
+    RPM_TO_RADS,
+    motor::{AlignError, BLDC, CurrentSensor, DQ},
+    sensor::SensorHardware,
+    util::Clock,
+};
+
+/// Current samples averaged for each measurement
+const CURRENT_SAMPLES: u32 = 32;
+
+/// How long the current is let to rise before sampling it for inductance
+const INDUCTANCE_PROBE_US: u32 = 100;
+
+/// Electrical revolutions the field turns through while counting pole pairs
+const POLE_PAIR_REVOLUTIONS: u32 = 2;
+
+/// Steps of each electrical revolution while counting pole pairs
+const POLE_PAIR_STEPS: u32 = 500;
+
+/// Result of the measurements with the errors of sensor `H`, PWM channel `A`
+/// and current sensor `I`
+type IdentifyResult<T, H, A, I> = Result<
+    T,
+    IdentifyError<
+        <H as SensorHardware>::Error,
+        <A as ErrorType>::Error,
+        <I as CurrentSensor>::Error,
+    >,
+>;
+
+/// Electrical and magnetic parameters of a motor
+///
+/// Can be measured with [`BLDC::identify`], stored with
+/// [`MotorParams::to_bytes`] and handed back to a driver with
+/// [`BLDC::with_params`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MotorParams {
+    pub pole_pairs: u8,
+
+    /// Phase resistance in Ω
+    pub phase_resistance: f32,
+
+    /// Phase inductance in H
+    pub phase_inductance: f32,
+
+    /// Velocity constant in RPM/V
+    pub kv: f32,
+}
+
+impl MotorParams {
+    /// Length of the serialized form
+    pub const SIZE: usize = 13;
+
+    /// Little endian serialization
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.pole_pairs;
+        bytes[1..5].copy_from_slice(&self.phase_resistance.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.phase_inductance.to_le_bytes());
+        bytes[9..13].copy_from_slice(&self.kv.to_le_bytes());
+        bytes
+    }
+
+    /// Reverse of [`MotorParams::to_bytes`], `None` if the values don't make
+    /// sense for a motor
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Option<Self> {
+        let f32_at =
+            |i: usize| f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        let params = Self {
+            pole_pairs: bytes[0],
+            phase_resistance: f32_at(1),
+            phase_inductance: f32_at(5),
+            kv: f32_at(9),
+        };
+
+        let valid = params.pole_pairs > 0
+            && [params.phase_resistance, params.phase_inductance, params.kv]
+                .iter()
+                .all(|v| v.is_finite() && *v > 0.);
+
+        valid.then_some(params)
+    }
+}
+
+#[derive(Debug)]
+pub enum IdentifyError<S, P, I> {
+    Sensor(S),
+    Pwm(P),
+    Current(I),
+
+    /// The rotor did not follow the rotating field
+    NoMovement,
+
+    /// The rotor moved as if it had `measured` pole pairs, not the `POLE` the
+    /// driver was built with
+    PolePairs {
+        expected: u8,
+        measured: u8,
+    },
+
+    /// Alignment failed
+    Align(AlignError<S, P>),
+
+    /// Not enough current flowed, check the wiring or raise the test voltage
+    NoCurrent,
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    H: SensorHardware,
+    A: SetDutyCycle,
+    B: SetDutyCycle<Error = A::Error>,
+    C: SetDutyCycle<Error = A::Error>,
+    K: Clock,
+{
+    /// Measure pole pairs, phase resistance, phase inductance and Kv by
+    /// injecting `voltage` (in V) into the motor
+    ///
+    /// The shaft must be free to spin: the field is first turned through a
+    /// few electrical revolutions, the mechanical travel on the sensor gives
+    /// the pole pairs. The driver is then aligned with [`BLDC::align`], the
+    /// rotor held still to measure R and L, and finally spun up under voltage
+    /// control to measure the back-EMF. `voltage` should push roughly the
+    /// rated current through a winding, which is fairly low for gimbal motors.
+    ///
+    /// On success the driver uses the measured values. Pole pairs other than
+    /// `POLE` are an error, the rest can't be measured with the wrong ones.
+    pub fn identify<I, D>(
+        &mut self,
+        current_sensor: &mut I,
+        delay: &mut D,
+        voltage: f32,
+    ) -> IdentifyResult<MotorParams, H, A, I>
+    where
+        I: CurrentSensor,
+        D: DelayNs,
+    {
+        // Release the phases however the measurements end
+        let result = self.measure_params(current_sensor, delay, voltage);
+        let released = self.set_phase_voltage(0., 0., 0.);
+        self.sensor.reset();
+        let params = result?;
+        released.map_err(IdentifyError::Pwm)?;
+
+        self.set_params(params);
+        Ok(params)
+    }
+
+    fn measure_params<I: CurrentSensor, D: DelayNs>(
+        &mut self,
+        current_sensor: &mut I,
+        delay: &mut D,
+        voltage: f32,
+    ) -> IdentifyResult<MotorParams, H, A, I> {
+        let pole_pairs = self.identify_pole_pairs::<I, D>(delay, voltage)?;
+        if pole_pairs != POLE {
+            return Err(IdentifyError::PolePairs {
+                expected: POLE,
+                measured: pole_pairs,
+            });
+        }
+        self.align(delay).map_err(IdentifyError::Align)?;
+
+        let phase_resistance = self.identify_resistance(current_sensor, delay, voltage)?;
+        let phase_inductance =
+            self.identify_inductance(current_sensor, delay, voltage, phase_resistance)?;
+        let kv = self.identify_kv(current_sensor, delay, voltage, phase_resistance)?;
+
+        Ok(MotorParams {
+            pole_pairs,
+            phase_resistance,
+            phase_inductance,
+            kv,
+        })
+    }
+
+    /// Drag the rotor through a few electrical revolutions with the field,
+    /// pole pairs are electrical revolutions per mechanical one
+    fn identify_pole_pairs<I: CurrentSensor, D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+        voltage: f32,
+    ) -> IdentifyResult<u8, H, A, I> {
+        self.sensor.reset();
+        self.set_phase_voltage(0., voltage, 0.)
+            .map_err(IdentifyError::Pwm)?;
+        delay.delay_ms(500);
+        self.update_sensor().map_err(IdentifyError::Sensor)?;
+        let start = self.sensor.state().total_angle();
+
+        for step in 1..=POLE_PAIR_REVOLUTIONS * POLE_PAIR_STEPS {
+            let angle = 2. * PI * (step % POLE_PAIR_STEPS) as f32 / POLE_PAIR_STEPS as f32;
+            self.set_phase_voltage(0., voltage, angle)
+                .map_err(IdentifyError::Pwm)?;
+            delay.delay_ms(2);
+            self.update_sensor().map_err(IdentifyError::Sensor)?;
+        }
+
+        // Whichever way the sensor counts
+        let travel = (self.sensor.state().total_angle() - start).abs();
+        let pole_pairs = Float::round(POLE_PAIR_REVOLUTIONS as f32 * 2. * PI / travel);
+        if !(1. ..=u8::MAX as f32).contains(&pole_pairs) {
+            return Err(IdentifyError::NoMovement);
+        }
+
+        Ok(pole_pairs as u8)
+    }
+
+    /// Hold the rotor on the d axis and read the steady state current
+    fn identify_resistance<I: CurrentSensor, D: DelayNs>(
+        &mut self,
+        current_sensor: &mut I,
+        delay: &mut D,
+        voltage: f32,
+    ) -> IdentifyResult<f32, H, A, I> {
+        self.set_phase_voltage(0., voltage, 0.)
+            .map_err(IdentifyError::Pwm)?;
+        delay.delay_ms(100);
+
+        let mut sum = 0.;
+        for _ in 0..CURRENT_SAMPLES {
+            sum += self.read_current_dq(current_sensor, 0.)?.d;
+            delay.delay_us(100);
+        }
+        let current = sum / CURRENT_SAMPLES as f32;
+
+        if current.abs() < 1e-3 {
+            return Err(IdentifyError::NoCurrent);
+        }
+
+        Ok(voltage / current)
+    }
+
+    /// Step the d axis voltage and look at how fast the current rises
+    ///
+    /// `i(t) = V/R * (1 - e^(-t * R/L))`, so `L = -R * t / ln(1 - i * R/V)`
+    fn identify_inductance<I: CurrentSensor, D: DelayNs>(
+        &mut self,
+        current_sensor: &mut I,
+        delay: &mut D,
+        voltage: f32,
+        resistance: f32,
+    ) -> IdentifyResult<f32, H, A, I> {
+        let mut sum = 0.;
+        for _ in 0..CURRENT_SAMPLES {
+            self.set_phase_voltage(0., 0., 0.)
+                .map_err(IdentifyError::Pwm)?;
+            delay.delay_ms(5);
+
+            let start = self.now();
+            self.set_phase_voltage(0., voltage, 0.)
+                .map_err(IdentifyError::Pwm)?;
+            delay.delay_us(INDUCTANCE_PROBE_US);
+            let current = self.read_current_dq(current_sensor, 0.)?.d;
+            let t = (self.now() - start).to_micros() as f32 * 1e-6;
+
+            // Close to the steady state the log blows up, clamp to keep the
+            // estimate finite
+            let ratio = (current * resistance / voltage).clamp(1e-3, 0.99);
+            sum += -resistance * t / Float::ln(1. - ratio);
+        }
+
+        Ok(sum / CURRENT_SAMPLES as f32)
+    }
+
+    /// Spin the motor with a fixed q voltage and compare speed to the voltage
+    /// left after the resistive drop
+    fn identify_kv<I: CurrentSensor, D: DelayNs>(
+        &mut self,
+        current_sensor: &mut I,
+        delay: &mut D,
+        voltage: f32,
+        resistance: f32,
+    ) -> IdentifyResult<f32, H, A, I> {
+        // Spin up, then measure over a window
+        let mut iq = 0.;
+        let mut samples = 0;
+        let mut window = None;
+
+        let start = self.now();
+        loop {
+            self.update_sensor().map_err(IdentifyError::Sensor)?;
+            let angle = self.electrical_angle();
+            let elapsed = (self.now() - start).to_millis();
+
+            if elapsed >= 1500 {
+                break;
+            }
+
+            if elapsed >= 1000 {
+                let state = self.sensor.state();
+                window.get_or_insert((self.now(), state.total_angle()));
+                iq += self.read_current_dq(current_sensor, angle)?.q;
+                samples += 1;
+            }
+
+            self.set_phase_voltage(voltage, 0., angle)
+                .map_err(IdentifyError::Pwm)?;
+            delay.delay_us(100);
+        }
+
+        let (window_start, angle_start) = window.ok_or(IdentifyError::NoMovement)?;
+        let dt = (self.now() - window_start).to_micros() as f32 * 1e-6;
+        let velocity = (self.sensor.state().total_angle() - angle_start) / dt;
+        let iq = iq / samples as f32;
+
+        let voltage_bemf = voltage - iq * resistance;
+        if velocity.abs() < 1e-1 || voltage_bemf <= 0. {
+            return Err(IdentifyError::NoMovement);
+        }
+
+        Ok(velocity.abs() / (voltage_bemf * SQRT_3 * RPM_TO_RADS))
+    }
+
+    fn read_current_dq<I: CurrentSensor>(
+        &mut self,
+        current_sensor: &mut I,
+        angle: f32,
+    ) -> IdentifyResult<DQ, H, A, I> {
+        Ok(current_sensor
+            .read_currents()
+            .map_err(IdentifyError::Current)?
+            .clarke()
+            .park(angle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimParams, Simulator};
+
+    fn assert_close(measured: f32, actual: f32, tolerance: f32) {
+        assert!(
+            (measured - actual).abs() < tolerance * actual,
+            "{measured} != {actual}"
+        );
+    }
+
+    #[test]
+    fn identify() {
+        let sim = Simulator::new(SimParams::default());
+        sim.set_angle(1.);
+        let mut motor = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder());
+
+        let params = motor
+            .identify(&mut sim.current_sensor(), &mut sim.clock(), 2.)
+            .unwrap();
+        let actual = sim.params();
+        assert_eq!(params.pole_pairs, actual.pole_pairs);
+        assert_close(params.phase_resistance, actual.phase_resistance, 0.01);
+        assert_close(params.phase_inductance, actual.phase_inductance, 0.05);
+        assert_close(params.kv, actual.kv, 0.03);
+        assert_eq!(motor.params(), Some(params));
+    }
+
+    #[test]
+    fn wrong_pole_pairs() {
+        let sim = Simulator::new(SimParams::default());
+        let mut motor = BLDC::new::<4>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder());
+
+        let result = motor.identify(&mut sim.current_sensor(), &mut sim.clock(), 2.);
+        assert!(
+            matches!(
+                result,
+                Err(IdentifyError::PolePairs {
+                    expected: 4,
+                    measured: 7
+                })
+            ),
+            "{result:?}"
+        );
+        assert_eq!(motor.params(), None);
+    }
+}
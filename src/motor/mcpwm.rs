@@ -0,0 +1,91 @@
+use core::convert::Infallible;
+
+use embedded_hal::pwm::{ErrorType, SetDutyCycle};
+use esp_hal::mcpwm::{
+    PwmPeripheral,
+    operator::{DeadTimeCfg, LinkedPins, PWMStream},
+};
+
+use crate::motor::PhaseEnable;
+
+/// One phase of a 6-PWM gate driver: the high and low side driven by the two
+/// outputs of an MCPWM operator, complementary with dead time
+///
+/// Meant for a timer counting up and down (`PwmWorkingMode::UpDown`), which
+/// centers the pulses of all three phases on the same instant. Create the
+/// pins with `PwmPinConfig::UP_DOWN_ACTIVE_HIGH` on the high side and
+/// `PwmPinConfig::EMPTY` on the low side:
+///
+/// ```ignore
+/// let clock_cfg = PeripheralClockConfig::with_frequency(Rate::from_mhz(16)).unwrap();
+/// let mut mcpwm = McPwm::new(peripherals.MCPWM0, clock_cfg);
+///
+/// // 20 kHz center aligned, 400 steps of duty
+/// let period = 400;
+/// let timer_clock_cfg = clock_cfg
+///     .timer_clock_with_frequency(period, PwmWorkingMode::UpDown, Rate::from_khz(20))
+///     .unwrap();
+///
+/// let pins = mcpwm.operator0.with_linked_pins(
+///     peripherals.GPIO7,
+///     PwmPinConfig::UP_DOWN_ACTIVE_HIGH,
+///     peripherals.GPIO8,
+///     PwmPinConfig::EMPTY,
+///     DeadTimeCfg::new_ahc(),
+/// );
+/// // 500 ns of dead time at 16 MHz
+/// let a = ComplementaryPhase::new(pins, period, 8);
+/// /* b and c on operator1 and operator2 */
+///
+/// mcpwm.timer0.start(timer_clock_cfg);
+/// ```
+pub struct ComplementaryPhase<'d, PWM, const OP: u8> {
+    pins: LinkedPins<'d, PWM, OP>,
+    period: u16,
+}
+
+impl<'d, PWM: PwmPeripheral, const OP: u8> ComplementaryPhase<'d, PWM, OP> {
+    /// `period` is the one the timer is started with, `dead_time` is in
+    /// cycles of the peripheral clock, inserted before each side turns on
+    pub fn new(mut pins: LinkedPins<'d, PWM, OP>, period: u16, dead_time: u16) -> Self {
+        pins.set_rising_edge_deadtime(dead_time);
+        pins.set_falling_edge_deadtime(dead_time);
+        pins.set_deadtime_cfg(DeadTimeCfg::new_ahc());
+
+        Self { pins, period }
+    }
+
+    pub fn into_inner(self) -> LinkedPins<'d, PWM, OP> {
+        self.pins
+    }
+}
+
+impl<PWM, const OP: u8> ErrorType for ComplementaryPhase<'_, PWM, OP> {
+    type Error = Infallible;
+}
+
+impl<PWM: PwmPeripheral, const OP: u8> SetDutyCycle for ComplementaryPhase<'_, PWM, OP> {
+    fn max_duty_cycle(&self) -> u16 {
+        self.period
+    }
+
+    /// Duty of the high side, the low side is on for the rest of the period
+    /// minus the dead time
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        self.pins.set_timestamp_a(duty.min(self.period));
+        Ok(())
+    }
+}
+
+impl<PWM: PwmPeripheral, const OP: u8> PhaseEnable for ComplementaryPhase<'_, PWM, OP> {
+    /// Off, both outputs follow the idle low side stream, so both switches
+    /// are open
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), Self::Error> {
+        let config = match enabled {
+            true => DeadTimeCfg::new_ahc(),
+            false => DeadTimeCfg::new_bypass().set_output_swap(PWMStream::PWMA, true),
+        };
+        self.pins.set_deadtime_cfg(config);
+        Ok(())
+    }
+}
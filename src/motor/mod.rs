@@ -1,30 +1,69 @@
-use core::f32::consts::PI;
+use core::f32::consts::{PI, SQRT_3};
 
 use cordic::sin_cos;
 use embedded_hal::pwm::SetDutyCycle;
-use esp_hal::delay::Delay;
 use fixed::types::I16F16;
 
 use crate::{
-    SQRT3_2, f,
-    sensor::{Sensor, SensorHardware},
-    util::Velocity,
+    RPM_TO_RADS, f,
+    sensor::{GlitchPolicy, Sensor, SensorHardware, VelocityEstimator},
+    util::{Clock, Instant, SystemClock},
 };
 
-mod_use::mod_use![open_loop, foc];
+mod_use::mod_use![
+    open_loop,
+    foc,
+    current,
+    identify,
+    align,
+    calibration,
+    haptic,
+    sensorless,
+    six_step,
+    axis,
+    command,
+    fault,
+    bus,
+    modulation,
+    weakening,
+    cogging
+];
+
+#[cfg(target_arch = "xtensa")]
+mod adc;
+#[cfg(target_arch = "xtensa")]
+mod mcpwm;
+#[cfg(target_arch = "xtensa")]
+pub use adc::*;
+#[cfg(target_arch = "xtensa")]
+pub use mcpwm::*;
 
 const DEFAULT_VOLTAGE_SUPPLY: f32 = 12.;
+const DEFAULT_ALIGN_VOLTAGE: f32 = 3.;
 
-pub struct BLDC<H, A, B, C, const POLE: u8> {
-    sensor: Sensor<H>,
+/// Switches all phases on or off, returning whether it succeeded
+type PhaseSwitch<A, B, C> = fn(&mut ThreePhasePwm<A, B, C>, bool) -> bool;
+
+pub struct BLDC<H, A, B, C, const POLE: u8, K = SystemClock> {
+    sensor: Sensor<H, K>,
     pwm: ThreePhasePwm<A, B, C>,
     zero_electrical_angle: Option<f32>,
     voltage_limit: f32,
-    velocity_limit: Velocity,
     voltage_power_supply: f32,
+    align_voltage: f32,
     kv: Option<f32>,
     phase_resistance: Option<f32>,
     phase_inductance: Option<f32>,
+    protection: Protection,
+    guard: Guard,
+
+    /// See [`BLDC::with_floating_safe_state`]
+    phase_switch: Option<PhaseSwitch<A, B, C>>,
+
+    bus_monitor: BusMonitor,
+    bus: Bus,
+    modulation: Modulation,
+    cogging: Option<CoggingMap>,
 }
 
 impl<A, B, C> BLDC<(), A, B, C, 0> {
@@ -34,26 +73,55 @@ impl<A, B, C> BLDC<(), A, B, C, 0> {
             zero_electrical_angle: None,
             pwm,
             voltage_limit: 12.,
-            velocity_limit: Velocity::rad(60. * PI).per_sec(1.), // 30 RPS or 1800 RPM
             voltage_power_supply: DEFAULT_VOLTAGE_SUPPLY,
+            align_voltage: DEFAULT_ALIGN_VOLTAGE,
             kv: None,
             phase_resistance: None,
             phase_inductance: None,
+            protection: Protection::new(),
+            guard: Guard::default(),
+            phase_switch: None,
+            bus_monitor: BusMonitor::new(),
+            bus: Bus::default(),
+            modulation: Modulation::SpaceVector,
+            cogging: None,
         }
     }
 }
 
-impl<H, A, B, C, const POLE: u8> BLDC<H, A, B, C, POLE> {
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K> {
     pub fn with_voltage_limit(mut self, limit: f32) -> Self {
         self.voltage_limit = limit;
         self
     }
 
+    /// Supply the duty cycles are computed against, until a measured bus
+    /// voltage is reported, see [`BLDC::report_bus_voltage`]
     pub fn with_voltage_power_supply(mut self, supply: f32) -> Self {
         self.voltage_power_supply = supply;
         self
     }
 
+    /// How the voltages are spread over the phases, space vector by default
+    pub fn with_modulation(mut self, modulation: Modulation) -> Self {
+        self.modulation = modulation;
+        self
+    }
+
+    pub fn modulation(&self) -> Modulation {
+        self.modulation
+    }
+
+    pub fn set_modulation(&mut self, modulation: Modulation) {
+        self.modulation = modulation;
+    }
+
+    /// Voltage used to pull the rotor around during [`BLDC::align`]
+    pub fn with_align_voltage(mut self, voltage: f32) -> Self {
+        self.align_voltage = voltage;
+        self
+    }
+
     pub fn with_kv(mut self, kv: f32) -> Self {
         self.kv = Some(kv);
         self
@@ -69,23 +137,114 @@ impl<H, A, B, C, const POLE: u8> BLDC<H, A, B, C, POLE> {
         self
     }
 
-    pub fn with_sensor<N>(self, sensor: N) -> BLDC<N, A, B, C, POLE> {
+    /// Use previously measured motor parameters, see [`BLDC::identify`]
+    pub fn with_params(mut self, params: MotorParams) -> Self {
+        self.set_params(params);
+        self
+    }
+
+    pub(crate) fn set_params(&mut self, params: MotorParams) {
+        debug_assert_eq!(params.pole_pairs, POLE);
+        self.phase_resistance = Some(params.phase_resistance);
+        self.phase_inductance = Some(params.phase_inductance);
+        self.kv = Some(params.kv);
+    }
+
+    /// Motor parameters, if all of them are known
+    pub fn params(&self) -> Option<MotorParams> {
+        Some(MotorParams {
+            pole_pairs: POLE,
+            phase_resistance: self.phase_resistance?,
+            phase_inductance: self.phase_inductance?,
+            kv: self.kv?,
+        })
+    }
+
+    /// Permanent magnet flux linkage in Wb, derived from the Kv
+    pub fn flux_linkage(&self) -> Option<f32> {
+        self.kv.map(|kv| flux_linkage(POLE, kv))
+    }
+
+    /// Read the angle from `sensor`
+    ///
+    /// Panics if the sensor decodes the angle for another number of pole
+    /// pairs than `POLE`, see [`SensorHardware::pole_pairs`].
+    pub fn with_sensor<N: SensorHardware>(self, sensor: N) -> BLDC<N, A, B, C, POLE, K> {
+        let pole_pairs = sensor.pole_pairs();
+        assert!(
+            pole_pairs.is_none_or(|pole_pairs| pole_pairs == POLE),
+            "Sensor set up for another number of pole pairs than the motor"
+        );
+        BLDC {
+            sensor: self.sensor.with_hardware(sensor),
+            ..self
+        }
+    }
+
+    /// Use another time source than the system timer, e.g. a virtual clock
+    pub fn with_clock<N>(self, clock: N) -> BLDC<H, A, B, C, POLE, N> {
         BLDC {
-            sensor: Sensor::new(sensor),
+            sensor: self.sensor.with_clock(clock),
             ..self
         }
     }
 
-    pub fn sensor(&self) -> &Sensor<H> {
+    /// See [`VelocityEstimator`], a plain difference by default
+    pub fn with_velocity_estimator(mut self, estimator: VelocityEstimator) -> Self {
+        self.sensor.set_velocity_estimator(estimator);
+        self
+    }
+
+    /// See [`GlitchPolicy`], every reading is taken by default
+    pub fn with_glitch_policy(mut self, glitches: GlitchPolicy) -> Self {
+        self.sensor = self.sensor.with_glitch_policy(glitches);
+        self
+    }
+
+    pub fn sensor(&self) -> &Sensor<H, K> {
         &self.sensor
     }
 
-    pub fn sensor_mut(&mut self) -> &mut Sensor<H> {
+    pub fn sensor_mut(&mut self) -> &mut Sensor<H, K> {
         &mut self.sensor
     }
+
+    /// Electrical angle of the d axis at sensor angle 0, known once aligned
+    pub fn zero_electrical_angle(&self) -> Option<f32> {
+        self.zero_electrical_angle
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    K: Clock,
+{
+    pub(crate) fn now(&self) -> Instant {
+        self.sensor.now()
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
+where
+    H: SensorHardware,
+    K: Clock,
+{
+    /// Update the sensor, keeping the zero electrical angle in step when the
+    /// sensor finds its reference
+    pub(crate) fn update_sensor(&mut self) -> Result<(), H::Error> {
+        self.sensor.update()?;
+
+        if let Some(shift) = self.sensor.take_shift() {
+            self.zero_electrical_angle = self
+                .zero_electrical_angle
+                .map(|zero| normalize_angle(zero + shift * POLE as f32));
+        }
+
+        Ok(())
+    }
 }
 
-impl<H, A, B, C, const POLE: u8> BLDC<H, A, B, C, POLE>
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
 where
     H: SensorHardware,
 {
@@ -101,48 +260,21 @@ where
     }
 }
 
-impl<H, A, B, C, const POLE: u8> BLDC<H, A, B, C, POLE>
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
 where
     H: SensorHardware,
     A: SetDutyCycle,
     B: SetDutyCycle<Error = A::Error>,
     C: SetDutyCycle<Error = A::Error>,
+    K: Clock,
 {
     /// FOC with velocity control and a single velocity PI controller
     pub fn foc(self) -> Foc<Self> {
         Foc::new(self)
     }
-
-    pub fn align(&mut self) -> Result<(), H::Error> {
-        self.sensor.update()?;
-        let angle = self.electrical_angle();
-        let vol = self.phase_voltage(f!(self.voltage_power_supply) / 2, I16F16::ZERO, 1.5 * PI);
-        self.pwm
-            .set_voltage(vol, f!(self.voltage_power_supply))
-            .unwrap();
-        Delay::new().delay_millis(700);
-        self.zero_electrical_angle = None;
-        self.sensor.update()?;
-
-        // Motor has moved a little bit, confirm alignment
-        if self.electrical_angle() != angle {
-            self.zero_electrical_angle = Some(self.electrical_angle());
-        } else {
-            panic!("Failed to align motor");
-        }
-
-        self.sensor.reset();
-
-        Ok(())
-    }
-
-    pub fn aligned(mut self) -> Result<Self, H::Error> {
-        self.align()?;
-        Ok(self)
-    }
 }
 
-impl<H, A, B, C, const POLE: u8> BLDC<H, A, B, C, POLE>
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K>
 where
     A: SetDutyCycle,
     B: SetDutyCycle<Error = A::Error>,
@@ -152,6 +284,22 @@ where
         OpenLoop::new(self, velocity)
     }
 
+    /// Trapezoidal commutation at `voltage` (in V), see [`SixStep`]
+    pub fn six_step(self, voltage: f32) -> SixStep<Self> {
+        SixStep::new(self, voltage)
+    }
+
+    /// Apply `volt_q` and `volt_d` (in V) at the electrical `angle`
+    pub(crate) fn set_phase_voltage(
+        &mut self,
+        volt_q: f32,
+        volt_d: f32,
+        angle: f32,
+    ) -> Result<(), A::Error> {
+        let v = self.phase_voltage(f!(volt_q), f!(volt_d), angle);
+        self.pwm.set_voltage(v, f!(self.voltage_power_supply))
+    }
+
     pub(crate) fn phase_voltage(
         &self,
         volt_q: I16F16,
@@ -162,40 +310,39 @@ where
         let alpha = cos * volt_d - sin * volt_q;
         let beta = sin * volt_d + cos * volt_q;
 
-        let mut a = alpha;
-        let mut b = f!("-0.5") * alpha + SQRT3_2 * beta;
-        let mut c = f!("-0.5") * alpha - SQRT3_2 * beta;
-
-        // Space vector modulation
-        let min = a.min(b).min(c);
-        let max = a.max(b).max(c);
-
-        let center = f!(self.voltage_power_supply) / 2 - (max + min) / 2;
-
-        a += center;
-        b += center;
-        c += center;
-
-        (a, b, c)
+        self.modulation
+            .phase_voltages(alpha, beta, f!(self.voltage_power_supply))
     }
 }
 
+/// The three half bridges of the inverter
+///
+/// Duties are set at the full resolution of each channel, see
+/// [`SetDutyCycle::max_duty_cycle`]. For a gate driver with separate high and
+/// low side inputs, the channels can be complementary pairs with dead time,
+/// e.g. `ComplementaryPhase` on the MCPWM.
 pub struct ThreePhasePwm<A, B, C> {
     pub a: A,
     pub b: B,
     pub c: C,
 }
 
+/// PWM channel whose half bridge can be switched off, leaving the phase
+/// floating (high impedance)
+pub trait PhaseEnable: SetDutyCycle {
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), Self::Error>;
+}
+
 impl<A, B, C> ThreePhasePwm<A, B, C>
 where
     A: SetDutyCycle,
     B: SetDutyCycle<Error = A::Error>,
     C: SetDutyCycle<Error = A::Error>,
 {
-    fn volt_to_percent(volt: I16F16, volt_max: I16F16) -> u16 {
-        (volt * 100 / volt_max)
-            .clamp(I16F16::ZERO, I16F16::lit("100"))
-            .to_num()
+    /// Duty for `volt` out of `volt_max`, scaled to `max_duty`
+    fn volt_to_duty(volt: I16F16, volt_max: I16F16, max_duty: u16) -> u16 {
+        let ratio = (volt / volt_max).clamp(I16F16::ZERO, I16F16::ONE);
+        ((ratio.to_bits() as u32 * max_duty as u32 + 0x8000) >> 16) as u16
     }
 
     pub fn set_voltage(
@@ -204,12 +351,14 @@ where
         volt_max: I16F16,
     ) -> Result<(), A::Error> {
         self.set_duty((
-            Self::volt_to_percent(duty.0, volt_max),
-            Self::volt_to_percent(duty.1, volt_max),
-            Self::volt_to_percent(duty.2, volt_max),
+            Self::volt_to_duty(duty.0, volt_max, self.a.max_duty_cycle()),
+            Self::volt_to_duty(duty.1, volt_max, self.b.max_duty_cycle()),
+            Self::volt_to_duty(duty.2, volt_max, self.c.max_duty_cycle()),
         ))
     }
 
+    /// Set the raw duty of each channel, out of its
+    /// [`SetDutyCycle::max_duty_cycle`]
     pub fn set_duty(&mut self, duty: (u16, u16, u16)) -> Result<(), A::Error> {
         self.a.set_duty_cycle(duty.0)?;
         self.b.set_duty_cycle(duty.1)?;
@@ -219,6 +368,28 @@ where
     }
 }
 
+impl<A, B, C> ThreePhasePwm<A, B, C>
+where
+    A: PhaseEnable,
+    B: PhaseEnable<Error = A::Error>,
+    C: PhaseEnable<Error = A::Error>,
+{
+    /// Switch each half bridge on or off, a phase that is off floats
+    pub fn set_enabled(&mut self, enabled: [bool; 3]) -> Result<(), A::Error> {
+        self.a.set_enabled(enabled[0])?;
+        self.b.set_enabled(enabled[1])?;
+        self.c.set_enabled(enabled[2])?;
+
+        Ok(())
+    }
+}
+
+/// Permanent magnet flux linkage in Wb of a motor with `pole_pairs` and `kv`
+/// (in RPM/V), matching how [`Foc`] estimates the back-EMF
+pub(crate) fn flux_linkage(pole_pairs: u8, kv: f32) -> f32 {
+    1. / (pole_pairs as f32 * kv * SQRT_3 * RPM_TO_RADS)
+}
+
 fn normalize_angle(angle: f32) -> f32 {
     let a = angle % (2. * PI);
     if a < 0. { a + 2. * PI } else { a }
@@ -0,0 +1,178 @@
+use core::f32::consts::{PI, SQRT_3};
+
+use fixed::types::I16F16;
+use num_traits::Float;
+
+use crate::{SQRT3_2, f};
+
+/// How [`BLDC`] turns a voltage vector into the voltages of the three phases
+///
+/// Only the voltages between phases reach the motor, so the strategies differ
+/// in the common mode they add, which sets how much of the supply can be used
+/// and how often the switches toggle. All of them produce phase voltages
+/// between 0 and the supply.
+///
+/// [`BLDC`]: super::BLDC
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Modulation {
+    /// Sinusoidal PWM, each phase around half the supply
+    ///
+    /// Clips above half the supply, the simplest and quietest.
+    Sine,
+
+    /// Space vector PWM by midpoint clamping: the phases are shifted so the
+    /// highest and lowest are centered in the supply
+    ///
+    /// Reaches the supply over √3, 15% more than [`Modulation::Sine`].
+    #[default]
+    SpaceVector,
+
+    /// Discontinuous PWM: the phase furthest from the center is clamped to
+    /// its rail for 60° at a time and doesn't switch
+    ///
+    /// Same range as [`Modulation::SpaceVector`] with a third less switching
+    /// losses, at the cost of more ripple at low voltages.
+    Discontinuous,
+
+    /// Block commutation driven from the angle: the highest phase is high,
+    /// the lowest low and the third at half the supply
+    ///
+    /// Sized to give the same fundamental as the sinusoidal strategies, up to
+    /// the supply times √3/π. Torque ripples like [`SixStep`], but it runs
+    /// with any sensor and control mode.
+    ///
+    /// [`SixStep`]: super::SixStep
+    Trapezoidal,
+}
+
+impl Modulation {
+    /// Largest voltage vector amplitude out of `supply` before the phases
+    /// clip, in V
+    pub fn max_voltage(self, supply: f32) -> f32 {
+        match self {
+            Self::Sine => supply / 2.,
+            Self::SpaceVector | Self::Discontinuous => supply / SQRT_3,
+            Self::Trapezoidal => supply * SQRT_3 / PI,
+        }
+    }
+
+    /// Phase voltages for the voltage vector (`alpha`, `beta`) in the
+    /// stationary frame, out of `supply`, all in V
+    pub fn phase_voltages(
+        self,
+        alpha: I16F16,
+        beta: I16F16,
+        supply: I16F16,
+    ) -> (I16F16, I16F16, I16F16) {
+        // Inverse Clarke transform
+        let a = alpha;
+        let b = f!("-0.5") * alpha + SQRT3_2 * beta;
+        let c = f!("-0.5") * alpha - SQRT3_2 * beta;
+
+        let min = a.min(b).min(c);
+        let max = a.max(b).max(c);
+
+        let center = match self {
+            Self::Sine => supply / 2,
+            Self::SpaceVector => supply / 2 - (max + min) / 2,
+            // Clamp whichever of the highest and lowest phase is the furthest
+            // out, which keeps the clamped phase carrying the most current
+            Self::Discontinuous if max + min > I16F16::ZERO => supply - max,
+            Self::Discontinuous => -min,
+            Self::Trapezoidal => {
+                let amplitude =
+                    Float::hypot(alpha.to_num::<f32>(), beta.to_num()) * PI / (2. * SQRT_3);
+                let amplitude = f!(amplitude);
+                let block = |phase: I16F16| match phase {
+                    phase if phase == max => supply / 2 + amplitude,
+                    phase if phase == min => supply / 2 - amplitude,
+                    _ => supply / 2,
+                };
+
+                return (block(a), block(b), block(c));
+            }
+        };
+
+        (a + center, b + center, c + center)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f32::consts::TAU;
+
+    use super::*;
+
+    const STRATEGIES: [Modulation; 4] = [
+        Modulation::Sine,
+        Modulation::SpaceVector,
+        Modulation::Discontinuous,
+        Modulation::Trapezoidal,
+    ];
+
+    /// Samples per turn, enough to resolve the edges of the blocks
+    const STEPS: usize = 3600;
+
+    /// Phase voltages over one electrical turn of a vector of `amplitude`
+    fn sweep(
+        modulation: Modulation,
+        amplitude: f32,
+        supply: f32,
+    ) -> impl Iterator<Item = (f32, [f32; 3])> {
+        (0..STEPS).map(move |step| {
+            let angle = step as f32 * TAU / STEPS as f32;
+            let (alpha, beta) = (amplitude * Float::cos(angle), amplitude * Float::sin(angle));
+            let (a, b, c) = modulation.phase_voltages(f!(alpha), f!(beta), f!(supply));
+            (angle, [a, b, c].map(|v| v.to_num()))
+        })
+    }
+
+    #[test]
+    fn line_to_line() {
+        let (alpha, beta, supply) = (f!("2.5"), f!("-1.5"), f!("12"));
+        let line = |(a, b, c): (I16F16, I16F16, I16F16)| (a - b, b - c);
+
+        let sine = line(Modulation::Sine.phase_voltages(alpha, beta, supply));
+        for modulation in [Modulation::SpaceVector, Modulation::Discontinuous] {
+            let (ab, bc) = line(modulation.phase_voltages(alpha, beta, supply));
+            assert!((ab - sine.0).abs() <= I16F16::DELTA * 2, "{modulation:?}");
+            assert!((bc - sine.1).abs() <= I16F16::DELTA * 2, "{modulation:?}");
+        }
+    }
+
+    #[test]
+    fn fundamental() {
+        // Blocks only match the sinusoidal strategies on their fundamental
+        for modulation in STRATEGIES {
+            let (cos, sin) = sweep(modulation, 3., 12.).fold((0., 0.), |(cos, sin), (angle, v)| {
+                let ab = (v[0] - v[1]) * 2. / STEPS as f32;
+                (cos + ab * Float::cos(angle), sin + ab * Float::sin(angle))
+            });
+            let fundamental = Float::hypot(cos, sin);
+            assert!(
+                (fundamental - 3. * SQRT_3).abs() < 0.01,
+                "{modulation:?}: {fundamental}"
+            );
+        }
+    }
+
+    #[test]
+    fn range() {
+        for modulation in STRATEGIES {
+            let amplitude = modulation.max_voltage(12.) * 0.999;
+            for (angle, v) in sweep(modulation, amplitude, 12.) {
+                assert!(
+                    v.iter().all(|&v| (-1e-3..=12.001).contains(&v)),
+                    "{modulation:?} at {angle}: {v:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn discontinuous() {
+        for (angle, v) in sweep(Modulation::Discontinuous, 3., 12.) {
+            assert!(v.iter().any(|&v| v == 0. || v == 12.), "{angle}: {v:?}");
+        }
+    }
+}
@@ -1,12 +1,15 @@
 use embedded_hal::pwm::SetDutyCycle;
-use esp_hal::time::Instant;
 use fixed::types::I16F16;
 
-use crate::{f, motor::BLDC};
+use crate::{
+    f,
+    motor::BLDC,
+    util::{Clock, Instant},
+};
 
 pub struct OpenLoop<M> {
     motor: M,
-    prev_tick: Instant,
+    prev_tick: Option<Instant>,
     shaft_angle: f32,
     velocity: f32,
 }
@@ -15,7 +18,7 @@ impl<M> OpenLoop<M> {
     pub fn new(motor: M, velocity: f32) -> Self {
         Self {
             motor,
-            prev_tick: Instant::now(),
+            prev_tick: None,
             shaft_angle: 0.,
             velocity,
         }
@@ -38,15 +41,19 @@ impl<M> OpenLoop<M> {
     }
 }
 
-impl<H, A, B, C, const POLE: u8> OpenLoop<BLDC<H, A, B, C, POLE>>
+impl<H, A, B, C, const POLE: u8, K> OpenLoop<BLDC<H, A, B, C, POLE, K>>
 where
     A: SetDutyCycle,
     B: SetDutyCycle<Error = A::Error>,
     C: SetDutyCycle<Error = A::Error>,
+    K: Clock,
 {
     pub fn tick(&mut self) -> Result<(), A::Error> {
-        let now = Instant::now();
-        let dt = (now - self.prev_tick).as_micros() as f32 * 1e-6; // Delta in seconds
+        let now = self.motor.now();
+        let dt = self
+            .prev_tick
+            .map(|prev| (now - prev).to_micros() as f32 * 1e-6) // Delta in seconds
+            .unwrap_or_default();
         let limit = f!(self.motor.voltage_limit);
 
         // self.shaft_angle = normalize_angle(self.shaft_angle + dt * self.velocity);
@@ -58,7 +65,7 @@ where
             .motor
             .phase_voltage(limit / 2, I16F16::ZERO, electronic_angle);
         self.motor.pwm.set_voltage(phase_voltage, limit)?;
-        self.prev_tick = now;
+        self.prev_tick = Some(now);
 
         Ok(())
     }
@@ -0,0 +1,332 @@
+use core::{convert::Infallible, f32::consts::PI};
+
+use num_traits::Float;
+
+use crate::{
+    motor::{AlphaBeta, BLDC, MotorParams, flux_linkage, normalize_angle},
+    sensor::{Direction, SensorHardware},
+    util::{Instant, Velocity},
+};
+
+/// Default observer gain in 1/s, roughly how fast the flux estimate settles
+const DEFAULT_GAIN: f32 = 2000.;
+
+/// Default PLL gains
+const DEFAULT_PLL_KP: f32 = 1000.;
+const DEFAULT_PLL_KI: f32 = 250000.;
+
+/// Estimates the rotor angle from the applied voltages and measured currents
+///
+/// This is the nonlinear flux observer of Ortega et al.: the stator flux is
+/// integrated from `v - R·i`, and the estimate of the rotor flux `ψ - L·i` is
+/// pulled towards the known magnitude of the magnets' flux, which keeps the
+/// integration from drifting. The electrical angle is the direction of the
+/// rotor flux, and a PLL tracking it gives a smooth speed.
+///
+/// The back-EMF vanishes at standstill, so the estimate is only good above
+/// some speed. [`Startup`] gets the motor there. It needs a current sensor
+/// and the motor parameters, see [`BLDC::sensorless`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FluxObserver {
+    pole_pairs: u8,
+    resistance: f32,
+    inductance: f32,
+    flux_linkage: f32,
+    gain: f32,
+    pll_kp: f32,
+    pll_ki: f32,
+
+    /// Stator flux estimate in Wb
+    flux: AlphaBeta,
+
+    /// Electrical angle of the rotor flux
+    angle: f32,
+
+    pll_angle: f32,
+
+    /// Electrical velocity from the PLL in rad/s
+    pll_velocity: f32,
+
+    /// Mechanical angle, accumulated from the electrical one
+    mechanical: f32,
+
+    prev: Option<Instant>,
+}
+
+impl FluxObserver {
+    pub fn new(params: MotorParams) -> Self {
+        let flux_linkage = params.flux_linkage();
+
+        Self {
+            pole_pairs: params.pole_pairs,
+            resistance: params.phase_resistance,
+            inductance: params.phase_inductance,
+            flux_linkage,
+            gain: DEFAULT_GAIN,
+            pll_kp: DEFAULT_PLL_KP,
+            pll_ki: DEFAULT_PLL_KI,
+            flux: AlphaBeta {
+                alpha: flux_linkage,
+                beta: 0.,
+            },
+            angle: 0.,
+            pll_angle: 0.,
+            pll_velocity: 0.,
+            mechanical: 0.,
+            prev: None,
+        }
+    }
+
+    /// How fast the flux estimate is pulled to the magnets' flux, in 1/s
+    pub fn with_gain(mut self, gain: f32) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn with_pll(mut self, kp: f32, ki: f32) -> Self {
+        self.pll_kp = kp;
+        self.pll_ki = ki;
+        self
+    }
+
+    /// Estimated electrical angle in rad
+    pub fn electrical_angle(&self) -> f32 {
+        self.angle
+    }
+
+    /// Estimated electrical velocity in rad/s
+    pub fn electrical_velocity(&self) -> f32 {
+        self.pll_velocity
+    }
+
+    /// Estimated mechanical velocity
+    pub fn velocity(&self) -> Velocity {
+        Velocity::per_sec(self.pll_velocity / self.pole_pairs as f32)
+    }
+}
+
+impl SensorHardware for FluxObserver {
+    type Error = Infallible;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        Ok(self.mechanical)
+    }
+
+    fn observe(&mut self, voltage: AlphaBeta, current: AlphaBeta, now: Instant) {
+        let Some(prev) = self.prev.replace(now) else {
+            return;
+        };
+        let dt = (now - prev).to_micros() as f32 * 1e-6;
+        if dt <= 0. {
+            return;
+        }
+
+        let (r, l) = (self.resistance, self.inductance);
+        let rotor_flux = |flux: AlphaBeta| AlphaBeta {
+            alpha: flux.alpha - l * current.alpha,
+            beta: flux.beta - l * current.beta,
+        };
+
+        let eta = rotor_flux(self.flux);
+        let err =
+            self.flux_linkage * self.flux_linkage - (eta.alpha * eta.alpha + eta.beta * eta.beta);
+        let gamma = self.gain / (self.flux_linkage * self.flux_linkage) / 2.;
+
+        self.flux.alpha += (voltage.alpha - r * current.alpha + gamma * eta.alpha * err) * dt;
+        self.flux.beta += (voltage.beta - r * current.beta + gamma * eta.beta * err) * dt;
+
+        let eta = rotor_flux(self.flux);
+        let angle = normalize_angle(Float::atan2(eta.beta, eta.alpha));
+
+        let delta = wrap_pi(angle - self.angle);
+        self.angle = angle;
+        self.mechanical = normalize_angle(self.mechanical + delta / self.pole_pairs as f32);
+
+        let err = wrap_pi(angle - self.pll_angle);
+        self.pll_angle =
+            normalize_angle(self.pll_angle + (self.pll_velocity + self.pll_kp * err) * dt);
+        self.pll_velocity += self.pll_ki * err * dt;
+    }
+}
+
+/// Open-loop ramp that spins a sensorless motor up until the observer can
+/// take over
+///
+/// [`Foc`](super::Foc) holds `current` (Iq in A) on a field rotating ever
+/// faster, which the rotor follows. Once the field turns at `handoff` and the
+/// observer agrees with it, the regular motion control takes over. Should the
+/// speed fall below `min_velocity` afterwards, e.g. when stalled, the ramp
+/// starts over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Startup {
+    current: f32,
+    acceleration: f32,
+    handoff: Velocity,
+    min_velocity: Velocity,
+
+    /// Electrical angle and mechanical velocity of the field while ramping
+    ramp: Option<(f32, f32)>,
+}
+
+impl Startup {
+    /// Ramp up at `acceleration` (in rad/s², mechanical) with `current` (in
+    /// A) until `handoff`
+    pub fn new(current: f32, acceleration: f32, handoff: Velocity) -> Self {
+        assert!(acceleration > 0. && handoff.as_secs() > 0.);
+
+        Self {
+            current,
+            acceleration,
+            handoff,
+            min_velocity: handoff * 0.5,
+            ramp: Some((0., 0.)),
+        }
+    }
+
+    /// Speed below which the observer isn't trusted anymore, half of the
+    /// handoff speed by default
+    pub fn with_min_velocity(mut self, velocity: Velocity) -> Self {
+        self.min_velocity = velocity;
+        self
+    }
+
+    /// Whether the ramp is running
+    pub fn is_ramping(&self) -> bool {
+        self.ramp.is_some()
+    }
+
+    pub(crate) fn current(&self) -> f32 {
+        self.current
+    }
+
+    /// Advance the ramp by `dt` s towards `direction`, returns the field's
+    /// electrical angle and mechanical velocity while ramping
+    ///
+    /// `velocity` is what the observer measures, and `angle` its electrical
+    /// angle, used to pick up again after a stall.
+    pub(crate) fn update(
+        &mut self,
+        dt: f32,
+        direction: f32,
+        velocity: Velocity,
+        angle: f32,
+        pole_pairs: u8,
+    ) -> Option<(f32, f32)> {
+        let velocity = velocity.as_secs();
+        let handoff = self.handoff.as_secs();
+
+        let Some((ref mut field, ref mut speed)) = self.ramp else {
+            if velocity.abs() < self.min_velocity.as_secs() {
+                self.ramp = Some((angle, velocity));
+            }
+            return None;
+        };
+
+        if speed.abs() >= handoff && (velocity - *speed).abs() < 0.2 * handoff {
+            self.ramp = None;
+            return None;
+        }
+
+        if speed.abs() < handoff {
+            *speed = (*speed + direction * self.acceleration * dt).clamp(-handoff, handoff);
+        }
+        *field = normalize_angle(*field + *speed * pole_pairs as f32 * dt);
+
+        Some((*field, *speed))
+    }
+}
+
+impl MotorParams {
+    /// Permanent magnet flux linkage in Wb, derived from the Kv
+    pub fn flux_linkage(&self) -> f32 {
+        flux_linkage(self.pole_pairs, self.kv)
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> BLDC<H, A, B, C, POLE, K> {
+    /// Replace the sensor with a [`FluxObserver`]
+    ///
+    /// # Panics
+    ///
+    /// If the motor parameters aren't known, see [`BLDC::with_params`] and
+    /// [`BLDC::identify`].
+    pub fn sensorless(self) -> BLDC<FluxObserver, A, B, C, POLE, K> {
+        let params = self
+            .params()
+            .expect("Sensorless control needs the motor parameters");
+        let mut motor = self.with_sensor(FluxObserver::new(params));
+
+        // The observer measures the electrical angle directly
+        motor.zero_electrical_angle = Some(0.);
+        motor.sensor.set_direction(Direction::Normal);
+        motor
+    }
+}
+
+/// Wrap into -π..π
+fn wrap_pi(angle: f32) -> f32 {
+    normalize_angle(angle + PI) - PI
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f32::consts::TAU;
+
+    use super::*;
+    use crate::{
+        sim::{SimParams, Simulator},
+        util::Duration,
+    };
+
+    /// What identification would find on the simulated motor
+    fn motor_params(sim: &SimParams) -> MotorParams {
+        MotorParams {
+            pole_pairs: sim.pole_pairs,
+            phase_resistance: sim.phase_resistance,
+            phase_inductance: sim.phase_inductance,
+            kv: sim.kv,
+        }
+    }
+
+    #[test]
+    fn flux_linkage_matches_sim() {
+        let sim = SimParams::default();
+        assert_eq!(motor_params(&sim).flux_linkage(), sim.flux_linkage());
+    }
+
+    #[test]
+    fn tracks_the_rotor() {
+        let sim = Simulator::new(SimParams::default());
+        let params = sim.params();
+        sim.set_angle(1.);
+
+        let mut foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_params(motor_params(params))
+            .sensorless()
+            .foc()
+            .with_current_sensor(sim.current_sensor())
+            .with_startup(Startup::new(0.5, 200., 3 * Velocity::RPS))
+            .to_velocity(10 * Velocity::RPS);
+
+        for step in 0..30_000 {
+            sim.advance(Duration::micros(100));
+            foc.tick().unwrap();
+
+            // Under load once it runs on the observer
+            if step == 20_000 {
+                sim.set_load_torque(0.005);
+            }
+        }
+
+        assert!(!foc.startup().unwrap().is_ramping());
+        let velocity = sim.velocity();
+        assert!((velocity - 10. * TAU).abs() < 3., "{velocity}");
+
+        let observer = foc.sensor().hardware();
+        let electrical = normalize_angle(sim.angle() * params.pole_pairs as f32);
+        let error = wrap_pi(observer.electrical_angle() - electrical);
+        assert!(error.abs() < 0.2, "{error}");
+        assert!((observer.velocity().as_secs() - velocity).abs() < 3.);
+    }
+}
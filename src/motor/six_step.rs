@@ -0,0 +1,242 @@
+use core::f32::consts::PI;
+
+use embedded_hal::pwm::SetDutyCycle;
+use fixed::types::I16F16;
+
+use crate::{
+    f,
+    motor::{BLDC, PhaseEnable},
+    sensor::SensorHardware,
+    util::Clock,
+};
+
+/// Phases driven high and low for each of the six voltage vectors, the
+/// vector `n` pointing at `30° + n·60°` electrical
+const COMMUTATION: [(usize, usize); 6] = [(0, 2), (1, 2), (1, 0), (2, 0), (2, 1), (0, 1)];
+
+/// Trapezoidal (six-step) commutation
+///
+/// Each 60° sector of the electrical angle drives one phase high and one low,
+/// with the voltage vector 60° to 120° ahead of the rotor, so the sensor only
+/// has to tell the sector apart. That makes it the mode for motors with
+/// nothing but hall sensors, see [`HallSensor`]. Like [`OpenLoop`], it is
+/// controlled through the applied voltage.
+///
+/// The third phase is held at half the supply, unless the driver can leave it
+/// floating, see [`SixStep::tick_floating`].
+///
+/// [`HallSensor`]: crate::sensor::HallSensor
+/// [`OpenLoop`]: super::OpenLoop
+pub struct SixStep<M> {
+    motor: M,
+    voltage: f32,
+    sector: Option<u8>,
+}
+
+impl<M> SixStep<M> {
+    pub fn new(motor: M, voltage: f32) -> Self {
+        Self {
+            motor,
+            voltage,
+            sector: None,
+        }
+    }
+
+    /// Voltage between the driven phases in V, negative to turn backwards
+    pub fn voltage(&self) -> f32 {
+        self.voltage
+    }
+
+    pub fn set_voltage(&mut self, voltage: f32) {
+        self.voltage = voltage;
+    }
+
+    /// Sector of the electrical angle the last tick commutated for, 0 to 5
+    pub fn sector(&self) -> Option<u8> {
+        self.sector
+    }
+
+    pub fn into_inner(self) -> M {
+        self.motor
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> SixStep<BLDC<H, A, B, C, POLE, K>>
+where
+    H: SensorHardware,
+    A: SetDutyCycle,
+    B: SetDutyCycle<Error = A::Error>,
+    C: SetDutyCycle<Error = A::Error>,
+    K: Clock,
+{
+    /// Commutate for the sector the rotor is in
+    ///
+    /// Like [`Foc::tick`], sensor failures and the limits of the
+    /// [`Protection`] trip a fault instead of panicking.
+    ///
+    /// [`Foc::tick`]: super::Foc::tick
+    /// [`Protection`]: super::Protection
+    pub fn tick(&mut self) -> Result<(), A::Error> {
+        let Some((high, low)) = self.commutate()? else {
+            return Ok(());
+        };
+
+        let supply = f!(self.motor.voltage_power_supply);
+        let voltage = f!(self.voltage.abs().min(self.motor.voltage_limit)).min(supply);
+
+        let mut duty = [supply / 2; 3];
+        duty[high] += voltage / 2;
+        duty[low] -= voltage / 2;
+
+        self.motor
+            .pwm
+            .set_voltage((duty[0], duty[1], duty[2]), supply)
+    }
+
+    /// Update the sensor and the sector, returns the phases to drive high and
+    /// low, or nothing if the output has to stay as it is
+    fn commutate(&mut self) -> Result<Option<(usize, usize)>, A::Error> {
+        if !self.motor.check_limits(None)? || !self.motor.update_sensor_guarded()? {
+            return Ok(None);
+        }
+
+        // Sectors are centered on multiples of 60°, so the vector one ahead
+        // leads the rotor by 90° on average
+        let angle = self.motor.electrical_angle();
+        let sector = ((angle + PI / 6.) / (PI / 3.)) as u8 % 6;
+        self.sector = Some(sector);
+
+        let (mut high, mut low) = COMMUTATION[(sector as usize + 1) % 6];
+        if self.voltage < 0. {
+            (high, low) = (low, high);
+        }
+
+        Ok(Some((high, low)))
+    }
+}
+
+impl<H, A, B, C, const POLE: u8, K> SixStep<BLDC<H, A, B, C, POLE, K>>
+where
+    H: SensorHardware,
+    A: PhaseEnable,
+    B: PhaseEnable<Error = A::Error>,
+    C: PhaseEnable<Error = A::Error>,
+    K: Clock,
+{
+    /// Like [`SixStep::tick`], but the undriven phase floats instead of
+    /// sitting at half the supply, which is the classic block commutation
+    ///
+    /// The high phase is switched against a low side held on, so the whole
+    /// voltage is across the two driven phases. Re-enable all phases with
+    /// [`ThreePhasePwm::set_enabled`] before switching to another mode.
+    ///
+    /// [`ThreePhasePwm::set_enabled`]: super::ThreePhasePwm::set_enabled
+    pub fn tick_floating(&mut self) -> Result<(), A::Error> {
+        let Some((high, low)) = self.commutate()? else {
+            return Ok(());
+        };
+
+        let supply = f!(self.motor.voltage_power_supply);
+        let voltage = f!(self.voltage.abs().min(self.motor.voltage_limit)).min(supply);
+
+        let mut duty = [I16F16::ZERO; 3];
+        duty[high] = voltage;
+
+        let mut enabled = [false; 3];
+        enabled[high] = true;
+        enabled[low] = true;
+
+        self.motor.pwm.set_enabled(enabled)?;
+        self.motor
+            .pwm
+            .set_voltage((duty[0], duty[1], duty[2]), supply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f32::consts::TAU;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        sensor::HallSensor,
+        sim::{SimClock, SimHallPin, SimParams, SimPhase, Simulator},
+        util::Duration,
+    };
+
+    type SimHall<'a> = HallSensor<SimHallPin<'a>, SimHallPin<'a>, SimHallPin<'a>, SimClock<'a>>;
+    type SimSixStep<'a> =
+        SixStep<BLDC<SimHall<'a>, SimPhase<'a>, SimPhase<'a>, SimPhase<'a>, 7, SimClock<'a>>>;
+
+    fn six_step(sim: &Simulator, voltage: f32) -> SimSixStep<'_> {
+        let [a, b, c] = sim.hall_pins();
+        BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(HallSensor::new(a, b, c, 7).with_clock(sim.clock()))
+            .six_step(voltage)
+    }
+
+    /// Tick at 10 kHz for `millis` ms, returns the sectors commutated for
+    fn run(sim: &Simulator, six_step: &mut SimSixStep<'_>, millis: u64) -> Vec<u8> {
+        let mut sectors = Vec::new();
+        for _ in 0..millis * 10 {
+            sim.advance(Duration::micros(100));
+            six_step.tick().unwrap();
+
+            // The interpolated angle stays within a sector of the rotor
+            let angle = six_step.motor.sensor().state().angle();
+            let error = (angle - sim.angle() + PI).rem_euclid(TAU) - PI;
+            assert!(error.abs() < PI / 3. / 7., "{angle} {}", sim.angle());
+
+            if six_step.sector() != sectors.last().copied() {
+                sectors.extend(six_step.sector());
+            }
+        }
+        sectors
+    }
+
+    #[test]
+    fn commutation() {
+        for voltage in [2., -2.] {
+            for sector in 0..6 {
+                // Rotor in the middle of the hall sector
+                let sim = Simulator::new(SimParams::default());
+                sim.set_angle((sector as f32 + 0.5) * PI / 3. / 7.);
+                let mut six_step = six_step(&sim, voltage);
+
+                // Right on the edge between two of the sectors commutated for
+                six_step.tick().unwrap();
+                let commutated = six_step.sector().unwrap();
+                assert!((commutated + 6 - sector) % 6 <= 1, "{sector}: {commutated}");
+                sim.advance(Duration::micros(500));
+
+                // The vector leads or lags the rotor by 60° to 120°
+                let current = sim.current();
+                assert!(current.q * voltage > 0., "{sector}: {current:?}");
+                assert!(current.d.abs() < current.q.abs(), "{sector}: {current:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn spins() {
+        let sim = Simulator::new(SimParams::default());
+        let mut six_step = six_step(&sim, 4.);
+
+        let sectors = run(&sim, &mut six_step, 500);
+        assert!(sim.velocity() > 5. * TAU, "{}", sim.velocity());
+        assert!(sectors.len() > 6 * 7 * 5, "{}", sectors.len());
+        for pair in sectors.windows(2) {
+            assert_eq!((pair[1] + 6 - pair[0]) % 6, 1, "{sectors:?}");
+        }
+
+        // And back the other way
+        six_step.set_voltage(-4.);
+        let sectors = run(&sim, &mut six_step, 1000);
+        assert!(sim.velocity() < -5. * TAU, "{}", sim.velocity());
+        for pair in sectors[sectors.len() / 2..].windows(2) {
+            assert_eq!((pair[0] + 6 - pair[1]) % 6, 1, "{sectors:?}");
+        }
+    }
+}
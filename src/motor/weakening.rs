@@ -0,0 +1,229 @@
+use num_traits::Float;
+
+/// Default share of the available voltage kept as headroom for the current
+/// loop
+const DEFAULT_HEADROOM: f32 = 0.95;
+
+/// Default integral gain, in A of Id per V of saturation and per s
+const DEFAULT_GAIN: f32 = 50.;
+
+/// Field weakening for [`Foc`], which drives a negative Id once the voltage
+/// runs out so the motor keeps speeding up past its base speed
+///
+/// Near the speed where the back-EMF takes up the whole supply, the loop
+/// integrates how far the voltage vector goes past `headroom` of what the
+/// [`Modulation`] can put out (and the voltage limit) into a negative Id,
+/// which opposes the magnet flux, and eases it back to zero when the voltage
+/// is available again. It needs a current sensor, and the Kv to tell the
+/// back-EMF.
+///
+/// The resistive drop of the negative Id comes out of the same voltage, so
+/// the gain in speed depends on the motor: the larger the inductance is
+/// against the resistance, the further it goes.
+///
+/// The torque per A drops as the field weakens, and if the loop is cut at
+/// speed the back-EMF exceeds the supply and brakes the motor through the
+/// diodes of the bridge, so limit the speed with care.
+///
+/// [`Foc`]: super::Foc
+/// [`Modulation`]: super::Modulation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FieldWeakening {
+    max_current: f32,
+    headroom: f32,
+    gain: f32,
+    current: f32,
+}
+
+impl FieldWeakening {
+    /// Weaken with at most `max_current` (in A) of negative Id
+    pub fn new(max_current: f32) -> Self {
+        Self {
+            max_current: max_current.abs(),
+            headroom: DEFAULT_HEADROOM,
+            gain: DEFAULT_GAIN,
+            current: 0.,
+        }
+    }
+
+    /// Share of the available voltage to stay under, 0.95 by default
+    pub fn with_headroom(mut self, headroom: f32) -> Self {
+        self.headroom = headroom.clamp(0., 1.);
+        self
+    }
+
+    /// How fast Id follows the lack of voltage, in A per V and s
+    pub fn with_gain(mut self, gain: f32) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn max_current(&self) -> f32 {
+        self.max_current
+    }
+
+    /// Id currently asked for, in A, zero or negative
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    pub fn reset(&mut self) {
+        self.current = 0.;
+    }
+
+    /// Integrate the `voltage` amplitude the controllers asked for against
+    /// the `available` one over `dt` (in s), returns the new Id
+    ///
+    /// Below half the base speed, as told by the back-EMF `bemf` (in V), the
+    /// lack of voltage comes from the current loop, which a weaker field
+    /// doesn't help, so Id only eases back there.
+    pub(crate) fn update(&mut self, voltage: f32, bemf: f32, available: f32, dt: f32) -> f32 {
+        let error = self.headroom * available - voltage;
+        let error = match bemf.abs() < available / 2. {
+            true => error.max(0.),
+            false => error,
+        };
+        self.current = (self.current + self.gain * error * dt).clamp(-self.max_current, 0.);
+        self.current
+    }
+}
+
+/// Maximum torque per ampere for motors with saliency, see
+/// [`Foc::with_mtpa`]
+///
+/// When the q axis inductance is larger than the d axis one (interior
+/// magnets), a negative Id adds reluctance torque, so part of the current is
+/// moved to Id. The flux linkage comes from the Kv of the motor. Surface
+/// magnet motors, where both are the same, don't gain anything.
+///
+/// [`Foc::with_mtpa`]: super::Foc::with_mtpa
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mtpa {
+    saliency: f32,
+}
+
+impl Mtpa {
+    /// `ld` and `lq` in H
+    pub fn new(ld: f32, lq: f32) -> Self {
+        Self { saliency: lq - ld }
+    }
+
+    /// Id (in A) giving the most torque along with `iq` (in A), for a
+    /// permanent magnet `flux_linkage` in Wb
+    pub fn current(&self, iq: f32, flux_linkage: f32) -> f32 {
+        if self.saliency.abs() < f32::EPSILON {
+            return 0.;
+        }
+
+        let saliency = 2. * self.saliency;
+        (flux_linkage - Float::sqrt(flux_linkage * flux_linkage + saliency * saliency * iq * iq))
+            / saliency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        motor::BLDC,
+        sim::{SimParams, Simulator},
+        util::Duration,
+    };
+
+    /// Out of the 12 V supply with space vector PWM
+    const AVAILABLE: f32 = 6.928;
+
+    #[test]
+    fn integrate() {
+        let mut weakening = FieldWeakening::new(2.);
+
+        // Short of voltage at low speed, from the current loop
+        weakening.update(7., 3., AVAILABLE, 0.01);
+        assert_eq!(weakening.current(), 0.);
+
+        // Short of it near the base speed, by 0.42 V
+        let id = weakening.update(7., 5., AVAILABLE, 0.01);
+        assert!((id + 50. * 0.42 * 0.01).abs() < 1e-3, "{id}");
+
+        for _ in 0..100 {
+            weakening.update(7., 5., AVAILABLE, 0.01);
+        }
+        assert_eq!(weakening.current(), -2.);
+    }
+
+    #[test]
+    fn ease_back() {
+        let mut weakening = FieldWeakening::new(2.);
+        for _ in 0..100 {
+            weakening.update(7., 5., AVAILABLE, 0.01);
+        }
+
+        // Voltage to spare, at any speed
+        let mut last = weakening.current();
+        for bemf in [5., 3., 0.] {
+            let id = weakening.update(5., bemf, AVAILABLE, 0.01);
+            assert!(id > last, "{id}");
+            last = id;
+        }
+        for _ in 0..100 {
+            weakening.update(5., 0., AVAILABLE, 0.01);
+        }
+        assert_eq!(weakening.current(), 0.);
+    }
+
+    #[test]
+    fn mtpa() {
+        assert_eq!(Mtpa::new(1e-3, 1e-3).current(10., 0.01), 0.);
+
+        // id = ψ / 2(Lq - Ld) - √(ψ² / 4(Lq - Ld)² + iq²)
+        let mtpa = Mtpa::new(0.5e-3, 1e-3);
+        let expected = 10. - Float::sqrt(200.);
+        assert!((mtpa.current(10., 0.01) - expected).abs() < 1e-4);
+        assert!((mtpa.current(-10., 0.01) - expected).abs() < 1e-4);
+
+        // More torque than any other split of the same current
+        let torque = |id: f32, iq: f32| iq * (0.01 - 0.5e-3 * id);
+        let amplitude = Float::hypot(expected, 10.);
+        for id in [-6., -5., -3., -2.] {
+            let iq = Float::sqrt(amplitude * amplitude - id * id);
+            assert!(torque(id, iq) < torque(expected, 10.), "{id}");
+        }
+    }
+
+    /// Velocity and Id reached at 1 A of Iq after 1 s
+    fn top_speed(weakening: Option<FieldWeakening>) -> (f32, f32) {
+        let sim = Simulator::new(SimParams::default());
+        let foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .with_kv(sim.params().kv)
+            .aligned(&mut sim.clock())
+            .unwrap()
+            .foc()
+            .with_current_sensor(sim.current_sensor())
+            .to_torque(1.);
+        let mut foc = match weakening {
+            Some(weakening) => foc.with_field_weakening(weakening),
+            None => foc,
+        };
+
+        for _ in 0..10_000 {
+            sim.advance(Duration::micros(100));
+            foc.tick().unwrap();
+        }
+        (sim.velocity(), sim.current().d)
+    }
+
+    #[test]
+    fn past_base_speed() {
+        let (base, _) = top_speed(None);
+        let (weakened, id) = top_speed(Some(FieldWeakening::new(2.)));
+        assert!(weakened > base * 1.1, "{weakened} {base}");
+        assert!(id < -1.9, "{id}");
+
+        // Past the no-load speed at the available voltage, too
+        let params = SimParams::default();
+        let no_load = AVAILABLE / (params.flux_linkage() * params.pole_pairs as f32);
+        assert!(weakened > no_load * 1.1, "{weakened} {no_load}");
+    }
+}
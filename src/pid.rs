@@ -1,9 +1,8 @@
 use core::f32;
 
-use esp_hal::time::Duration;
 use tap::Pipe;
 
-use crate::util::Velocity;
+use crate::util::{Duration, Velocity};
 
 #[derive(Clone, Copy, Debug)]
 pub struct PIDController {
@@ -21,7 +20,7 @@ pub struct PIDController {
     state: PIDState,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 struct PIDState {
     /// Integral
     integral: f32,
@@ -31,6 +30,12 @@ struct PIDState {
 
     /// Previous output
     output: f32,
+
+    /// Previous proportional term
+    p: f32,
+
+    /// Previous derivative term
+    d: f32,
 }
 
 impl PIDController {
@@ -41,11 +46,7 @@ impl PIDController {
             d: 0.0,
             output_ramp: None,
             limit: f32::MAX,
-            state: PIDState {
-                integral: 0.0,
-                err: 0.0,
-                output: 0.0,
-            },
+            state: PIDState::default(),
         }
     }
 
@@ -74,8 +75,18 @@ impl PIDController {
         self
     }
 
+    /// Proportional, integral and derivative terms of the last output
+    pub fn terms(&self) -> (f32, f32, f32) {
+        (self.state.p, self.state.integral, self.state.d)
+    }
+
+    /// Clear the integral and the previous error/output
+    pub fn reset(&mut self) {
+        self.state = PIDState::default();
+    }
+
     pub fn compute(&mut self, target: f32, measure: f32, dt: Duration) -> f32 {
-        let dt = dt.as_micros() as f32 * 1e-6;
+        let dt = dt.to_micros() as f32 * 1e-6;
 
         let err = target - measure;
 
@@ -103,6 +114,8 @@ impl PIDController {
         self.state.integral = i;
         self.state.err = err;
         self.state.output = output;
+        self.state.p = p;
+        self.state.d = d;
 
         output
     }
@@ -114,6 +127,7 @@ impl Default for PIDController {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct VelocityPID(PIDController);
 
 impl VelocityPID {
@@ -125,9 +139,98 @@ impl VelocityPID {
         Self(inner)
     }
 
+    pub fn reset(&mut self) {
+        self.0.reset();
+    }
+
+    pub fn terms(&self) -> (f32, f32, f32) {
+        self.0.terms()
+    }
+
     pub fn compute(&mut self, target: Velocity, measure: Velocity, dt: Duration) -> Velocity {
         self.0
             .compute(target.as_secs(), measure.as_secs(), dt)
             .pipe(Velocity::per_sec)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MILLI: Duration = Duration::millis(1);
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{a} != {b}");
+    }
+
+    #[test]
+    fn proportional() {
+        let mut pid = PIDController::new().p(2.);
+        assert_close(pid.compute(1., 0.25, MILLI), 1.5);
+        assert_close(pid.compute(-1., 0., MILLI), -2.);
+    }
+
+    #[test]
+    fn integral() {
+        // Trapezoidal, starting from a zero error
+        let mut pid = PIDController::new().i(10.);
+        let dt = Duration::millis(100);
+        assert_close(pid.compute(1., 0., dt), 0.5);
+        assert_close(pid.compute(1., 0., dt), 1.5);
+        assert_close(pid.terms().1, 1.5);
+
+        pid.reset();
+        assert_eq!(pid.terms(), (0., 0., 0.));
+        assert_close(pid.compute(1., 0., dt), 0.5);
+    }
+
+    #[test]
+    fn derivative() {
+        let mut pid = PIDController::new().d(0.5);
+        let dt = Duration::millis(100);
+        assert_close(pid.compute(1., 0., dt), 5.);
+        assert_close(pid.compute(1., 0., dt), 0.);
+    }
+
+    #[test]
+    fn limit() {
+        // The integral is clamped too, so it doesn't wind up
+        let mut pid = PIDController::new().p(1.).i(100.).limit(2.);
+        for _ in 0..100 {
+            assert!(pid.compute(10., 0., MILLI) <= 2.);
+        }
+        assert_close(pid.terms().1, 2.);
+
+        let output = pid.compute(-10., 0., MILLI);
+        assert!(output < 0., "{output}");
+    }
+
+    #[test]
+    fn ramp() {
+        let mut pid = PIDController::new().p(100.).ramp(10.);
+        let dt = Duration::millis(10);
+        assert_close(pid.compute(1., 0., dt), 0.1);
+        assert_close(pid.compute(1., 0., dt), 0.2);
+        assert_close(pid.compute(-1., 0., dt), 0.1);
+    }
+
+    #[test]
+    fn converges() {
+        // Drive an integrator to the target
+        let mut pid = PIDController::new().p(5.).i(20.).limit(10.);
+        let mut position = 0.;
+        for _ in 0..5000 {
+            position += pid.compute(2., position, MILLI) * 1e-3;
+        }
+        assert_close(position, 2.);
+    }
+
+    #[test]
+    fn velocity() {
+        let mut pid = VelocityPID::new(PIDController::new()).update(|pid| pid.p(0.5));
+        let output = pid.compute(Velocity::RPS, Velocity::ZERO, MILLI);
+        assert_close(output.as_secs(), core::f32::consts::PI);
+        assert_close(pid.terms().0, core::f32::consts::PI);
+    }
+}
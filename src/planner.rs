@@ -0,0 +1,567 @@
+//! Motion profiles for point to point moves
+//!
+//! [`Planner`] turns a target position into a trajectory that respects a
+//! maximum velocity and acceleration, and optionally a maximum jerk. Without a
+//! jerk limit the velocity follows a trapezoid: accelerate, cruise, decelerate.
+//! With one, the trapezoid is smoothed into an S-curve by averaging it over a
+//! sliding window of `acceleration / jerk` seconds, which ramps the
+//! acceleration instead of stepping it and makes the move that much longer.
+//!
+//! Moves can be retargeted at any time, the new trajectory continues from
+//! the position and velocity of the current one. With a jerk limit, a
+//! retarget can briefly reach up to twice the limit.
+//!
+//! A move can also be stretched to take a given time, see
+//! [`Planner::plan_with_duration`], to have several axes arrive together.
+
+use num_traits::Float;
+
+use crate::util::Instant;
+
+/// Plans moves and samples them over time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Planner {
+    velocity: f32,
+    acceleration: f32,
+    jerk: Option<f32>,
+
+    /// How much slower than the limits the current move runs, 1 at full speed
+    scale: f32,
+
+    /// Trajectory being followed
+    current: Option<Trapezoid>,
+
+    /// Trajectory before the last retarget, still needed for the end of the
+    /// smoothing window
+    previous: Option<Trapezoid>,
+}
+
+/// Where the trajectory is at some point in time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Setpoint {
+    /// Position in rad
+    pub position: f32,
+
+    /// Velocity in rad/s
+    pub velocity: f32,
+
+    /// Acceleration in rad/s²
+    pub acceleration: f32,
+}
+
+impl Planner {
+    /// A trapezoidal planner limited to `velocity` (in rad/s) and
+    /// `acceleration` (in rad/s²)
+    pub fn new(velocity: f32, acceleration: f32) -> Self {
+        assert!(velocity > 0. && acceleration > 0.);
+
+        Self {
+            velocity,
+            acceleration,
+            jerk: None,
+            scale: 1.,
+            current: None,
+            previous: None,
+        }
+    }
+
+    /// Limit the jerk (in rad/s³) as well, making it an S-curve planner
+    pub fn with_jerk(mut self, jerk: f32) -> Self {
+        assert!(jerk > 0.);
+        self.jerk = Some(jerk);
+        self
+    }
+
+    pub fn velocity_limit(&self) -> f32 {
+        self.velocity
+    }
+
+    pub fn acceleration_limit(&self) -> f32 {
+        self.acceleration
+    }
+
+    pub fn jerk_limit(&self) -> Option<f32> {
+        self.jerk
+    }
+
+    /// Start from `position` (in rad) moving at `velocity` (in rad/s),
+    /// dropping any trajectory
+    ///
+    /// The planner then holds `position` until [`Planner::plan`] is called.
+    pub fn reset(&mut self, position: f32, velocity: f32, now: Instant) {
+        // Before the start the trajectory keeps going at `velocity`, which the
+        // smoothing averages half a window behind. Lead by as much so the
+        // smoothed trajectory starts right at `position`.
+        self.scale = 1.;
+        let position = position + velocity * self.window() / 2.;
+        self.previous = None;
+        self.current = Some(self.trapezoid(now, position, velocity, position));
+    }
+
+    /// Forget the trajectory, the next move has to start with
+    /// [`Planner::reset`]
+    pub fn clear(&mut self) {
+        self.previous = None;
+        self.current = None;
+    }
+
+    /// Whether the planner has a starting point
+    pub fn is_planned(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Move to `target` (in rad), starting at `now`
+    ///
+    /// # Panics
+    ///
+    /// If the planner hasn't been [`Planner::reset`] yet.
+    pub fn plan(&mut self, target: f32, now: Instant) {
+        self.plan_scaled(target, 1., now);
+    }
+
+    /// Move to `target` (in rad), starting at `now` and taking `duration` s,
+    /// or as long as it takes at the limits if that's longer
+    ///
+    /// The limits are scaled down to stretch the move: the velocity by the
+    /// ratio of the durations, the acceleration by its square and the jerk
+    /// by its cube. That is exact for moves from rest, and close enough from
+    /// motion.
+    ///
+    /// # Panics
+    ///
+    /// If the planner hasn't been [`Planner::reset`] yet.
+    pub fn plan_with_duration(&mut self, target: f32, duration: f32, now: Instant) {
+        let fastest = self.duration_to(target, now);
+        let scale = if fastest > 0. {
+            (duration / fastest).max(1.)
+        } else {
+            1.
+        };
+        self.plan_scaled(target, scale, now);
+    }
+
+    /// How long a move to `target` starting at `now` would take at the limits,
+    /// in s, including the smoothing
+    ///
+    /// # Panics
+    ///
+    /// If the planner hasn't been [`Planner::reset`] yet.
+    pub fn duration_to(&self, target: f32, now: Instant) -> f32 {
+        let current = self.current.expect("Planner has no starting point");
+        let (position, velocity, _) = self.raw(secs(now, current.start));
+
+        let planner = Self { scale: 1., ..*self };
+        planner
+            .trapezoid(now, position, velocity, target)
+            .duration()
+            + planner.window()
+    }
+
+    fn plan_scaled(&mut self, target: f32, scale: f32, now: Instant) {
+        let current = self.current.expect("Planner has no starting point");
+        let (position, velocity, _) = self.raw(secs(now, current.start));
+
+        self.scale = scale;
+        self.previous = Some(current);
+        self.current = Some(self.trapezoid(now, position, velocity, target));
+    }
+
+    /// Target of the last move
+    pub fn target(&self) -> Option<f32> {
+        self.current.map(|t| t.target)
+    }
+
+    /// Total time of the last move in s, including the smoothing
+    pub fn duration(&self) -> Option<f32> {
+        self.current.map(|t| t.duration() + self.window())
+    }
+
+    /// Whether the last move is over at `now`
+    pub fn is_done(&self, now: Instant) -> bool {
+        match self.current {
+            Some(t) => secs(now, t.start) >= t.duration() + self.window(),
+            None => true,
+        }
+    }
+
+    /// Where the trajectory is at `now`
+    ///
+    /// # Panics
+    ///
+    /// If the planner hasn't been [`Planner::reset`] yet.
+    pub fn sample(&self, now: Instant) -> Setpoint {
+        let current = self.current.expect("Planner has no starting point");
+        let t = secs(now, current.start);
+        let window = self.window();
+
+        if window == 0. {
+            let (position, velocity, acceleration) = self.raw(t);
+            return Setpoint {
+                position,
+                velocity,
+                acceleration,
+            };
+        }
+
+        // Moving average of the trapezoid over the window, its derivatives are
+        // differences of the trapezoid's at both ends of the window
+        let (p1, v1, _) = self.raw(t);
+        let (p0, v0, _) = self.raw(t - window);
+
+        Setpoint {
+            position: current.position + self.integral(t - window, t, current.position) / window,
+            velocity: (p1 - p0) / window,
+            acceleration: (v1 - v0) / window,
+        }
+    }
+
+    /// Length of the smoothing window in s
+    fn window(&self) -> f32 {
+        self.jerk
+            .map_or(0., |jerk| self.scale * self.acceleration / jerk)
+    }
+
+    fn trapezoid(&self, start: Instant, position: f32, velocity: f32, target: f32) -> Trapezoid {
+        Trapezoid::new(
+            start,
+            position,
+            velocity,
+            target,
+            self.velocity / self.scale,
+            self.acceleration / (self.scale * self.scale),
+        )
+    }
+
+    /// Trajectory at `t` s after the start of the current one, before
+    /// smoothing
+    fn raw(&self, t: f32) -> (f32, f32, f32) {
+        let current = self.current.expect("Planner has no starting point");
+        match self.previous {
+            Some(previous) if t < 0. => previous.state(t + secs(current.start, previous.start)),
+            _ => current.state(t),
+        }
+    }
+
+    /// Integral of the trajectory before smoothing minus `origin`, between
+    /// `from` and `to` s after the start of the current one
+    fn integral(&self, from: f32, to: f32, origin: f32) -> f32 {
+        let current = self.current.expect("Planner has no starting point");
+        match self.previous {
+            Some(previous) if from < 0. => {
+                let offset = secs(current.start, previous.start);
+                previous.integral(from + offset, to.min(0.) + offset, origin)
+                    + current.integral(0., to.max(0.), origin)
+            }
+            _ => current.integral(from, to, origin),
+        }
+    }
+}
+
+/// Trapezoidal move from rest or motion to rest
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Trapezoid {
+    start: Instant,
+    position: f32,
+    velocity: f32,
+    target: f32,
+
+    /// Duration in s and acceleration of the acceleration, cruise and
+    /// deceleration phases
+    phases: [(f32, f32); 3],
+}
+
+/// Part of a [`Trapezoid`] with constant acceleration
+struct Phase {
+    /// When the phase starts, in s after the start of the trapezoid
+    start: f32,
+
+    /// When the phase ends
+    end: f32,
+
+    position: f32,
+    velocity: f32,
+    acceleration: f32,
+}
+
+impl Trapezoid {
+    fn new(
+        start: Instant,
+        position: f32,
+        velocity: f32,
+        target: f32,
+        velocity_limit: f32,
+        acceleration_limit: f32,
+    ) -> Self {
+        let distance = target - position;
+        let stopping = velocity * velocity.abs() / (2. * acceleration_limit);
+
+        // Work in the direction of the move. Braking right away would end at
+        // `stopping`, so past the target the move has to turn around.
+        let direction = if distance >= stopping { 1. } else { -1. };
+        let distance = (distance * direction).max(0.);
+        let velocity = velocity * direction;
+
+        let peak = if velocity > velocity_limit {
+            velocity_limit
+        } else {
+            velocity_limit.min(Float::sqrt(
+                acceleration_limit * distance + velocity * velocity / 2.,
+            ))
+        };
+
+        let accelerating = (peak - velocity).abs() / acceleration_limit;
+        let decelerating = peak / acceleration_limit;
+        let covered = (peak - velocity).signum() * (peak * peak - velocity * velocity)
+            / (2. * acceleration_limit)
+            + peak * peak / (2. * acceleration_limit);
+        let cruising = if peak > 0. {
+            ((distance - covered) / peak).max(0.)
+        } else {
+            0.
+        };
+
+        Self {
+            start,
+            position,
+            velocity: velocity * direction,
+            target,
+            phases: [
+                (
+                    accelerating,
+                    (peak - velocity).signum() * acceleration_limit * direction,
+                ),
+                (cruising, 0.),
+                (decelerating, -acceleration_limit * direction),
+            ],
+        }
+    }
+
+    fn duration(&self) -> f32 {
+        self.phases.iter().map(|(duration, _)| duration).sum()
+    }
+
+    /// Phase containing `t`, before the start the initial velocity is
+    /// extrapolated and after the end the target is held
+    fn phase(&self, t: f32) -> Phase {
+        if t < 0. {
+            return Phase {
+                start: 0.,
+                end: 0.,
+                position: self.position,
+                velocity: self.velocity,
+                acceleration: 0.,
+            };
+        }
+
+        let mut start = 0.;
+        let mut position = self.position;
+        let mut velocity = self.velocity;
+
+        for (duration, acceleration) in self.phases {
+            let end = start + duration;
+            if t < end {
+                return Phase {
+                    start,
+                    end,
+                    position,
+                    velocity,
+                    acceleration,
+                };
+            }
+
+            position += velocity * duration + acceleration * duration * duration / 2.;
+            velocity += acceleration * duration;
+            start = end;
+        }
+
+        Phase {
+            start,
+            end: f32::INFINITY,
+            position: self.target,
+            velocity: 0.,
+            acceleration: 0.,
+        }
+    }
+
+    /// Position, velocity and acceleration at `t` s after the start
+    fn state(&self, t: f32) -> (f32, f32, f32) {
+        let phase = self.phase(t);
+        let dt = t - phase.start;
+
+        (
+            phase.position + phase.velocity * dt + phase.acceleration * dt * dt / 2.,
+            phase.velocity + phase.acceleration * dt,
+            phase.acceleration,
+        )
+    }
+
+    /// Integral of the position minus `origin` between `from` and `to` s
+    /// after the start
+    ///
+    /// Each phase is integrated from the state at the start of the interval,
+    /// which keeps the terms small.
+    fn integral(&self, from: f32, to: f32, origin: f32) -> f32 {
+        let mut sum = 0.;
+        let mut t = from;
+
+        while t < to {
+            let phase = self.phase(t);
+            let (position, velocity, acceleration) = self.state(t);
+            let h = phase.end.min(to) - t;
+
+            sum += (position - origin) * h + velocity * h * h / 2. + acceleration * h * h * h / 6.;
+            t = phase.end.min(to);
+        }
+
+        sum
+    }
+}
+
+/// `a - b` in s
+fn secs(a: Instant, b: Instant) -> f32 {
+    if a >= b {
+        (a - b).to_micros() as f32 * 1e-6
+    } else {
+        -((b - a).to_micros() as f32 * 1e-6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_ticks(millis * 1000)
+    }
+
+    /// Setpoints every ms from `from` until the move is over, and 10 ms past
+    /// it
+    fn samples(planner: &Planner, from: u64) -> impl Iterator<Item = Setpoint> + '_ {
+        let end = (planner.duration().unwrap() * 1e3) as u64 + from + 10;
+        (from..=end).map(move |millis| planner.sample(at(millis)))
+    }
+
+    /// Trapezoidal and S-curve planners with the same limits
+    fn both() -> [Planner; 2] {
+        let planner = Planner::new(10., 50.);
+        [planner, planner.with_jerk(500.)]
+    }
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!((a - b).abs() < tolerance, "{a} != {b}");
+    }
+
+    fn assert_within_limits(planner: &Planner, from: u64, jerk_margin: f32) {
+        let mut prev: Option<Setpoint> = None;
+        for setpoint in samples(planner, from) {
+            assert!(setpoint.velocity.abs() <= planner.velocity_limit() * 1.001);
+            assert!(setpoint.acceleration.abs() <= planner.acceleration_limit() * 1.001);
+            if let (Some(prev), Some(jerk)) = (prev, planner.jerk_limit()) {
+                let rate = (setpoint.acceleration - prev.acceleration).abs() / 1e-3;
+                assert!(rate <= jerk * jerk_margin, "{rate}");
+            }
+            prev = Some(setpoint);
+        }
+    }
+
+    #[test]
+    fn trapezoid() {
+        let mut planner = Planner::new(10., 50.);
+        planner.reset(0., 0., at(0));
+        planner.plan(5., at(0));
+
+        // 0.2 s up to speed over 1 rad, 0.3 s cruising, 0.2 s down
+        assert_close(planner.duration().unwrap(), 0.7, 1e-5);
+        assert_within_limits(&planner, 0, 1.);
+        assert_close(planner.sample(at(350)).velocity, 10., 1e-3);
+
+        assert!(!planner.is_done(at(699)));
+        assert!(planner.is_done(at(700)));
+        let end = planner.sample(at(700));
+        assert_close(end.position, 5., 1e-4);
+        assert_eq!(end.velocity, 0.);
+    }
+
+    #[test]
+    fn triangle() {
+        // Too short to reach the velocity limit
+        let mut planner = Planner::new(10., 50.);
+        planner.reset(0., 0., at(0));
+        planner.plan(-0.5, at(0));
+
+        assert_close(planner.duration().unwrap(), 0.2, 1e-5);
+        assert_close(planner.sample(at(100)).velocity, -5., 1e-3);
+        assert_close(planner.sample(at(200)).position, -0.5, 1e-4);
+    }
+
+    #[test]
+    fn s_curve() {
+        let mut planner = Planner::new(10., 50.).with_jerk(500.);
+        planner.reset(1., 0., at(0));
+        planner.plan(6., at(0));
+
+        // Longer by the 0.1 s smoothing window
+        assert_close(planner.duration().unwrap(), 0.8, 1e-5);
+        assert_within_limits(&planner, 0, 1.01);
+        assert_close(planner.sample(at(0)).position, 1., 1e-5);
+        assert_close(planner.sample(at(800)).position, 6., 1e-4);
+        assert!(planner.is_done(at(800)));
+    }
+
+    #[test]
+    fn from_motion() {
+        // Moving away from the target, it has to turn around
+        let mut planner = Planner::new(10., 50.);
+        planner.reset(0., 5., at(0));
+        let start = planner.sample(at(0));
+        assert_close(start.position, 0., 1e-6);
+        assert_close(start.velocity, 5., 1e-6);
+
+        planner.plan(0., at(0));
+        let furthest = samples(&planner, 0).map(|s| s.position).fold(0., f32::max);
+        assert_close(furthest, 0.25, 1e-3);
+        assert_close(planner.sample(at(1000)).position, 0., 1e-4);
+    }
+
+    #[test]
+    fn retarget() {
+        for mut planner in both() {
+            planner.reset(0., 0., at(0));
+            planner.plan(5., at(0));
+            let before = planner.sample(at(300));
+
+            // Back while at full speed, continuing from where it is
+            planner.plan(-2., at(300));
+            let after = planner.sample(at(300));
+            assert_close(after.position, before.position, 1e-4);
+            assert_close(after.velocity, before.velocity, 1e-3);
+            assert_eq!(planner.target(), Some(-2.));
+
+            // Up to twice the jerk limit right after the retarget
+            assert_within_limits(&planner, 300, 2.01);
+            let end = (planner.duration().unwrap() * 1e3) as u64 + 300;
+            assert_close(planner.sample(at(end)).position, -2., 1e-3);
+        }
+    }
+
+    #[test]
+    fn stretched() {
+        for mut planner in both() {
+            planner.reset(0., 0., at(0));
+            let fastest = planner.duration_to(5., at(0));
+
+            // Twice as long, at half the velocity
+            planner.plan_with_duration(5., 2. * fastest, at(0));
+            assert_close(planner.duration().unwrap(), 2. * fastest, 1e-4);
+            let peak = samples(&planner, 0).map(|s| s.velocity).fold(0., f32::max);
+            assert_close(peak, 5., 1e-2);
+            let end = (2. * fastest * 1e3).ceil() as u64;
+            assert_close(planner.sample(at(end)).position, 5., 1e-4);
+
+            // Never faster than the limits allow
+            planner.reset(0., 0., at(0));
+            planner.plan_with_duration(5., 0.1, at(0));
+            assert_close(planner.duration().unwrap(), fastest, 1e-5);
+        }
+    }
+}
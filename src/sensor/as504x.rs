@@ -0,0 +1,311 @@
+use core::{f32::consts::PI, marker::PhantomData};
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::sensor::{MagnetStatus, SensorHardware};
+
+/// Reading a register, parity and address still to be added
+const READ: u16 = 0x4000;
+
+/// Register holding the error flags, cleared by reading it
+const ERRFL: u16 = 0x0001;
+
+const NOP: u16 = 0x0000;
+
+/// 14-bit magnetic encoder of the AS5047P/AS5048A family over SPI (mode 1)
+///
+/// Every read is checked for parity and for the error flag. Unless disabled
+/// with [`As504x::without_diagnostics`], the magnetic field and the CORDIC
+/// are checked too, at the cost of one more frame per read.
+pub struct As504x<S, V> {
+    spi: S,
+    diagnostics: bool,
+    _variant: PhantomData<V>,
+}
+
+pub type As5047p<S> = As504x<S, As5047pRegisters>;
+pub type As5048a<S> = As504x<S, As5048aRegisters>;
+
+/// Registers and diagnostic bits, which differ slightly between the chips
+pub trait As504xVariant {
+    const ANGLE: u16;
+    const DIAGNOSTICS: u16;
+    const MAGNITUDE: u16;
+
+    /// Bits of the diagnostics register flagging a weak and a strong field
+    const MAGNET_WEAK: u16;
+    const MAGNET_STRONG: u16;
+
+    const CORDIC_OVERFLOW: u16 = 1 << 9;
+}
+
+pub struct As5047pRegisters;
+
+impl As504xVariant for As5047pRegisters {
+    /// ANGLECOM, with dynamic angle error compensation
+    const ANGLE: u16 = 0x3FFF;
+    const DIAGNOSTICS: u16 = 0x3FFC;
+    const MAGNET_STRONG: u16 = 1 << 10;
+    const MAGNET_WEAK: u16 = 1 << 11;
+    const MAGNITUDE: u16 = 0x3FFD;
+}
+
+pub struct As5048aRegisters;
+
+impl As504xVariant for As5048aRegisters {
+    const ANGLE: u16 = 0x3FFF;
+    const DIAGNOSTICS: u16 = 0x3FFD;
+    const MAGNET_STRONG: u16 = 1 << 10;
+    /// "Comp high" and "Comp low", the AGC at either end of its range
+    const MAGNET_WEAK: u16 = 1 << 11;
+    const MAGNITUDE: u16 = 0x3FFE;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum As504xError<E> {
+    Spi(E),
+
+    /// A response failed the parity check
+    Parity,
+
+    /// The sensor flagged the previous command, with the error register:
+    /// framing (bit 0), invalid command (bit 1) or parity (bit 2) error
+    Command(u16),
+
+    /// The AGC is at its maximum, the magnet is too far or too weak
+    MagnetTooWeak,
+
+    /// The AGC is at its minimum, the magnet is too close or too strong
+    MagnetTooStrong,
+
+    /// The CORDIC overflowed, the angle is invalid
+    CordicOverflow,
+}
+
+impl<S, V> As504x<S, V> {
+    pub fn new(spi: S) -> Self {
+        Self {
+            spi,
+            diagnostics: true,
+            _variant: PhantomData,
+        }
+    }
+
+    /// Only read the angle, skipping the magnetic field and CORDIC checks
+    pub fn without_diagnostics(mut self) -> Self {
+        self.diagnostics = false;
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.spi
+    }
+}
+
+impl<S: SpiDevice, V: As504xVariant> As504x<S, V> {
+    /// Read the register at `address`
+    pub fn read_register(&mut self, address: u16) -> Result<u16, As504xError<S::Error>> {
+        self.frame(command(READ | address))?;
+        self.frame(command(READ | NOP))
+    }
+
+    /// Send one frame, returns the response to the previous one
+    fn frame(&mut self, frame: u16) -> Result<u16, As504xError<S::Error>> {
+        let response = self.transfer(frame)?;
+
+        if !response.count_ones().is_multiple_of(2) {
+            return Err(As504xError::Parity);
+        }
+
+        if response & 0x4000 != 0 {
+            // Reading the error register clears the flag
+            self.transfer(command(READ | ERRFL))?;
+            let errors = self.transfer(command(READ | NOP))?;
+            return Err(As504xError::Command(errors & 0x3FFF));
+        }
+
+        Ok(response & 0x3FFF)
+    }
+
+    fn transfer(&mut self, frame: u16) -> Result<u16, As504xError<S::Error>> {
+        let mut buf = frame.to_be_bytes();
+        self.spi
+            .transfer_in_place(&mut buf)
+            .map_err(As504xError::Spi)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Check the magnetic field and the CORDIC
+    pub fn check_diagnostics(&mut self) -> Result<(), As504xError<S::Error>> {
+        let diagnostics = self.read_register(V::DIAGNOSTICS)?;
+        Self::check(diagnostics)
+    }
+
+    fn check(diagnostics: u16) -> Result<(), As504xError<S::Error>> {
+        if diagnostics & V::CORDIC_OVERFLOW != 0 {
+            Err(As504xError::CordicOverflow)
+        } else if diagnostics & V::MAGNET_WEAK != 0 {
+            Err(As504xError::MagnetTooWeak)
+        } else if diagnostics & V::MAGNET_STRONG != 0 {
+            Err(As504xError::MagnetTooStrong)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: SpiDevice, V: As504xVariant> SensorHardware for As504x<S, V> {
+    type Error = As504xError<S::Error>;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        let angle = if self.diagnostics {
+            // Pipelined, each frame returns what the previous one asked for
+            self.frame(command(READ | V::DIAGNOSTICS))?;
+            let diagnostics = self.frame(command(READ | V::ANGLE))?;
+            let angle = self.frame(command(READ | NOP))?;
+            Self::check(diagnostics)?;
+            angle
+        } else {
+            self.read_register(V::ANGLE)?
+        };
+
+        Ok(angle as f32 * 2. * PI / 16384.)
+    }
+
+    fn counts_per_revolution(&self) -> Option<u32> {
+        Some(16384)
+    }
+
+    fn read_raw(&mut self) -> Result<u32, Self::Error> {
+        Ok(self.read_register(V::ANGLE)? as u32)
+    }
+
+    fn magnet_status(&mut self) -> Result<MagnetStatus, Self::Error> {
+        let diagnostics = self.read_register(V::DIAGNOSTICS)?;
+
+        Ok(if diagnostics & V::MAGNET_WEAK != 0 {
+            MagnetStatus::TooWeak
+        } else if diagnostics & V::MAGNET_STRONG != 0 {
+            MagnetStatus::TooStrong
+        } else {
+            MagnetStatus::Ok
+        })
+    }
+
+    /// 0 for a strong field up to 255 for a weak one
+    fn agc(&mut self) -> Result<Option<u8>, Self::Error> {
+        Ok(Some(self.read_register(V::DIAGNOSTICS)? as u8))
+    }
+
+    fn magnitude(&mut self) -> Result<Option<u16>, Self::Error> {
+        Ok(Some(self.read_register(V::MAGNITUDE)?))
+    }
+}
+
+/// Add the even parity bit to `frame`
+fn command(frame: u16) -> u16 {
+    if !frame.count_ones().is_multiple_of(2) {
+        frame | 0x8000
+    } else {
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::*;
+    use crate::sensor::tests::MockSpi;
+
+    type Sensor = As5047p<MockSpi>;
+
+    /// Response frame carrying `value`, with even parity
+    fn word(value: u16) -> Vec<u8> {
+        command(value).to_be_bytes().to_vec()
+    }
+
+    fn sensor(responses: impl IntoIterator<Item = Vec<u8>>) -> Sensor {
+        As504x::new(MockSpi::new(responses))
+    }
+
+    #[test]
+    fn parity() {
+        assert_eq!(command(READ | As5047pRegisters::ANGLE), 0xFFFF);
+        assert_eq!(command(READ | ERRFL), 0x4001);
+        assert_eq!(command(READ | NOP), 0xC000);
+    }
+
+    #[test]
+    fn read_angle() {
+        // Whatever came before, the diagnostics, then the angle
+        let mut sensor = sensor([word(0), word(0x0080), word(0x2000)]);
+        assert_eq!(sensor.read_angle(), Ok(PI));
+
+        let written = sensor.into_inner().written;
+        assert_eq!(written, vec![word(0x7FFC), word(0x7FFF), word(0x4000)]);
+    }
+
+    #[test]
+    fn without_diagnostics() {
+        let responses = [word(0), word(0x1000), word(0), word(0x1000)];
+        let mut sensor = sensor(responses).without_diagnostics();
+        assert_eq!(sensor.read_raw(), Ok(0x1000));
+        assert_eq!(sensor.read_angle(), Ok(PI / 2.));
+        assert_eq!(sensor.into_inner().written.len(), 4);
+    }
+
+    #[test]
+    fn bad_parity() {
+        let mut sensor = sensor([vec![0x00, 0x01]]).without_diagnostics();
+        assert_eq!(sensor.read_angle(), Err(As504xError::Parity));
+    }
+
+    #[test]
+    fn command_error() {
+        // The error flag, then the error register read back: parity error
+        let mut sensor = sensor([word(0x4000), word(0), word(0x0004)]);
+        assert_eq!(
+            sensor.read_register(0x0018),
+            Err(As504xError::Command(0x0004))
+        );
+        assert_eq!(
+            sensor.into_inner().written[1..],
+            [word(0x4001), word(0x4000)]
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        for (diagnostics, error, status) in [
+            (1 << 9, As504xError::CordicOverflow, MagnetStatus::Ok),
+            (1 << 11, As504xError::MagnetTooWeak, MagnetStatus::TooWeak),
+            (
+                1 << 10,
+                As504xError::MagnetTooStrong,
+                MagnetStatus::TooStrong,
+            ),
+        ] {
+            let mut reading = sensor([word(0), word(diagnostics), word(0x2000)]);
+            assert_eq!(reading.read_angle(), Err(error));
+
+            let mut checking = sensor([word(0), word(diagnostics)]);
+            assert_eq!(checking.magnet_status(), Ok(status));
+        }
+    }
+
+    #[test]
+    fn as5048a() {
+        let responses = [word(0), word(1 << 11 | 0x80), word(0x2000)];
+        let mut sensor = As5048a::new(MockSpi::new(responses));
+        assert_eq!(sensor.read_angle(), Err(As504xError::MagnetTooWeak));
+        assert_eq!(sensor.into_inner().written[0], word(READ | 0x3FFD));
+
+        let mut sensor = As5048a::new(MockSpi::new([word(0), word(0x0180)]));
+        assert_eq!(sensor.agc(), Ok(Some(0x80)));
+        assert_eq!(
+            sensor.into_inner().written,
+            [word(READ | 0x3FFD), word(READ)]
+        );
+    }
+}
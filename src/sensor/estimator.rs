@@ -0,0 +1,195 @@
+use crate::util::{Duration, Velocity};
+
+/// How [`Sensor`] turns angle readings into a velocity
+///
+/// A plain difference between two readings is fine at low update rates, but
+/// at tens of kHz a 12-bit sensor moves by a count or none between readings
+/// and the velocity jumps between 0 and a few rad/s. The other estimators
+/// trade some lag for a clean signal.
+///
+/// [`Sensor`]: crate::sensor::Sensor
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum VelocityEstimator {
+    /// Difference between the last two readings
+    #[default]
+    Difference,
+
+    /// Difference through a first-order low-pass filter with a time constant
+    /// in s
+    LowPass { time_constant: f32 },
+
+    /// Angle tracking loop, which follows the angle with a second order
+    /// observer of the given bandwidth in rad/s
+    ///
+    /// Critically damped, it follows a constant velocity without error and
+    /// filters the quantization above the bandwidth.
+    Pll { bandwidth: f32 },
+
+    /// Difference over at least `min_dt`, readings in between accumulate and
+    /// the velocity holds
+    MinDt { min_dt: Duration },
+}
+
+/// [`VelocityEstimator`] with its state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Estimator {
+    kind: VelocityEstimator,
+    velocity: f32,
+
+    /// Angle not yet accounted for, in rad: the tracking error of the PLL or
+    /// the angle accumulated towards `min_dt`
+    angle: f32,
+
+    /// Time accumulated towards `min_dt`
+    elapsed: Duration,
+}
+
+impl Estimator {
+    pub(crate) fn new(kind: VelocityEstimator) -> Self {
+        Self {
+            kind,
+            velocity: 0.,
+            angle: 0.,
+            elapsed: Duration::from_ticks(0),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> VelocityEstimator {
+        self.kind
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new(self.kind);
+    }
+
+    /// Feed the angle moved (in rad) over `dt`, returns the new estimate
+    pub(crate) fn update(&mut self, delta: f32, dt: Duration) -> Velocity {
+        let secs = dt.to_micros() as f32 * 1e-6;
+        if secs <= 0. {
+            return Velocity::per_sec(self.velocity);
+        }
+
+        match self.kind {
+            VelocityEstimator::Difference => self.velocity = delta / secs,
+            VelocityEstimator::LowPass { time_constant } => {
+                let alpha = secs / (time_constant + secs);
+                self.velocity += alpha * (delta / secs - self.velocity);
+            }
+            VelocityEstimator::Pll { bandwidth } => {
+                let (kp, ki) = (2. * bandwidth, bandwidth * bandwidth);
+
+                // The estimated angle moved by velocity * dt, the error is
+                // whatever the reading moved beyond that
+                self.angle += delta - self.velocity * secs;
+                self.velocity += ki * self.angle * secs;
+                self.angle -= kp * self.angle * secs;
+            }
+            VelocityEstimator::MinDt { min_dt } => {
+                self.angle += delta;
+                self.elapsed += dt;
+                if self.elapsed >= min_dt {
+                    self.velocity = self.angle / (self.elapsed.to_micros() as f32 * 1e-6);
+                    self.angle = 0.;
+                    self.elapsed = Duration::from_ticks(0);
+                }
+            }
+        }
+
+        Velocity::per_sec(self.velocity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f32::consts::TAU;
+
+    use super::*;
+
+    /// Reading period in μs
+    const PERIOD: u64 = 100;
+
+    const ALL: [VelocityEstimator; 4] = [
+        VelocityEstimator::Difference,
+        VelocityEstimator::LowPass {
+            time_constant: 10e-3,
+        },
+        VelocityEstimator::Pll { bandwidth: 200. },
+        VelocityEstimator::MinDt {
+            min_dt: Duration::millis(1),
+        },
+    ];
+
+    /// Estimates over `samples` readings of a rotor moving at `velocity(t)`,
+    /// with `counts` per revolution if quantized
+    fn trace(
+        kind: VelocityEstimator,
+        velocity: impl Fn(f32) -> f32,
+        counts: Option<u32>,
+        samples: usize,
+    ) -> impl Iterator<Item = f32> {
+        let mut estimator = Estimator::new(kind);
+        let dt = PERIOD as f32 * 1e-6;
+        let quantize = move |angle: f32| match counts {
+            Some(counts) => (angle / TAU * counts as f32).floor() * TAU / counts as f32,
+            None => angle,
+        };
+
+        let (mut angle, mut reading) = (0., 0.);
+        (1..=samples).map(move |sample| {
+            angle += velocity(sample as f32 * dt) * dt;
+            let delta = quantize(angle) - reading;
+            reading += delta;
+            estimator.update(delta, Duration::micros(PERIOD)).as_secs()
+        })
+    }
+
+    #[test]
+    fn ramp() {
+        // A constant velocity comes out as it is once settled
+        for kind in ALL {
+            let last = trace(kind, |_| 10., None, 5000).last().unwrap();
+            assert!((last - 10.).abs() < 1e-2, "{kind:?} {last}");
+        }
+    }
+
+    #[test]
+    fn step() {
+        let step = |kind| trace(kind, |_| 10., None, 1000).collect::<Vec<_>>();
+
+        // Right away
+        assert!((step(ALL[0])[0] - 10.).abs() < 1e-3);
+
+        // 63 % after the time constant
+        let low_pass = step(ALL[1]);
+        assert!((low_pass[99] - 6.3).abs() < 0.2, "{}", low_pass[99]);
+        assert!((low_pass[999] - 10.).abs() < 0.01);
+
+        // Critically damped, no overshoot past a few %
+        let pll = step(ALL[2]);
+        assert!(pll.iter().all(|&v| v < 10.5));
+        assert!((pll[499] - 10.).abs() < 0.1, "{}", pll[499]);
+
+        // Holds until `min_dt` passed
+        let min_dt = step(ALL[3]);
+        assert!(min_dt[..9].iter().all(|&v| v == 0.));
+        assert!((min_dt[9] - 10.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn quantized() {
+        // 2 rad/s is under one count of a 12-bit sensor per reading at 10 kHz
+        for kind in ALL {
+            let trace: Vec<_> = trace(kind, |_| 2., Some(4096), 20_000)
+                .skip(10_000)
+                .collect();
+            let mean = trace.iter().sum::<f32>() / trace.len() as f32;
+            let ripple = trace.iter().map(|v| (v - 2.).abs()).fold(0., f32::max);
+            assert!((mean - 2.).abs() < 0.05, "{kind:?} {mean}");
+
+            match kind {
+                VelocityEstimator::Difference => assert!(ripple > 10., "{ripple}"),
+                _ => assert!(ripple < 2., "{kind:?} {ripple}"),
+            }
+        }
+    }
+}
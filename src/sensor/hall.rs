@@ -0,0 +1,513 @@
+use core::{cell::Cell, convert::Infallible, f32::consts::PI};
+
+use critical_section::Mutex;
+use embedded_hal::digital::InputPin;
+
+use crate::{
+    sensor::SensorHardware,
+    util::{Clock, Duration, Instant, SystemClock, Velocity},
+};
+
+/// Hall states (A in bit 0, B in bit 1, C in bit 2) in electrical order, each
+/// one covering 60°
+pub(crate) const HALL_SEQUENCE: [u8; 6] = [0b001, 0b011, 0b010, 0b110, 0b100, 0b101];
+
+const SECTOR: f32 = PI / 3.;
+
+/// Sector changes and the speed between them, the decoding shared by
+/// [`HallSensor`] and [`SharedHall`]
+///
+/// The angle is interpolated within a sector from the speed measured over the
+/// last one, but never past the sector's far edge, so it stops when the rotor
+/// does. Until the first edge, it sits in the middle of the sector.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hall {
+    pole_pairs: u8,
+
+    /// Sectors since the reference, within one mechanical revolution
+    position: Option<u16>,
+
+    /// Time and direction of the last edge
+    edge: Option<(Instant, i8)>,
+
+    /// Time between the last two edges
+    interval: Option<Duration>,
+
+    invalid: Option<u8>,
+    changed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HallError<E> {
+    Pin(E),
+
+    /// All sensors high or all low, usually a disconnected sensor
+    InvalidState(u8),
+}
+
+impl Hall {
+    pub const fn new(pole_pairs: u8) -> Self {
+        Self {
+            pole_pairs,
+            position: None,
+            edge: None,
+            interval: None,
+            invalid: None,
+            changed: false,
+        }
+    }
+
+    /// Record the hall `state` (A in bit 0, B in bit 1, C in bit 2) read at
+    /// `now`, returns the new sector if it changed
+    pub fn update(&mut self, state: u8, now: Instant) -> Option<u8> {
+        let Some(sector) = HALL_SEQUENCE.iter().position(|s| *s == state & 0b111) else {
+            self.invalid = Some(state);
+            return None;
+        };
+        let sectors = 6 * self.pole_pairs as u16;
+
+        let Some(position) = self.position else {
+            self.position = Some(sector as u16);
+            self.changed = true;
+            return Some(sector as u8);
+        };
+
+        let direction = self.edge.map(|(_, direction)| direction);
+        let step: i8 = match (
+            (sector as i8 - (position % 6) as i8).rem_euclid(6),
+            direction,
+        ) {
+            (0, _) => return None,
+            (1, _) => 1,
+            (5, _) => -1,
+            // One edge missed, assume the rotor kept going
+            (2, Some(1)) => 2,
+            (4, Some(-1)) => -2,
+            // Can't tell the direction, only keep the sector
+            _ => {
+                self.position = Some(position - position % 6 + sector as u16);
+                self.edge = None;
+                self.interval = None;
+                self.changed = true;
+                return Some(sector as u8);
+            }
+        };
+
+        self.interval = match self.edge {
+            // A reversal says nothing about the speed
+            Some((prev, direction)) if direction == step.signum() => {
+                Some((now - prev) / step.unsigned_abs() as u32)
+            }
+            _ => None,
+        };
+        self.edge = Some((now, step.signum()));
+        self.position = Some((position as i32 + step as i32).rem_euclid(sectors as i32) as u16);
+        self.changed = true;
+
+        Some(sector as u8)
+    }
+
+    pub fn pole_pairs(&self) -> u8 {
+        self.pole_pairs
+    }
+
+    /// Sectors per mechanical revolution
+    pub fn counts_per_revolution(&self) -> u32 {
+        6 * self.pole_pairs as u32
+    }
+
+    /// Current sector, 0 to 5, once a valid state has been read
+    pub fn sector(&self) -> Option<u8> {
+        self.position.map(|position| (position % 6) as u8)
+    }
+
+    /// New sector since the last call, if the rotor moved
+    pub fn take_change(&mut self) -> Option<u8> {
+        core::mem::take(&mut self.changed)
+            .then(|| self.sector())
+            .flatten()
+    }
+
+    /// Invalid state read since the last call
+    pub fn take_invalid(&mut self) -> Option<u8> {
+        self.invalid.take()
+    }
+
+    /// Mechanical velocity at `now`, from the time between edges
+    ///
+    /// Decays once the next edge is overdue, and drops to zero when it is
+    /// twice as late as the last one.
+    pub fn velocity(&self, now: Instant) -> Velocity {
+        let (Some((edge, direction)), Some(interval)) = (self.edge, self.interval) else {
+            return Velocity::ZERO;
+        };
+
+        let since = (now - edge).to_micros() as f32;
+        let interval = interval.to_micros() as f32;
+        if since > 2. * interval {
+            return Velocity::ZERO;
+        }
+
+        let velocity = SECTOR / interval.max(since) / self.pole_pairs as f32;
+        Velocity::per_micro(direction as f32 * velocity)
+    }
+
+    /// Mechanical angle in rad at `now`, interpolated within the sector
+    pub fn angle(&self, now: Instant) -> f32 {
+        let Some(position) = self.position else {
+            return 0.;
+        };
+        let position = position as f32;
+
+        let sectors = match (self.edge, self.interval) {
+            (Some((edge, direction)), Some(interval)) => {
+                let since = (now - edge).to_micros() as f32;
+                let travelled = (since / interval.to_micros() as f32).min(0.99);
+                match direction {
+                    1 => position + travelled,
+                    _ => position + 1. - travelled,
+                }
+            }
+            // Entered through an edge but the speed isn't known yet
+            (Some((_, 1)), None) => position,
+            (Some(_), None) => position + 1.,
+            (None, _) => position + 0.5,
+        };
+
+        (sectors * SECTOR / self.pole_pairs as f32) % (2. * PI)
+    }
+}
+
+/// Three hall sensors read by polling their pins on every update
+///
+/// Edges are only caught as often as the control loop runs, which is fine at
+/// low speed. Otherwise, see [`SharedHall`].
+pub struct HallSensor<A, B, C, K = SystemClock> {
+    a: A,
+    b: B,
+    c: C,
+    hall: Hall,
+    clock: K,
+}
+
+impl<A, B, C> HallSensor<A, B, C> {
+    /// Hall sensors on pins `a`, `b` and `c`, of a motor with `pole_pairs`,
+    /// the same as its `POLE`
+    pub fn new(a: A, b: B, c: C, pole_pairs: u8) -> Self {
+        Self {
+            a,
+            b,
+            c,
+            hall: Hall::new(pole_pairs),
+            clock: SystemClock,
+        }
+    }
+}
+
+impl<A, B, C, K> HallSensor<A, B, C, K> {
+    pub fn with_clock<N>(self, clock: N) -> HallSensor<A, B, C, N> {
+        HallSensor {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            hall: self.hall,
+            clock,
+        }
+    }
+
+    pub fn hall(&self) -> &Hall {
+        &self.hall
+    }
+
+    /// See [`Hall::take_change`]
+    pub fn take_change(&mut self) -> Option<u8> {
+        self.hall.take_change()
+    }
+}
+
+impl<A, B, C, K> HallSensor<A, B, C, K>
+where
+    A: InputPin,
+    B: InputPin<Error = A::Error>,
+    C: InputPin<Error = A::Error>,
+{
+    /// Hall state, A in bit 0, B in bit 1, C in bit 2
+    pub fn state(&mut self) -> Result<u8, A::Error> {
+        Ok(self.a.is_high()? as u8
+            | (self.b.is_high()? as u8) << 1
+            | (self.c.is_high()? as u8) << 2)
+    }
+}
+
+impl<A, B, C, K> SensorHardware for HallSensor<A, B, C, K>
+where
+    A: InputPin,
+    B: InputPin<Error = A::Error>,
+    C: InputPin<Error = A::Error>,
+    K: Clock,
+{
+    type Error = HallError<A::Error>;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        let state = self.state().map_err(HallError::Pin)?;
+        let now = self.clock.now();
+
+        self.hall.update(state, now);
+        if let Some(state) = self.hall.take_invalid() {
+            return Err(HallError::InvalidState(state));
+        }
+
+        Ok(self.hall.angle(now))
+    }
+
+    fn pole_pairs(&self) -> Option<u8> {
+        Some(self.hall.pole_pairs())
+    }
+
+    fn counts_per_revolution(&self) -> Option<u32> {
+        Some(self.hall.counts_per_revolution())
+    }
+}
+
+/// Hall decoding fed from GPIO interrupts, so no edge is missed
+///
+/// Keep it in a `static`, call [`SharedHall::edge`] from the interrupt handler
+/// of the three pins (both edges), and give [`SharedHall::sensor`] to the
+/// motor:
+///
+/// ```ignore
+/// static HALL: SharedHall = SharedHall::new(7);
+///
+/// #[handler]
+/// fn on_hall_edge() {
+///     let state = /* read the pins and clear their interrupts */;
+///     HALL.edge(state, SystemClock.now());
+/// }
+///
+/// let motor = BLDC::new::<7>(pwm).with_sensor(HALL.sensor(SystemClock));
+/// ```
+pub struct SharedHall(Mutex<Cell<Hall>>);
+
+impl SharedHall {
+    pub const fn new(pole_pairs: u8) -> Self {
+        Self(Mutex::new(Cell::new(Hall::new(pole_pairs))))
+    }
+
+    /// Record the hall `state` read at `now`, see [`Hall::update`]
+    pub fn edge(&self, state: u8, now: Instant) -> Option<u8> {
+        self.with(|hall| hall.update(state, now))
+    }
+
+    /// See [`Hall::take_change`]
+    pub fn take_change(&self) -> Option<u8> {
+        self.with(Hall::take_change)
+    }
+
+    pub fn hall(&self) -> Hall {
+        critical_section::with(|cs| self.0.borrow(cs).get())
+    }
+
+    /// The angle as [`SensorHardware`], timed with `clock`
+    pub fn sensor<K: Clock>(&'static self, clock: K) -> SharedHallSensor<K> {
+        SharedHallSensor { hall: self, clock }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Hall) -> R) -> R {
+        critical_section::with(|cs| {
+            let cell = self.0.borrow(cs);
+            let mut hall = cell.get();
+            let result = f(&mut hall);
+            cell.set(hall);
+            result
+        })
+    }
+}
+
+/// Reads the angle decoded by a [`SharedHall`]
+pub struct SharedHallSensor<K = SystemClock> {
+    hall: &'static SharedHall,
+    clock: K,
+}
+
+impl<K: Clock> SensorHardware for SharedHallSensor<K> {
+    type Error = HallError<Infallible>;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        let now = self.clock.now();
+        let (hall, invalid) = self.hall.with(|hall| (*hall, hall.take_invalid()));
+
+        match invalid {
+            Some(state) => Err(HallError::InvalidState(state)),
+            None => Ok(hall.angle(now)),
+        }
+    }
+
+    fn pole_pairs(&self) -> Option<u8> {
+        Some(self.hall.hall().pole_pairs())
+    }
+
+    fn counts_per_revolution(&self) -> Option<u32> {
+        Some(self.hall.hall().counts_per_revolution())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        motor::BLDC,
+        sim::{SimParams, Simulator},
+    };
+
+    fn at(micros: u64) -> Instant {
+        Instant::from_ticks(micros)
+    }
+
+    /// Hall state of `sector`
+    fn state(sector: usize) -> u8 {
+        HALL_SEQUENCE[sector % 6]
+    }
+
+    fn assert_angle(hall: &Hall, now: Instant, sectors: f32) {
+        let angle = hall.angle(now);
+        let expected = sectors * SECTOR / hall.pole_pairs() as f32 % (2. * PI);
+        assert!((angle - expected).abs() < 1e-4, "{angle} != {expected}");
+    }
+
+    #[test]
+    fn steps() {
+        let mut hall = Hall::new(1);
+        assert_eq!(hall.sector(), None);
+
+        // Middle of the sector until the first edge
+        assert_eq!(hall.update(state(2), at(0)), Some(2));
+        assert_eq!(hall.update(state(2), at(500)), None);
+        assert_eq!(hall.take_change(), Some(2));
+        assert_eq!(hall.take_change(), None);
+        assert_angle(&hall, at(500), 2.5);
+
+        // Entered at its near edge, the speed isn't known yet
+        assert_eq!(hall.update(state(3), at(1000)), Some(3));
+        assert_angle(&hall, at(1500), 3.);
+        assert_eq!(hall.velocity(at(1500)), Velocity::ZERO);
+
+        // One sector per ms from then on
+        assert_eq!(hall.update(state(4), at(2000)), Some(4));
+        assert_angle(&hall, at(2500), 4.5);
+        let velocity = hall.velocity(at(2500)).as_secs();
+        assert!((velocity - SECTOR * 1e3).abs() < 1e-2, "{velocity}");
+
+        // A missed edge counts as two steps, and the wrap around a revolution
+        assert_eq!(hall.update(state(0), at(4000)), Some(0));
+        assert_eq!(hall.take_change(), Some(0));
+        assert_angle(&hall, at(4250), 6.25);
+        let velocity = hall.velocity(at(4250)).as_secs();
+        assert!((velocity - SECTOR * 1e3).abs() < 1e-2, "{velocity}");
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut hall = Hall::new(7);
+        for (sector, time) in [(0, 0), (1, 1000), (2, 2000)] {
+            hall.update(state(sector), at(time));
+        }
+
+        assert_angle(&hall, at(2250), 2.25);
+        assert_angle(&hall, at(2990), 2.99);
+
+        // Stops short of the next sector when the rotor does
+        assert_angle(&hall, at(3500), 2.99);
+        assert_angle(&hall, at(10_000), 2.99);
+    }
+
+    #[test]
+    fn velocity_decays() {
+        let mut hall = Hall::new(1);
+        for (sector, time) in [(0, 0), (1, 1000), (2, 2000)] {
+            hall.update(state(sector), at(time));
+        }
+        let velocity = |micros| hall.velocity(at(micros)).as_secs();
+
+        assert!((velocity(2500) - SECTOR * 1e3).abs() < 1e-2);
+
+        // Slower once the edge is overdue, as if it came now
+        assert!((velocity(3500) - SECTOR / 1.5e-3).abs() < 1e-2);
+        assert!((velocity(4000) - SECTOR / 2e-3).abs() < 1e-2);
+
+        // Stopped past twice the interval
+        assert_eq!(velocity(4001), 0.);
+    }
+
+    #[test]
+    fn reverse() {
+        let mut hall = Hall::new(1);
+        for (sector, time) in [(3, 0), (4, 1000), (5, 2000)] {
+            hall.update(state(sector), at(time));
+        }
+        assert!(hall.velocity(at(2000)).as_secs() > 0.);
+
+        // Back into sector 4 through its far edge, the speed is unknown again
+        assert_eq!(hall.update(state(4), at(2500)), Some(4));
+        assert_eq!(hall.velocity(at(2500)), Velocity::ZERO);
+        assert_angle(&hall, at(2500), 5.);
+
+        // And counted down from there
+        assert_eq!(hall.update(state(3), at(3500)), Some(3));
+        assert_angle(&hall, at(3750), 3.75);
+        let velocity = hall.velocity(at(3750)).as_secs();
+        assert!((velocity + SECTOR * 1e3).abs() < 1e-2, "{velocity}");
+
+        // Missed edge backwards
+        assert_eq!(hall.update(state(1), at(5500)), Some(1));
+        assert_angle(&hall, at(5500), 2.);
+        assert!((hall.velocity(at(5500)).as_secs() + SECTOR * 1e3).abs() < 1e-2);
+    }
+
+    #[test]
+    fn unknown_direction() {
+        // Two sectors on with nothing to tell the way round
+        let mut hall = Hall::new(1);
+        hall.update(state(0), at(0));
+        assert_eq!(hall.update(state(2), at(1000)), Some(2));
+        assert_eq!(hall.sector(), Some(2));
+        assert_eq!(hall.velocity(at(1000)), Velocity::ZERO);
+        assert_angle(&hall, at(1000), 2.5);
+
+        // Half a turn away, whichever way it was running
+        for (sector, time) in [(3, 2000), (4, 3000)] {
+            hall.update(state(sector), at(time));
+        }
+        assert_eq!(hall.update(state(1), at(4000)), Some(1));
+        assert_eq!(hall.velocity(at(4000)), Velocity::ZERO);
+        assert_angle(&hall, at(4000), 1.5);
+    }
+
+    #[test]
+    fn invalid_state() {
+        let mut hall = Hall::new(1);
+        hall.update(state(1), at(0));
+
+        for invalid in [0b000, 0b111] {
+            assert_eq!(hall.update(invalid, at(1000)), None);
+            assert_eq!(hall.take_invalid(), Some(invalid));
+            assert_eq!(hall.sector(), Some(1));
+        }
+        assert_eq!(hall.take_invalid(), None);
+    }
+
+    #[test]
+    fn pole_pairs() {
+        static HALL: SharedHall = SharedHall::new(7);
+        let sim = Simulator::new(SimParams::default());
+        let motor = BLDC::new::<7>(sim.pwm()).with_sensor(HALL.sensor(sim.clock()));
+        assert_eq!(motor.sensor().counts_per_revolution(), Some(42));
+    }
+
+    #[test]
+    #[should_panic(expected = "pole pairs")]
+    fn other_pole_pairs() {
+        static HALL: SharedHall = SharedHall::new(4);
+        let sim = Simulator::new(SimParams::default());
+        let _ = BLDC::new::<7>(sim.pwm()).with_sensor(HALL.sensor(sim.clock()));
+    }
+}
@@ -0,0 +1,817 @@
+use core::{
+    f32::consts::PI,
+    fmt::{Debug, Display},
+};
+
+use embedded_hal::i2c::I2c;
+use num_traits::Float;
+
+use crate::{
+    motor::AlphaBeta,
+    util::{Clock, Duration, Instant, SystemClock, Velocity},
+};
+
+mod_use::mod_use![estimator, hall, quadrature, as504x, mt6701, tle5012];
+
+#[cfg(target_arch = "xtensa")]
+mod pcnt;
+#[cfg(target_arch = "xtensa")]
+pub use pcnt::*;
+
+const TWO_PI: f32 = 2. * PI;
+
+pub trait SensorHardware {
+    type Error: Debug;
+
+    /// Reads the current angle in rad, results should range between 0 and 2π
+    fn read_angle(&mut self) -> Result<f32, Self::Error>;
+
+    /// Voltage applied since the last call and current measured `now`, both
+    /// in the stationary frame
+    ///
+    /// Only used by sensors estimating the angle from the motor's electrical
+    /// behaviour, like [`FluxObserver`]. Called by [`Foc`] before every
+    /// reading when it has a current sensor.
+    ///
+    /// [`FluxObserver`]: crate::motor::FluxObserver
+    /// [`Foc`]: crate::motor::Foc
+    fn observe(&mut self, _voltage: AlphaBeta, _current: AlphaBeta, _now: Instant) {}
+
+    /// Whether the angle is absolute
+    ///
+    /// Incremental encoders only count from where they were powered on until
+    /// they pass their index, angles read before that are relative.
+    fn is_referenced(&self) -> bool {
+        true
+    }
+
+    /// Pole pairs the angle is decoded for, if it depends on them like with
+    /// hall sensors
+    ///
+    /// [`BLDC::with_sensor`] checks it against the motor's.
+    ///
+    /// [`BLDC::with_sensor`]: crate::motor::BLDC::with_sensor
+    fn pole_pairs(&self) -> Option<u8> {
+        None
+    }
+
+    /// Resolution of the raw readings, if the sensor has one
+    fn counts_per_revolution(&self) -> Option<u32> {
+        None
+    }
+
+    /// Reads the current angle in counts, straight from the sensor
+    ///
+    /// Sensors without a resolution of their own scale the angle to 16 bits.
+    fn read_raw(&mut self) -> Result<u32, Self::Error> {
+        let counts = self.counts_per_revolution().unwrap_or(1 << 16);
+        Ok((self.read_angle()? / TWO_PI * counts as f32) as u32 % counts)
+    }
+
+    /// Reads whether the magnet is where it should be, for magnetic encoders
+    fn magnet_status(&mut self) -> Result<MagnetStatus, Self::Error> {
+        Ok(MagnetStatus::Unknown)
+    }
+
+    /// Reads the level of the automatic gain control, which rises as the
+    /// field gets weaker
+    ///
+    /// The range is the sensor's own, e.g. 0 to 255 for the AS5600 at 5 V.
+    fn agc(&mut self) -> Result<Option<u8>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Reads the magnitude of the field, in the sensor's own unit
+    fn magnitude(&mut self) -> Result<Option<u16>, Self::Error> {
+        Ok(None)
+    }
+}
+
+/// Health of the magnet of a magnetic encoder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MagnetStatus {
+    /// The sensor can't tell
+    Unknown,
+
+    Ok,
+
+    /// Too far from the sensor, or too weak
+    TooWeak,
+
+    /// Too close to the sensor, or too strong
+    TooStrong,
+
+    /// Out of range, the sensor doesn't tell which way
+    OutOfRange,
+
+    Missing,
+}
+
+impl MagnetStatus {
+    /// Whether readings can be trusted, as far as the sensor knows
+    pub fn is_usable(self) -> bool {
+        matches!(self, MagnetStatus::Unknown | MagnetStatus::Ok)
+    }
+}
+
+impl<I: I2c<Error: Debug>> SensorHardware for as5600::As5600<I> {
+    type Error = as5600::error::Error<I::Error>;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        Ok(self.raw_angle()? as f32 * TWO_PI / 4096.)
+    }
+
+    fn counts_per_revolution(&self) -> Option<u32> {
+        Some(4096)
+    }
+
+    fn read_raw(&mut self) -> Result<u32, Self::Error> {
+        Ok(self.raw_angle()? as u32)
+    }
+
+    fn magnet_status(&mut self) -> Result<MagnetStatus, Self::Error> {
+        use as5600::status::Status;
+
+        Ok(match as5600::As5600::magnet_status(self)? {
+            Status::MagnetDetected => MagnetStatus::Ok,
+            Status::MagnetLow => MagnetStatus::TooWeak,
+            Status::MagnetHigh => MagnetStatus::TooStrong,
+            Status::MagnetNotDetected => MagnetStatus::Missing,
+        })
+    }
+
+    fn agc(&mut self) -> Result<Option<u8>, Self::Error> {
+        Ok(Some(self.automatic_gain_control()?))
+    }
+
+    fn magnitude(&mut self) -> Result<Option<u16>, Self::Error> {
+        Ok(Some(as5600::As5600::magnitude(self)?))
+    }
+}
+
+/// A wrapper around sensor hardware that provides state
+///
+/// The state includes the current angle, the total angle, the number of full
+/// rotations, the angular velocity, and the time since the last record. For
+/// more, see [`SensorState`]. Time is taken from the [`Clock`] `K`, the system
+/// timer by default. The velocity is estimated as set with
+/// [`Sensor::with_velocity_estimator`].
+#[derive(Debug, PartialEq)]
+pub struct Sensor<H, K = SystemClock> {
+    inner: H,
+    state: SensorState,
+    clock: K,
+    direction: Direction,
+    estimator: Estimator,
+    glitches: GlitchPolicy,
+
+    /// How far the angle jumped when the sensor found its reference, until
+    /// taken
+    shift: Option<f32>,
+}
+
+/// Which way the sensor counts relative to the motor's electrical rotation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    Normal,
+
+    /// Sensor angle decreases as the electrical angle increases, readings are
+    /// mirrored
+    Inverted,
+}
+
+impl<I> Sensor<I> {
+    pub fn new(hardware: I) -> Self {
+        Self {
+            inner: hardware,
+            state: SensorState::default(),
+            clock: SystemClock,
+            direction: Direction::Normal,
+            estimator: Estimator::new(VelocityEstimator::Difference),
+            glitches: GlitchPolicy::Accept,
+            shift: None,
+        }
+    }
+}
+
+impl<H, K> Sensor<H, K> {
+    pub fn with_clock<N>(self, clock: N) -> Sensor<H, N> {
+        Sensor { clock, ..self }
+    }
+
+    /// Swap the sensor hardware, keeping the clock, direction, velocity
+    /// estimator and glitch policy
+    pub fn with_hardware<N>(self, hardware: N) -> Sensor<N, K> {
+        Sensor {
+            inner: hardware,
+            state: SensorState::default(),
+            clock: self.clock,
+            direction: self.direction,
+            estimator: Estimator::new(self.estimator.kind()),
+            glitches: self.glitches,
+            shift: None,
+        }
+    }
+
+    pub fn with_velocity_estimator(mut self, estimator: VelocityEstimator) -> Self {
+        self.set_velocity_estimator(estimator);
+        self
+    }
+
+    pub fn velocity_estimator(&self) -> VelocityEstimator {
+        self.estimator.kind()
+    }
+
+    pub fn set_velocity_estimator(&mut self, estimator: VelocityEstimator) {
+        self.estimator = Estimator::new(estimator);
+    }
+
+    pub fn with_glitch_policy(mut self, glitches: GlitchPolicy) -> Self {
+        self.glitches = glitches;
+        self
+    }
+
+    pub fn glitch_policy(&self) -> GlitchPolicy {
+        self.glitches
+    }
+
+    pub fn clock(&self) -> &K {
+        &self.clock
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Changing the direction invalidates the state, [`Sensor::reset`] it
+    /// afterwards
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+
+    pub fn state(&self) -> SensorState {
+        self.state
+    }
+
+    pub fn hardware(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn hardware_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H: SensorHardware, K> Sensor<H, K> {
+    /// See [`SensorHardware::counts_per_revolution`]
+    pub fn counts_per_revolution(&self) -> Option<u32> {
+        self.inner.counts_per_revolution()
+    }
+
+    /// Raw reading of the hardware, see [`SensorHardware::read_raw`]
+    ///
+    /// Not mirrored for an inverted [`Direction`].
+    pub fn read_raw(&mut self) -> Result<u32, H::Error> {
+        self.inner.read_raw()
+    }
+
+    /// See [`SensorHardware::magnet_status`]
+    pub fn magnet_status(&mut self) -> Result<MagnetStatus, H::Error> {
+        self.inner.magnet_status()
+    }
+
+    /// See [`SensorHardware::agc`]
+    pub fn agc(&mut self) -> Result<Option<u8>, H::Error> {
+        self.inner.agc()
+    }
+
+    /// See [`SensorHardware::magnitude`]
+    pub fn magnitude(&mut self) -> Result<Option<u16>, H::Error> {
+        self.inner.magnitude()
+    }
+}
+
+impl<H, K: Clock> Sensor<H, K> {
+    pub fn reset(&mut self) {
+        let referenced = self.state.referenced;
+        self.state = SensorState::new(self.clock.now());
+        self.state.referenced = referenced;
+        self.estimator.reset();
+        self.shift = None;
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+}
+
+impl<H, K> Sensor<H, K> {
+    /// How far (in rad) the angle jumped when the sensor found its reference,
+    /// once
+    pub fn take_shift(&mut self) -> Option<f32> {
+        self.shift.take()
+    }
+}
+
+impl<H: SensorHardware, K: Clock> Sensor<H, K> {
+    /// See [`SensorHardware::observe`]
+    pub fn observe(&mut self, voltage: AlphaBeta, current: AlphaBeta) {
+        self.inner.observe(voltage, current, self.clock.now());
+    }
+
+    pub fn update(&mut self) -> Result<(), H::Error> {
+        let angle = match (self.direction, self.inner.read_angle()?) {
+            (Direction::Normal, angle) => angle,
+            (Direction::Inverted, angle) if angle > 0. => TWO_PI - angle,
+            (Direction::Inverted, _) => 0.,
+        };
+
+        let now = self.clock.now();
+        match self.inner.is_referenced() {
+            true if !self.state.referenced => {
+                // Compare against where the rotor would be without the jump,
+                // nothing moves when it is referenced from the start
+                if self.state.seeded {
+                    let dt = (now - self.state.prev.instant).to_micros() as f32 * 1e-6;
+                    let expected = self.state.angle + self.state.velocity.as_secs() * dt;
+                    self.shift = Some(angle - expected);
+                }
+                self.state.rebase(angle, now);
+            }
+            referenced => {
+                self.state.referenced = referenced;
+                self.state
+                    .record(angle, now, &mut self.estimator, self.glitches);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SensorState {
+    angle: f32,
+    prev: Snapshot,
+    full_rotations: i64,
+    velocity: Velocity,
+    referenced: bool,
+
+    /// Whether a reading was taken since the reset, the first one sets the
+    /// angle
+    seeded: bool,
+
+    /// Readings rejected in a row, and in total
+    rejected: u8,
+    glitches: u32,
+}
+
+impl Default for SensorState {
+    fn default() -> Self {
+        Self::new(Instant::from_ticks(0))
+    }
+}
+
+impl SensorState {
+    fn new(now: Instant) -> Self {
+        Self {
+            angle: 0.,
+            prev: Snapshot {
+                instant: now,
+                dt: Duration::millis(1),
+                total_angle: 0.,
+            },
+            full_rotations: 0,
+            velocity: Velocity::ZERO,
+            referenced: true,
+            seeded: false,
+            rejected: 0,
+            glitches: 0,
+        }
+    }
+
+    /// Jump to `angle` without counting it as movement
+    fn seed(&mut self, angle: f32, now: Instant) {
+        self.angle = angle;
+        self.full_rotations = 0;
+        self.prev = Snapshot {
+            dt: now - self.prev.instant,
+            instant: now,
+            total_angle: angle,
+        };
+        self.seeded = true;
+    }
+
+    /// Jump to `angle` once the sensor found its reference
+    fn rebase(&mut self, angle: f32, now: Instant) {
+        self.seed(angle, now);
+        self.referenced = true;
+    }
+
+    fn record(
+        &mut self,
+        new_angle: f32,
+        now: Instant,
+        estimator: &mut Estimator,
+        glitches: GlitchPolicy,
+    ) {
+        // Nothing to compare the first reading with, the rotor is wherever it
+        // says
+        if !self.seeded {
+            self.seed(new_angle, now);
+            return;
+        }
+
+        let dt = now - self.prev.instant;
+
+        // Unwrap around where the rotor should be by now rather than where it
+        // was, so the wrap threshold scales with the sample rate: readings
+        // are unambiguous as long as the velocity changes by less than half
+        // a revolution per sample
+        let expected = self.velocity.as_secs() * dt.to_micros() as f32 * 1e-6;
+        let deviation = new_angle - self.angle - expected + PI;
+        let deviation = deviation - TWO_PI * Float::floor(deviation / TWO_PI) - PI;
+
+        if let GlitchPolicy::Reject {
+            max_jump,
+            max_rejected,
+        } = glitches
+        {
+            if deviation.abs() > max_jump && self.rejected < max_rejected {
+                // Keep the previous reading, the next one spans both samples
+                self.rejected += 1;
+                self.glitches = self.glitches.saturating_add(1);
+                return;
+            }
+        }
+        self.rejected = 0;
+
+        let delta = expected + deviation;
+        self.full_rotations += Float::round((self.angle + delta - new_angle) / TWO_PI) as i64;
+        self.angle = new_angle;
+        self.velocity = estimator.update(delta, dt);
+        self.prev = Snapshot {
+            dt,
+            instant: now,
+            total_angle: self.total_angle(),
+        };
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.prev
+    }
+
+    /// Current angle in rad
+    pub fn angle(&self) -> f32 {
+        self.angle
+    }
+
+    /// Total angle in rad
+    ///
+    /// Loses resolution as the turns add up, f32 has about 1e-3 rad left
+    /// after 1000 turns. See [`SensorState::total_angle_f64`] for long runs.
+    pub fn total_angle(&self) -> f32 {
+        self.full_rotations as f32 * TWO_PI + self.angle
+    }
+
+    /// Total angle in rad, at full resolution for any number of turns
+    pub fn total_angle_f64(&self) -> f64 {
+        self.full_rotations as f64 * core::f64::consts::TAU + self.angle as f64
+    }
+
+    /// Signed distance in rad from the total angle to `target`, without the
+    /// rounding of [`SensorState::total_angle`]
+    pub fn distance_to(&self, target: f32) -> f32 {
+        (target as f64 - self.total_angle_f64()) as f32
+    }
+
+    /// Full rotations counter
+    pub fn full_rotations(&self) -> i64 {
+        self.full_rotations
+    }
+
+    /// Angular velocity in rad/s
+    pub fn velocity(&self) -> Velocity {
+        self.velocity
+    }
+
+    /// Duration between last 2 records
+    pub fn last_dt(&self) -> Duration {
+        self.prev.dt
+    }
+
+    /// Whether the angle is absolute, see [`SensorHardware::is_referenced`]
+    ///
+    /// Once the sensor finds its reference, the angle jumps there and the
+    /// full rotations start over.
+    pub fn is_referenced(&self) -> bool {
+        self.referenced
+    }
+
+    /// Readings rejected as glitches since the last reset, see
+    /// [`GlitchPolicy`]
+    pub fn glitches(&self) -> u32 {
+        self.glitches
+    }
+}
+
+/// What [`Sensor`] does with a reading far from where the rotor should be
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GlitchPolicy {
+    /// Take every reading as it is
+    #[default]
+    Accept,
+
+    /// Drop readings more than `max_jump` rad away from the extrapolated
+    /// angle, up to `max_rejected` in a row after which the rotor is taken
+    /// to have really moved there
+    Reject { max_jump: f32, max_rejected: u8 },
+}
+
+impl Display for SensorState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:.2} ({}, total {:.2}) @ {}",
+            self.angle,
+            self.full_rotations,
+            self.total_angle(),
+            self.velocity,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Snapshot {
+    instant: Instant,
+    dt: Duration,
+    total_angle: f32,
+}
+
+impl Snapshot {
+    pub fn dt_secs(&self) -> f32 {
+        self.dt.to_micros() as f32 * 1e-6
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{cell::Cell, convert::Infallible};
+    use std::{collections::VecDeque, vec::Vec};
+
+    use embedded_hal::spi::{self, Operation, SpiDevice};
+
+    use super::*;
+
+    /// SPI device answering each read or transfer with the next queued
+    /// response, and keeping what was written
+    pub(crate) struct MockSpi {
+        pub(crate) responses: VecDeque<Vec<u8>>,
+        pub(crate) written: Vec<Vec<u8>>,
+    }
+
+    impl MockSpi {
+        pub(crate) fn new(responses: impl IntoIterator<Item = Vec<u8>>) -> Self {
+            Self {
+                responses: responses.into_iter().collect(),
+                written: Vec::new(),
+            }
+        }
+
+        fn respond(&mut self, buf: &mut [u8]) {
+            let response = self.responses.pop_front().expect("No response queued");
+            buf.copy_from_slice(&response);
+        }
+    }
+
+    impl spi::ErrorType for MockSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for MockSpi {
+        fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+            for operation in operations {
+                match operation {
+                    Operation::Read(buf) => self.respond(buf),
+                    Operation::Write(bytes) => self.written.push(bytes.to_vec()),
+                    Operation::Transfer(read, write) => {
+                        self.written.push(write.to_vec());
+                        self.respond(read);
+                    }
+                    Operation::TransferInPlace(buf) => {
+                        self.written.push(buf.to_vec());
+                        self.respond(buf);
+                    }
+                    Operation::DelayNs(_) => {}
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Time moved by hand
+    #[derive(Default)]
+    struct FakeClock(Cell<u64>);
+
+    impl FakeClock {
+        fn advance(&self, micros: u64) {
+            self.0.set(self.0.get() + micros);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            Instant::from_ticks(self.0.get())
+        }
+    }
+
+    /// Reads back whatever angle was set last, wrapped like a real sensor
+    #[derive(Default)]
+    struct FakeHardware(f64);
+
+    impl SensorHardware for FakeHardware {
+        type Error = Infallible;
+
+        fn read_angle(&mut self) -> Result<f32, Infallible> {
+            Ok(self.0.rem_euclid(core::f64::consts::TAU) as f32)
+        }
+    }
+
+    /// Has a resolution, but no raw readings of its own
+    struct Counted(f32);
+
+    impl SensorHardware for Counted {
+        type Error = Infallible;
+
+        fn read_angle(&mut self) -> Result<f32, Infallible> {
+            Ok(self.0)
+        }
+
+        fn counts_per_revolution(&self) -> Option<u32> {
+            Some(4096)
+        }
+    }
+
+    fn read(sensor: &mut Sensor<FakeHardware, &FakeClock>, angle: f64) {
+        sensor.hardware_mut().0 = angle;
+        sensor.update().unwrap();
+    }
+
+    #[test]
+    fn defaults() {
+        let mut hardware = FakeHardware(TWO_PI as f64 / 4.);
+        assert_eq!(hardware.counts_per_revolution(), None);
+        assert_eq!(hardware.read_raw(), Ok(16384));
+        assert_eq!(hardware.magnet_status(), Ok(MagnetStatus::Unknown));
+        assert!(MagnetStatus::Unknown.is_usable());
+        assert_eq!(hardware.agc(), Ok(None));
+        assert_eq!(hardware.magnitude(), Ok(None));
+        assert!(hardware.is_referenced());
+        assert_eq!(hardware.pole_pairs(), None);
+
+        // Scaled to the resolution, wrapping below a full turn
+        assert_eq!(Counted(TWO_PI / 2.).read_raw(), Ok(2048));
+        assert_eq!(Counted(TWO_PI - 1e-3).read_raw(), Ok(4095));
+        assert_eq!(Counted(TWO_PI).read_raw(), Ok(0));
+    }
+
+    #[test]
+    fn dt() {
+        let clock = FakeClock::default();
+        let mut sensor = Sensor::new(FakeHardware::default()).with_clock(&clock);
+
+        read(&mut sensor, 0.);
+        clock.advance(1000);
+        read(&mut sensor, 0.1);
+        assert_eq!(sensor.state().last_dt(), Duration::micros(1000));
+
+        clock.advance(250);
+        read(&mut sensor, 0.2);
+        assert_eq!(sensor.state().last_dt(), Duration::micros(250));
+        assert_eq!(sensor.state().snapshot().dt_secs(), 250e-6);
+        assert!((sensor.state().velocity().as_secs() - 400.).abs() < 1.);
+    }
+
+    #[test]
+    fn first_reading_sets_angle() {
+        let clock = FakeClock::default();
+        let mut sensor = Sensor::new(FakeHardware::default())
+            .with_clock(&clock)
+            .with_glitch_policy(GlitchPolicy::Reject {
+                max_jump: 0.1,
+                max_rejected: 3,
+            });
+
+        // Past π, which used to count as a turn backwards from 0
+        clock.advance(1000);
+        read(&mut sensor, 4.);
+        let state = sensor.state();
+        assert_eq!(state.angle(), 4.);
+        assert_eq!(state.full_rotations(), 0);
+        assert_eq!(state.glitches(), 0);
+        assert_eq!(state.velocity(), Velocity::ZERO);
+
+        sensor.reset();
+        read(&mut sensor, 1.);
+        assert_eq!(sensor.state().total_angle(), 1.);
+        assert_eq!(sensor.state().glitches(), 0);
+    }
+
+    #[test]
+    fn wraps_at_speed() {
+        let clock = FakeClock::default();
+        let mut sensor = Sensor::new(FakeHardware::default()).with_clock(&clock);
+
+        // Up to 5.5 rad between readings, more than half a revolution, with
+        // the velocity changing by 0.1 rad per reading
+        let mut angle = 0.;
+        read(&mut sensor, angle);
+        for step in 1..=55 {
+            angle += step as f64 * 0.1;
+            clock.advance(1000);
+            read(&mut sensor, angle);
+
+            let total = sensor.state().total_angle_f64();
+            assert!((total - angle).abs() < 1e-4, "{total} != {angle}");
+        }
+
+        // And back down
+        for step in (-55..=55).rev() {
+            angle += step as f64 * 0.1;
+            clock.advance(1000);
+            read(&mut sensor, angle);
+        }
+        let total = sensor.state().total_angle_f64();
+        assert!((total - angle).abs() < 1e-4, "{total} != {angle}");
+    }
+
+    #[test]
+    fn counts_turns() {
+        // 2 rad per reading either way, long past where f32 can tell 1e-3 rad
+        // apart
+        for step in [2., -2.] {
+            let clock = FakeClock::default();
+            let mut sensor = Sensor::new(FakeHardware::default()).with_clock(&clock);
+
+            let mut angle = 0.5;
+            read(&mut sensor, angle);
+            for _ in 0..1_000_000 {
+                angle += step;
+                clock.advance(100);
+                read(&mut sensor, angle);
+            }
+
+            let state = sensor.state();
+            let turns = (angle / core::f64::consts::TAU).floor() as i64;
+            assert_eq!(state.full_rotations(), turns);
+            assert!((state.total_angle_f64() - angle).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn rejects_glitches() {
+        let clock = FakeClock::default();
+        let mut sensor = Sensor::new(FakeHardware::default())
+            .with_clock(&clock)
+            .with_glitch_policy(GlitchPolicy::Reject {
+                max_jump: 0.5,
+                max_rejected: 2,
+            });
+
+        read(&mut sensor, 6.2);
+        clock.advance(1000);
+
+        // Across the wrap, no glitch
+        read(&mut sensor, 6.3);
+        assert_eq!(sensor.state().glitches(), 0);
+        assert_eq!(sensor.state().full_rotations(), 1);
+
+        // A single bad reading is dropped
+        clock.advance(1000);
+        read(&mut sensor, 9.);
+        assert_eq!(sensor.state().glitches(), 1);
+        assert!((sensor.state().total_angle_f64() - 6.3).abs() < 1e-5);
+
+        clock.advance(1000);
+        read(&mut sensor, 6.3);
+        assert_eq!(sensor.state().glitches(), 1);
+
+        // A real jump is taken after `max_rejected` readings
+        for _ in 0..2 {
+            clock.advance(1000);
+            read(&mut sensor, 8.);
+        }
+        assert_eq!(sensor.state().glitches(), 3);
+        assert!((sensor.state().total_angle_f64() - 6.3).abs() < 1e-5);
+
+        clock.advance(1000);
+        read(&mut sensor, 8.);
+        assert_eq!(sensor.state().glitches(), 3);
+        assert!((sensor.state().total_angle_f64() - 8.).abs() < 1e-5);
+    }
+}
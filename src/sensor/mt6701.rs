@@ -0,0 +1,175 @@
+use core::f32::consts::PI;
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::sensor::{MagnetStatus, SensorHardware};
+
+/// MT6701 magnetic encoder read over SSI
+///
+/// SSI is read-only and clocked like SPI mode 2, so an SPI bus without MOSI
+/// does. Each frame carries the 14-bit angle, the magnetic field status and a
+/// CRC, which are all checked.
+pub struct Mt6701<S> {
+    spi: S,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mt6701Error<E> {
+    Spi(E),
+
+    /// The frame doesn't match its CRC
+    Crc,
+
+    MagnetTooWeak,
+    MagnetTooStrong,
+
+    /// The rotor turned faster than the sensor could track
+    LossOfTrack,
+}
+
+impl<S> Mt6701<S> {
+    pub fn new(spi: S) -> Self {
+        Self { spi }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.spi
+    }
+}
+
+impl<S: SpiDevice> Mt6701<S> {
+    /// Read a frame, returns the angle in counts and the field status
+    fn read_frame(&mut self) -> Result<(u16, u8), Mt6701Error<S::Error>> {
+        let mut buf = [0; 3];
+        self.spi.read(&mut buf).map_err(Mt6701Error::Spi)?;
+
+        // Angle (14 bits), field status (4 bits) and CRC (6 bits)
+        let frame = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]);
+        let data = frame >> 6;
+        if crc6(data) != (frame & 0x3F) as u8 {
+            return Err(Mt6701Error::Crc);
+        }
+
+        Ok(((data >> 4) as u16, (data & 0xF) as u8))
+    }
+}
+
+impl<S: SpiDevice> SensorHardware for Mt6701<S> {
+    type Error = Mt6701Error<S::Error>;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        let angle = self.read_raw()?;
+        Ok(angle as f32 * 2. * PI / 16384.)
+    }
+
+    fn counts_per_revolution(&self) -> Option<u32> {
+        Some(16384)
+    }
+
+    fn read_raw(&mut self) -> Result<u32, Self::Error> {
+        let (angle, status) = self.read_frame()?;
+
+        match status & 0b11 {
+            0b01 => return Err(Mt6701Error::MagnetTooStrong),
+            0b10 => return Err(Mt6701Error::MagnetTooWeak),
+            _ => {}
+        }
+        if status & 0b1000 != 0 {
+            return Err(Mt6701Error::LossOfTrack);
+        }
+
+        Ok(angle as u32)
+    }
+
+    fn magnet_status(&mut self) -> Result<MagnetStatus, Self::Error> {
+        let (_, status) = self.read_frame()?;
+
+        Ok(match status & 0b11 {
+            0b01 => MagnetStatus::TooStrong,
+            0b10 => MagnetStatus::TooWeak,
+            _ => MagnetStatus::Ok,
+        })
+    }
+}
+
+/// CRC-6 (x⁶ + x + 1) of the 18 data bits
+fn crc6(data: u32) -> u8 {
+    let mut crc = 0u8;
+    for bit in (0..18).rev() {
+        let feedback = ((data >> bit) as u8 ^ crc >> 5) & 1;
+        crc = (crc << 1) & 0x3F;
+        if feedback != 0 {
+            crc ^= 0x03;
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sensor::tests::MockSpi;
+
+    /// CRC-6 as the remainder of the polynomial long division
+    fn reference_crc6(data: u32) -> u8 {
+        let mut remainder = data << 6;
+        for bit in (6..24).rev() {
+            if remainder & 1 << bit != 0 {
+                remainder ^= 0b100_0011 << (bit - 6);
+            }
+        }
+        remainder as u8
+    }
+
+    /// SSI frame of `angle` and `status`, with its CRC
+    fn frame(angle: u16, status: u8) -> Vec<u8> {
+        let data = (angle as u32) << 4 | status as u32;
+        ((data << 6) | crc6(data) as u32).to_be_bytes()[1..].to_vec()
+    }
+
+    fn sensor(frames: impl IntoIterator<Item = Vec<u8>>) -> Mt6701<MockSpi> {
+        Mt6701::new(MockSpi::new(frames))
+    }
+
+    #[test]
+    fn crc() {
+        for data in [0, 1, 0x2_0000, 0x3_FFFF, 0x1_2345, 0x2_AAAA] {
+            assert_eq!(crc6(data), reference_crc6(data), "{data:#x}");
+        }
+    }
+
+    #[test]
+    fn read_angle() {
+        let mut sensor = sensor([frame(0x2000, 0), frame(0x3FFF, 0b0100)]);
+        assert_eq!(sensor.read_angle(), Ok(PI));
+        assert_eq!(sensor.read_raw(), Ok(0x3FFF));
+    }
+
+    #[test]
+    fn bad_crc() {
+        for bit in 0..24 {
+            let mut bytes = frame(0x1234, 0);
+            bytes[2 - bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(sensor([bytes]).read_angle(), Err(Mt6701Error::Crc));
+        }
+    }
+
+    #[test]
+    fn status() {
+        for (status, error, magnet) in [
+            (
+                0b0001,
+                Mt6701Error::MagnetTooStrong,
+                MagnetStatus::TooStrong,
+            ),
+            (0b0010, Mt6701Error::MagnetTooWeak, MagnetStatus::TooWeak),
+            (0b1000, Mt6701Error::LossOfTrack, MagnetStatus::Ok),
+        ] {
+            let mut sensor = sensor([frame(0x1234, status), frame(0x1234, status)]);
+            assert_eq!(sensor.read_angle(), Err(error));
+            assert_eq!(sensor.magnet_status(), Ok(magnet));
+        }
+    }
+}
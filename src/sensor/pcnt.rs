@@ -0,0 +1,52 @@
+use esp_hal::{
+    gpio::interconnect::InputSignal,
+    pcnt::{
+        channel::{CtrlMode, EdgeMode},
+        unit::{Counter, Unit},
+    },
+};
+
+use crate::sensor::PulseCounter;
+
+impl<const NUM: usize> PulseCounter for Counter<'_, NUM> {
+    fn count(&mut self) -> i16 {
+        self.get()
+    }
+}
+
+/// Set up `unit` to count every edge of the `a` and `b` channels of an
+/// incremental encoder, up when A leads
+///
+/// The counter goes back to 0 at `±limit`, pass the same limit to
+/// [`QuadratureEncoder::with_counter_limit`]. Noisy lines may also need a
+/// glitch filter, see [`Unit::set_filter`].
+///
+/// [`QuadratureEncoder::with_counter_limit`]: crate::sensor::QuadratureEncoder::with_counter_limit
+pub fn quadrature_counter<'d, const NUM: usize>(
+    unit: &Unit<'d, NUM>,
+    a: InputSignal,
+    b: InputSignal,
+    limit: i16,
+) -> Counter<'d, NUM> {
+    unit.set_low_limit(Some(-limit)).unwrap();
+    unit.set_high_limit(Some(limit)).unwrap();
+    unit.clear();
+
+    // Edges of B counted with A as the direction, and the other way around
+    unit.channel0.set_ctrl_signal(a.clone());
+    unit.channel0.set_edge_signal(b.clone());
+    unit.channel0
+        .set_ctrl_mode(CtrlMode::Reverse, CtrlMode::Keep);
+    unit.channel0
+        .set_input_mode(EdgeMode::Increment, EdgeMode::Decrement);
+
+    unit.channel1.set_ctrl_signal(b);
+    unit.channel1.set_edge_signal(a);
+    unit.channel1
+        .set_ctrl_mode(CtrlMode::Reverse, CtrlMode::Keep);
+    unit.channel1
+        .set_input_mode(EdgeMode::Decrement, EdgeMode::Increment);
+
+    unit.resume();
+    unit.counter.clone()
+}
@@ -0,0 +1,297 @@
+use core::{cell::Cell, convert::Infallible, f32::consts::PI};
+
+use critical_section::Mutex;
+
+use crate::sensor::SensorHardware;
+
+/// Default limit of the hardware counter, see
+/// [`QuadratureEncoder::with_counter_limit`]
+const DEFAULT_COUNTER_LIMIT: i16 = 30000;
+
+/// Hardware counting the edges of an incremental encoder
+pub trait PulseCounter {
+    /// Raw value of the counter
+    fn count(&mut self) -> i16;
+}
+
+/// ABZ incremental encoder
+///
+/// The hardware counter is only 16 bit and wraps around at its limit, so it
+/// has to be read at least once every half of that many counts. Edges are
+/// accumulated here into a count that doesn't overflow.
+///
+/// Until the Z index is seen, angles are counted from wherever the encoder
+/// was when powered on, see [`SensorHardware::is_referenced`]. Each index
+/// pulse resets the angle to 0, which also makes up for lost counts. It is
+/// caught by an interrupt on the Z pin, see [`IndexLatch`].
+pub struct QuadratureEncoder<C> {
+    counter: C,
+    counts_per_revolution: u32,
+    limit: i16,
+    count: i64,
+    last: Option<i16>,
+
+    /// Count at the index pulse
+    reference: Option<i64>,
+
+    index: Option<&'static IndexLatch>,
+}
+
+impl<C> QuadratureEncoder<C> {
+    /// `counts_per_revolution` counts all edges of both channels, four times
+    /// the lines of the encoder
+    pub fn new(counter: C, counts_per_revolution: u32) -> Self {
+        assert!(counts_per_revolution > 0);
+
+        Self {
+            counter,
+            counts_per_revolution,
+            limit: DEFAULT_COUNTER_LIMIT,
+            count: 0,
+            last: None,
+            reference: None,
+            index: None,
+        }
+    }
+
+    /// The hardware counter goes back to 0 when it reaches `±limit`
+    pub fn with_counter_limit(mut self, limit: i16) -> Self {
+        assert!(limit > 0);
+        self.limit = limit;
+        self
+    }
+
+    /// Reference the angle to the index pulses latched by `index`
+    pub fn with_index(mut self, index: &'static IndexLatch) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn counts_per_revolution(&self) -> u32 {
+        self.counts_per_revolution
+    }
+
+    /// Counts since power on
+    pub fn count(&self) -> i64 {
+        self.count
+    }
+
+    pub fn counter(&self) -> &C {
+        &self.counter
+    }
+
+    pub fn counter_mut(&mut self) -> &mut C {
+        &mut self.counter
+    }
+
+    /// Forget the index, the angle is relative again until the next pulse
+    pub fn clear_reference(&mut self) {
+        self.reference = None;
+    }
+
+    /// Counts from `from` to `to` (raw counter values), the shorter way
+    /// around the counter's range
+    fn delta(&self, from: i16, to: i16) -> i64 {
+        let limit = self.limit as i64;
+        let delta = (to as i64 - from as i64).rem_euclid(limit);
+        if delta > limit / 2 {
+            delta - limit
+        } else {
+            delta
+        }
+    }
+}
+
+impl<C: PulseCounter> QuadratureEncoder<C> {
+    /// Read the counter and accumulate the edges since the last read
+    pub fn update(&mut self) {
+        let raw = self.counter.count();
+        if let Some(last) = self.last {
+            self.count += self.delta(last, raw);
+        }
+        self.last = Some(raw);
+
+        if let Some(index) = self.index.and_then(IndexLatch::take) {
+            self.reference = Some(self.count - self.delta(index, raw));
+        }
+    }
+}
+
+impl<C: PulseCounter> SensorHardware for QuadratureEncoder<C> {
+    type Error = Infallible;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        let counts = self.read_raw()?;
+        Ok(counts as f32 * 2. * PI / self.counts_per_revolution as f32)
+    }
+
+    fn counts_per_revolution(&self) -> Option<u32> {
+        Some(self.counts_per_revolution)
+    }
+
+    /// Counts since the index, or since power on until it is found
+    fn read_raw(&mut self) -> Result<u32, Self::Error> {
+        self.update();
+
+        let counts = (self.count - self.reference.unwrap_or_default())
+            .rem_euclid(self.counts_per_revolution as i64);
+        Ok(counts as u32)
+    }
+
+    fn is_referenced(&self) -> bool {
+        self.reference.is_some()
+    }
+}
+
+/// Counter value at the last index pulse, set from the Z pin's interrupt
+///
+/// Keep it in a `static` and [`IndexLatch::latch`] the counter on the rising
+/// edge of Z:
+///
+/// ```ignore
+/// static INDEX: IndexLatch = IndexLatch::new();
+///
+/// #[handler]
+/// fn on_index() {
+///     INDEX.latch(counter.get());
+///     /* clear the pin's interrupt */
+/// }
+///
+/// let encoder = QuadratureEncoder::new(counter, 4096).with_index(&INDEX);
+/// ```
+pub struct IndexLatch(Mutex<Cell<Option<i16>>>);
+
+impl IndexLatch {
+    pub const fn new() -> Self {
+        Self(Mutex::new(Cell::new(None)))
+    }
+
+    pub fn latch(&self, count: i16) {
+        critical_section::with(|cs| self.0.borrow(cs).set(Some(count)));
+    }
+
+    /// Last latched count, if any since the last call
+    pub fn take(&self) -> Option<i16> {
+        critical_section::with(|cs| self.0.borrow(cs).take())
+    }
+}
+
+impl Default for IndexLatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+
+    use super::*;
+
+    const LIMIT: i16 = 1000;
+    const COUNTS: u32 = 400;
+
+    /// Counter going back to 0 when it reaches either limit, like the PCNT
+    #[derive(Default)]
+    struct FakeCounter {
+        position: i64,
+        raw: i16,
+    }
+
+    impl FakeCounter {
+        fn move_to(&mut self, position: i64) {
+            while self.position != position {
+                let step = (position - self.position).signum();
+                self.position += step;
+                self.raw += step as i16;
+                if self.raw.abs() == LIMIT {
+                    self.raw = 0;
+                }
+            }
+        }
+    }
+
+    impl PulseCounter for FakeCounter {
+        fn count(&mut self) -> i16 {
+            self.raw
+        }
+    }
+
+    fn encoder() -> QuadratureEncoder<FakeCounter> {
+        let mut encoder =
+            QuadratureEncoder::new(FakeCounter::default(), COUNTS).with_counter_limit(LIMIT);
+        encoder.update();
+        encoder
+    }
+
+    fn move_to(encoder: &mut QuadratureEncoder<FakeCounter>, position: i64) -> u32 {
+        encoder.counter_mut().move_to(position);
+        encoder.read_raw().unwrap()
+    }
+
+    #[test]
+    fn wraps() {
+        let mut encoder = encoder();
+
+        // Several revolutions and counter wraps each way, in steps short of
+        // half the limit
+        let mut position = 0;
+        for step in [37, -41] {
+            for _ in 0..500 {
+                position += step;
+                let raw = move_to(&mut encoder, position);
+                assert_eq!(encoder.count(), position);
+                assert_eq!(raw as i64, position.rem_euclid(COUNTS as i64));
+            }
+        }
+        assert_eq!(position, -2000);
+    }
+
+    #[test]
+    fn across_limit() {
+        let mut encoder = encoder();
+
+        // Back and forth right at the limit, where the counter reads the
+        // same position as 10 or -990 depending on how it got there
+        for position in [
+            400, 800, 990, 1010, 990, 1010, 600, 200, -10, 10, -400, -800, -990, -1010, -990,
+        ] {
+            move_to(&mut encoder, position);
+            assert_eq!(encoder.count(), position);
+        }
+    }
+
+    #[test]
+    fn index() {
+        let index = Box::leak(Box::new(IndexLatch::new()));
+        let mut encoder = encoder().with_index(index);
+
+        assert_eq!(move_to(&mut encoder, 150), 150);
+        assert!(!encoder.is_referenced());
+
+        // Latched just before the counter wrapped, read just after
+        move_to(&mut encoder, 600);
+        move_to(&mut encoder, 990);
+        index.latch(encoder.counter().raw);
+        assert_eq!(move_to(&mut encoder, 1010), 20);
+        assert!(encoder.is_referenced());
+        assert_eq!(encoder.read_angle(), Ok(20. * 2. * PI / COUNTS as f32));
+
+        // Counted from the index from then on
+        for revolution in 1..=3 {
+            assert_eq!(move_to(&mut encoder, 990 + revolution * 400 + 7), 7);
+        }
+        for revolution in (-1..=2).rev() {
+            assert_eq!(move_to(&mut encoder, 990 + revolution * 400 - 7), 393);
+        }
+
+        // Another pulse makes up for lost counts
+        move_to(&mut encoder, 200);
+        index.latch(-10);
+        assert_eq!(move_to(&mut encoder, 0), 10);
+
+        encoder.clear_reference();
+        assert!(!encoder.is_referenced());
+        assert_eq!(move_to(&mut encoder, 50), 50);
+    }
+}
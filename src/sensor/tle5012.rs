@@ -0,0 +1,241 @@
+use core::f32::consts::PI;
+
+use embedded_hal::spi::{Operation, SpiDevice};
+
+use crate::sensor::{MagnetStatus, SensorHardware};
+
+/// Reading one word, register address and length still to be added
+const READ: u16 = 0x8000;
+
+const STAT: u16 = 0x00;
+const AVAL: u16 = 0x02;
+
+/// Bits of the safety word, active low
+const SAFETY_SYSTEM: u16 = 1 << 14;
+const SAFETY_INTERFACE: u16 = 1 << 13;
+const SAFETY_ANGLE: u16 = 1 << 12;
+
+/// Bits of the status register
+const STAT_OVERFLOW: u16 = 1 << 5;
+const STAT_MAGNITUDE: u16 = 1 << 7;
+
+/// TLE5012B magnetic encoder over SSC
+///
+/// SSC is SPI (mode 1) with a single data line, MOSI tied to the sensor's
+/// data pin and MISO through a resistor. Every read is checked against the
+/// safety word's CRC and status bits.
+pub struct Tle5012<S> {
+    spi: S,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tle5012Error<E> {
+    Spi(E),
+
+    /// The response doesn't match the safety word's CRC
+    Crc,
+
+    /// The magnitude of the field is out of range, check the magnet
+    Magnet,
+
+    /// Internal overflow, the angle is invalid
+    Overflow,
+
+    /// Another system error, with the status register
+    System(u16),
+
+    /// The sensor didn't accept the command
+    Interface,
+
+    /// The angle value is invalid
+    InvalidAngle,
+}
+
+impl<S> Tle5012<S> {
+    pub fn new(spi: S) -> Self {
+        Self { spi }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.spi
+    }
+}
+
+impl<S: SpiDevice> Tle5012<S> {
+    /// Read the register at `address`, checking the safety word
+    pub fn read_register(&mut self, address: u16) -> Result<u16, Tle5012Error<S::Error>> {
+        let command = READ | address << 4 | 1;
+        let mut response = [0; 4];
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&command.to_be_bytes()),
+                Operation::Read(&mut response),
+            ])
+            .map_err(Tle5012Error::Spi)?;
+
+        let value = u16::from_be_bytes([response[0], response[1]]);
+        let safety = u16::from_be_bytes([response[2], response[3]]);
+
+        let [command_high, command_low] = command.to_be_bytes();
+        if crc8(&[command_high, command_low, response[0], response[1]]) != safety as u8 {
+            return Err(Tle5012Error::Crc);
+        }
+
+        if safety & SAFETY_INTERFACE == 0 {
+            return Err(Tle5012Error::Interface);
+        }
+        if safety & SAFETY_SYSTEM == 0 && address != STAT {
+            let status = self.read_register(STAT)?;
+            return Err(if status & STAT_MAGNITUDE != 0 {
+                Tle5012Error::Magnet
+            } else if status & STAT_OVERFLOW != 0 {
+                Tle5012Error::Overflow
+            } else {
+                Tle5012Error::System(status)
+            });
+        }
+        if safety & SAFETY_ANGLE == 0 && address == AVAL {
+            return Err(Tle5012Error::InvalidAngle);
+        }
+
+        Ok(value)
+    }
+}
+
+impl<S: SpiDevice> SensorHardware for Tle5012<S> {
+    type Error = Tle5012Error<S::Error>;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        Ok(self.read_raw()? as f32 * PI / 16384.)
+    }
+
+    fn counts_per_revolution(&self) -> Option<u32> {
+        Some(32768)
+    }
+
+    fn read_raw(&mut self) -> Result<u32, Self::Error> {
+        // 15-bit two's complement, -180° to 180°
+        let value = self.read_register(AVAL)?;
+        Ok(((value << 1) as i16 >> 1).rem_euclid(1 << 15) as u32)
+    }
+
+    fn magnet_status(&mut self) -> Result<MagnetStatus, Self::Error> {
+        Ok(match self.read_register(STAT)? & STAT_MAGNITUDE {
+            0 => MagnetStatus::Ok,
+            _ => MagnetStatus::OutOfRange,
+        })
+    }
+}
+
+/// CRC-8 (SAE J1850) of the command and data words
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x1D
+            } else {
+                crc << 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::*;
+    use crate::sensor::tests::MockSpi;
+
+    const SAFETY_OK: u16 = SAFETY_SYSTEM | SAFETY_INTERFACE | SAFETY_ANGLE;
+
+    /// Response to reading `address`, with the safety bits and their CRC
+    fn response(address: u16, value: u16, safety: u16) -> Vec<u8> {
+        let [command_high, command_low] = (READ | address << 4 | 1).to_be_bytes();
+        let [value_high, value_low] = value.to_be_bytes();
+        let crc = crc8(&[command_high, command_low, value_high, value_low]);
+        [value.to_be_bytes(), (safety | crc as u16).to_be_bytes()].concat()
+    }
+
+    fn sensor(responses: impl IntoIterator<Item = Vec<u8>>) -> Tle5012<MockSpi> {
+        Tle5012::new(MockSpi::new(responses))
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc8(b"123456789"), 0x4B);
+    }
+
+    #[test]
+    fn read_angle() {
+        let mut sensor = sensor([response(AVAL, 0x2000, SAFETY_OK)]);
+        assert_eq!(sensor.read_angle(), Ok(PI / 2.));
+        assert_eq!(sensor.into_inner().written, [vec![0x80, 0x21]]);
+    }
+
+    #[test]
+    fn sign_extension() {
+        for (value, raw) in [
+            (0x0000, 0),
+            (0x3FFF, 16383),
+            (0x4000, 16384),
+            (0x7FFF, 32767),
+            (0x8001, 1),
+        ] {
+            let mut sensor = sensor([response(AVAL, value, SAFETY_OK)]);
+            assert_eq!(sensor.read_raw(), Ok(raw), "{value:#x}");
+        }
+    }
+
+    #[test]
+    fn bad_crc() {
+        // The CRC covers the value, not the safety bits next to it
+        for (byte, bit) in [0, 1, 3]
+            .into_iter()
+            .flat_map(|byte| (0..8).map(move |bit| (byte, bit)))
+        {
+            let mut bytes = response(AVAL, 0x1234, SAFETY_OK);
+            bytes[byte] ^= 1 << bit;
+            assert_eq!(sensor([bytes]).read_angle(), Err(Tle5012Error::Crc));
+        }
+    }
+
+    #[test]
+    fn safety() {
+        let bytes = response(AVAL, 0, SAFETY_OK & !SAFETY_INTERFACE);
+        assert_eq!(sensor([bytes]).read_angle(), Err(Tle5012Error::Interface));
+
+        let bytes = response(AVAL, 0, SAFETY_OK & !SAFETY_ANGLE);
+        assert_eq!(
+            sensor([bytes]).read_angle(),
+            Err(Tle5012Error::InvalidAngle)
+        );
+
+        // A system error is looked up in the status register
+        for (status, error) in [
+            (STAT_MAGNITUDE, Tle5012Error::Magnet),
+            (STAT_OVERFLOW, Tle5012Error::Overflow),
+            (1 << 1, Tle5012Error::System(1 << 1)),
+        ] {
+            let mut failing = sensor([
+                response(AVAL, 0, SAFETY_OK & !SAFETY_SYSTEM),
+                response(STAT, status, SAFETY_OK & !SAFETY_SYSTEM),
+            ]);
+            assert_eq!(failing.read_angle(), Err(error));
+            assert_eq!(failing.into_inner().written[1], [0x80, 0x01]);
+        }
+    }
+
+    #[test]
+    fn magnet_status() {
+        let mut sensor = sensor([
+            response(STAT, 0, SAFETY_OK),
+            response(STAT, STAT_MAGNITUDE, SAFETY_OK & !SAFETY_SYSTEM),
+        ]);
+        assert_eq!(sensor.magnet_status(), Ok(MagnetStatus::Ok));
+        assert_eq!(sensor.magnet_status(), Ok(MagnetStatus::OutOfRange));
+    }
+}
@@ -0,0 +1,686 @@
+//! Simulated BLDC motor for running the control loops without hardware
+//!
+//! [`Simulator`] models a surface mounted PMSM in the rotor (d-q) frame,
+//! together with the rotor's inertia, friction and cogging. Handles borrowed
+//! from it plug into the rest of the crate: [`SimPhase`] drives the three
+//! phases of a [`ThreePhasePwm`], [`SimEncoder`] reads the rotor angle like a
+//! magnetic encoder, [`SimHallPin`]s like hall sensors, [`SimCurrentSensor`]
+//! reads the phase currents and [`SimClock`] keeps the virtual time, advancing
+//! the model whenever it is asked to delay.
+
+use core::{cell::RefCell, convert::Infallible, f32::consts::FRAC_1_SQRT_3, f64::consts::TAU};
+
+use embedded_hal::{
+    delay::DelayNs,
+    digital::{self, InputPin},
+    pwm::{ErrorType, SetDutyCycle},
+};
+use num_traits::Float;
+
+use crate::{
+    motor::{CurrentSensor, DQ, PhaseCurrents, ThreePhasePwm, flux_linkage},
+    sensor::{HALL_SEQUENCE, MagnetStatus, SensorHardware},
+    util::{Clock, Duration, Instant},
+};
+
+/// Duty cycle resolution of the simulated PWM channels, like an MCPWM timer
+/// with a period of 800 ticks
+const MAX_DUTY: u16 = 800;
+
+/// A simulated motor and its virtual clock
+pub struct Simulator {
+    params: SimParams,
+    state: RefCell<SimState>,
+}
+
+/// Physical parameters of the simulated motor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimParams {
+    pub pole_pairs: u8,
+
+    /// Phase resistance in Ω
+    pub phase_resistance: f32,
+
+    /// Phase inductance in H
+    pub phase_inductance: f32,
+
+    /// Velocity constant in RPM/V
+    pub kv: f32,
+
+    /// Rotor inertia in kg·m²
+    pub inertia: f32,
+
+    /// Viscous friction in N·m·s/rad
+    pub viscous_friction: f32,
+
+    /// Coulomb (dry) friction in N·m
+    pub coulomb_friction: f32,
+
+    /// Amplitude of the cogging torque in N·m, sinusoidal over the angle
+    pub cogging_torque: f32,
+
+    /// Cogging periods per revolution, the least common multiple of the slot
+    /// and pole counts
+    pub cogging_periods: u16,
+
+    /// Bus voltage in V
+    pub voltage_power_supply: f32,
+
+    /// Integration step in μs
+    pub step: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct SimState {
+    now: u64,
+    duty: [u16; 3],
+    current: DQ,
+    velocity: f32,
+    angle: f64,
+    load_torque: f32,
+}
+
+impl Default for SimParams {
+    /// A typical 2204 gimbal motor on a 12V supply
+    fn default() -> Self {
+        Self {
+            pole_pairs: 7,
+            phase_resistance: 2.3,
+            phase_inductance: 0.86e-3,
+            kv: 220.,
+            inertia: 1e-5,
+            viscous_friction: 1e-5,
+            coulomb_friction: 1e-3,
+            cogging_torque: 0.,
+            cogging_periods: 84,
+            voltage_power_supply: 12.,
+            step: 5,
+        }
+    }
+}
+
+impl SimParams {
+    /// Permanent magnet flux linkage in Wb, derived from the Kv
+    pub fn flux_linkage(&self) -> f32 {
+        flux_linkage(self.pole_pairs, self.kv)
+    }
+
+    /// Torque constant in N·m/A of Iq
+    pub fn torque_constant(&self) -> f32 {
+        1.5 * self.pole_pairs as f32 * self.flux_linkage()
+    }
+}
+
+impl Simulator {
+    pub fn new(params: SimParams) -> Self {
+        Self {
+            params,
+            state: RefCell::new(SimState::default()),
+        }
+    }
+
+    pub fn params(&self) -> &SimParams {
+        &self.params
+    }
+
+    /// PWM channels driving the simulated phases
+    pub fn pwm(&self) -> ThreePhasePwm<SimPhase<'_>, SimPhase<'_>, SimPhase<'_>> {
+        ThreePhasePwm {
+            a: SimPhase {
+                sim: self,
+                phase: 0,
+            },
+            b: SimPhase {
+                sim: self,
+                phase: 1,
+            },
+            c: SimPhase {
+                sim: self,
+                phase: 2,
+            },
+        }
+    }
+
+    /// Ideal encoder on the rotor shaft
+    pub fn encoder(&self) -> SimEncoder<'_> {
+        SimEncoder {
+            sim: self,
+            counts: None,
+        }
+    }
+
+    /// Outputs of three hall sensors (A, B, C), switching every 60°
+    /// electrical with sector 0 starting at electrical angle 0
+    pub fn hall_pins(&self) -> [SimHallPin<'_>; 3] {
+        [0, 1, 2].map(|bit| SimHallPin { sim: self, bit })
+    }
+
+    pub fn current_sensor(&self) -> SimCurrentSensor<'_> {
+        SimCurrentSensor { sim: self }
+    }
+
+    pub fn clock(&self) -> SimClock<'_> {
+        SimClock { sim: self }
+    }
+
+    /// Virtual time since the simulation started
+    pub fn now(&self) -> Instant {
+        Instant::from_ticks(self.state.borrow().now)
+    }
+
+    /// Mechanical angle of the rotor in rad, not wrapped
+    pub fn angle(&self) -> f32 {
+        self.state.borrow().angle as f32
+    }
+
+    /// Mechanical velocity of the rotor in rad/s
+    pub fn velocity(&self) -> f32 {
+        self.state.borrow().velocity
+    }
+
+    /// Current in the rotor frame in A
+    pub fn current(&self) -> DQ {
+        self.state.borrow().current
+    }
+
+    /// Electromagnetic torque produced by the motor in N·m
+    pub fn torque(&self) -> f32 {
+        self.params.torque_constant() * self.state.borrow().current.q
+    }
+
+    /// External torque opposing the rotor in N·m
+    pub fn set_load_torque(&self, torque: f32) {
+        self.state.borrow_mut().load_torque = torque;
+    }
+
+    /// Move the rotor without going through the dynamics, e.g. to start from
+    /// a known position
+    pub fn set_angle(&self, angle: f32) {
+        self.state.borrow_mut().angle = angle as f64;
+    }
+
+    /// Run the model for `duration` with the phase voltages last set
+    pub fn advance(&self, duration: Duration) {
+        let mut remaining = duration.ticks();
+        let mut state = self.state.borrow_mut();
+
+        while remaining > 0 {
+            let step = remaining.min(self.params.step.max(1) as u64);
+            self.integrate(&mut state, step as f32 * 1e-6);
+            state.now += step;
+            remaining -= step;
+        }
+    }
+
+    fn integrate(&self, state: &mut SimState, h: f32) {
+        let SimParams {
+            pole_pairs,
+            phase_resistance: r,
+            phase_inductance: l,
+            inertia,
+            viscous_friction,
+            coulomb_friction,
+            voltage_power_supply,
+            ..
+        } = self.params;
+
+        let electrical_angle = wrap(state.angle * pole_pairs as f64) as f32;
+        let we = state.velocity * pole_pairs as f32;
+        let flux = self.params.flux_linkage();
+
+        // Star connected windings, the neutral point floats so only the
+        // differential part of the phase voltages matters
+        let [va, vb, vc] = state
+            .duty
+            .map(|duty| duty as f32 / MAX_DUTY as f32 * voltage_power_supply);
+        let alpha = (2. * va - vb - vc) / 3.;
+        let beta = (vb - vc) * FRAC_1_SQRT_3;
+        let (sin, cos) = Float::sin_cos(electrical_angle);
+        let vd = alpha * cos + beta * sin;
+        let vq = beta * cos - alpha * sin;
+
+        // Resistive drop is integrated implicitly to stay stable with small L/R
+        let DQ { d, q } = state.current;
+        let d = (d + h / l * (vd + we * l * q)) / (1. + h * r / l);
+        let q = (q + h / l * (vq - we * l * d - we * flux)) / (1. + h * r / l);
+        state.current = DQ { d, q };
+
+        let cogging = self.params.cogging_torque
+            * Float::sin(state.angle * self.params.cogging_periods as f64) as f32;
+        let torque = self.params.torque_constant() * q - state.load_torque - cogging;
+        let velocity = state.velocity;
+        let resting = velocity.abs() < 1e-3;
+
+        // Stiction: dry friction holds the rotor until the torque overcomes it
+        let velocity = if resting && torque.abs() <= coulomb_friction {
+            0.
+        } else {
+            let direction = if resting { torque } else { velocity }.signum();
+            let friction = viscous_friction * velocity + coulomb_friction * direction;
+            velocity + h * (torque - friction) / inertia
+        };
+
+        state.velocity = velocity;
+        state.angle += velocity as f64 * h as f64;
+    }
+}
+
+/// One phase of the simulated inverter
+pub struct SimPhase<'a> {
+    sim: &'a Simulator,
+    phase: usize,
+}
+
+impl ErrorType for SimPhase<'_> {
+    type Error = Infallible;
+}
+
+impl SetDutyCycle for SimPhase<'_> {
+    fn max_duty_cycle(&self) -> u16 {
+        MAX_DUTY
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        self.sim.state.borrow_mut().duty[self.phase] = duty.min(MAX_DUTY);
+        Ok(())
+    }
+}
+
+/// Angle sensor on the simulated rotor
+pub struct SimEncoder<'a> {
+    sim: &'a Simulator,
+    counts: Option<u32>,
+}
+
+impl SimEncoder<'_> {
+    /// Quantize readings to `counts` per revolution, e.g. 4096 for an AS5600
+    pub fn with_resolution(mut self, counts: u32) -> Self {
+        self.counts = Some(counts);
+        self
+    }
+}
+
+impl SensorHardware for SimEncoder<'_> {
+    type Error = Infallible;
+
+    fn read_angle(&mut self) -> Result<f32, Self::Error> {
+        let angle = wrap(self.sim.state.borrow().angle);
+
+        Ok(match self.counts {
+            Some(counts) => {
+                let step = TAU / counts as f64;
+                (Float::floor(angle / step) * step) as f32
+            }
+            None => angle as f32,
+        })
+    }
+
+    fn counts_per_revolution(&self) -> Option<u32> {
+        self.counts
+    }
+
+    fn read_raw(&mut self) -> Result<u32, Self::Error> {
+        let counts = self.counts.unwrap_or(1 << 16);
+        let angle = wrap(self.sim.state.borrow().angle);
+
+        Ok((angle / TAU * counts as f64) as u32 % counts)
+    }
+
+    fn magnet_status(&mut self) -> Result<MagnetStatus, Self::Error> {
+        Ok(MagnetStatus::Ok)
+    }
+}
+
+/// One hall sensor output of the simulated motor
+pub struct SimHallPin<'a> {
+    sim: &'a Simulator,
+    bit: u8,
+}
+
+impl digital::ErrorType for SimHallPin<'_> {
+    type Error = Infallible;
+}
+
+impl InputPin for SimHallPin<'_> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        let electrical_angle =
+            wrap(self.sim.state.borrow().angle * self.sim.params.pole_pairs as f64);
+        let sector = ((electrical_angle / (TAU / 6.)) as usize).min(5);
+
+        Ok(HALL_SEQUENCE[sector] >> self.bit & 1 != 0)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.is_high()?)
+    }
+}
+
+/// Phase current sensor on the simulated motor
+pub struct SimCurrentSensor<'a> {
+    sim: &'a Simulator,
+}
+
+impl CurrentSensor for SimCurrentSensor<'_> {
+    type Error = Infallible;
+
+    fn read_currents(&mut self) -> Result<PhaseCurrents, Self::Error> {
+        let state = self.sim.state.borrow();
+        let electrical_angle = wrap(state.angle * self.sim.params.pole_pairs as f64) as f32;
+
+        Ok(state
+            .current
+            .inverse_park(electrical_angle)
+            .inverse_clarke())
+    }
+}
+
+/// Virtual clock of the simulation
+///
+/// Delaying on it advances the simulated motor by the same amount of time.
+pub struct SimClock<'a> {
+    sim: &'a Simulator,
+}
+
+impl Clock for SimClock<'_> {
+    fn now(&self) -> Instant {
+        self.sim.now()
+    }
+}
+
+impl DelayNs for SimClock<'_> {
+    fn delay_ns(&mut self, ns: u32) {
+        self.sim
+            .advance(Duration::from_ticks(ns.div_ceil(1000) as u64));
+    }
+}
+
+fn wrap(angle: f64) -> f64 {
+    let a = angle % TAU;
+    if a < 0. { a + TAU } else { a }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{cell::Cell, f32::consts::TAU};
+
+    use super::*;
+    use crate::{
+        motor::{BLDC, Fault, Foc},
+        planner::Planner,
+        util::Velocity,
+    };
+
+    type SimFoc<'a, I = ()> =
+        Foc<BLDC<SimEncoder<'a>, SimPhase<'a>, SimPhase<'a>, SimPhase<'a>, 7, SimClock<'a>>, I>;
+
+    /// Aligned FOC on a motor that starts at `angle`
+    fn foc(sim: &Simulator, angle: f32) -> SimFoc<'_> {
+        sim.set_angle(angle);
+        BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(sim.encoder())
+            .aligned(&mut sim.clock())
+            .unwrap()
+            .foc()
+    }
+
+    /// Tick at 10 kHz for `millis` ms
+    fn run<I: CurrentSensor>(sim: &Simulator, foc: &mut SimFoc<'_, I>, millis: u64) {
+        for _ in 0..millis * 10 {
+            sim.advance(Duration::micros(100));
+            foc.tick().unwrap();
+        }
+    }
+
+    fn total_angle<I>(foc: &SimFoc<'_, I>) -> f32 {
+        foc.sensor().state().total_angle()
+    }
+
+    /// Simulated encoder whose reads fail while `failing` is set
+    struct FlakyEncoder<'a> {
+        encoder: SimEncoder<'a>,
+        failing: &'a Cell<bool>,
+    }
+
+    impl SensorHardware for FlakyEncoder<'_> {
+        type Error = ();
+
+        fn read_angle(&mut self) -> Result<f32, Self::Error> {
+            match self.failing.get() {
+                true => Err(()),
+                false => Ok(self.encoder.read_angle().unwrap()),
+            }
+        }
+    }
+
+    #[test]
+    fn velocity() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 1.).to_velocity(5 * Velocity::RPS);
+
+        run(&sim, &mut foc, 2000);
+        assert!(
+            (sim.velocity() - 5. * TAU).abs() < 0.5,
+            "{}",
+            sim.velocity()
+        );
+    }
+
+    #[test]
+    fn quantized() {
+        // A 12-bit encoder like the AS5600
+        let sim = Simulator::new(SimParams::default());
+        sim.set_angle(1.);
+        let mut encoder = sim.encoder().with_resolution(4096);
+        let step = TAU / 4096.;
+        let angle = encoder.read_angle().unwrap();
+        assert!(angle <= 1. && angle > 1. - step, "{angle}");
+        assert_eq!(encoder.read_raw(), Ok((1. / step) as u32));
+
+        let mut foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(encoder)
+            .aligned(&mut sim.clock())
+            .unwrap()
+            .foc()
+            .to_velocity(5 * Velocity::RPS);
+        run(&sim, &mut foc, 2000);
+        assert!(
+            (sim.velocity() - 5. * TAU).abs() < 0.5,
+            "{}",
+            sim.velocity()
+        );
+    }
+
+    #[test]
+    fn angle() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 1.).to_angle(3.);
+
+        run(&sim, &mut foc, 2000);
+        assert!(
+            (total_angle(&foc) - 3.).abs() < 0.05,
+            "{}",
+            total_angle(&foc)
+        );
+        assert!(foc.is_move_done());
+    }
+
+    #[test]
+    fn planned_angle() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 1.).with_planner(Planner::new(20., 200.));
+        foc.move_to(-4., 0.);
+        assert!(!foc.is_move_done());
+
+        run(&sim, &mut foc, 2000);
+        assert!(
+            (total_angle(&foc) + 4.).abs() < 0.05,
+            "{}",
+            total_angle(&foc)
+        );
+        assert!(foc.is_move_done());
+    }
+
+    #[test]
+    fn voltage_torque() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 1.).to_torque(1.);
+
+        // Up to a bit under the speed where the back-EMF takes the whole volt
+        run(&sim, &mut foc, 500);
+        let params = sim.params();
+        let no_load = 1. / (params.flux_linkage() * params.pole_pairs as f32);
+        assert!(sim.velocity() > 0.8 * no_load && sim.velocity() < no_load);
+    }
+
+    #[test]
+    fn current_torque() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 1.)
+            .with_current_sensor(sim.current_sensor())
+            .to_torque(0.2);
+        // Hold the rotor about still
+        sim.set_load_torque(0.2 * sim.params().torque_constant());
+
+        run(&sim, &mut foc, 200);
+        assert!((sim.current().q - 0.2).abs() < 0.02, "{:?}", sim.current());
+        assert!(sim.current().d.abs() < 0.02, "{:?}", sim.current());
+    }
+
+    #[test]
+    fn haptic() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 0.).to_ratchet(5);
+
+        // Settles into the detent at 0
+        sim.set_angle(0.4);
+        run(&sim, &mut foc, 500);
+        assert!(total_angle(&foc).abs() < 0.1, "{}", total_angle(&foc));
+
+        // Pushed into the next one
+        sim.set_load_torque(-0.04);
+        run(&sim, &mut foc, 300);
+        sim.set_load_torque(0.);
+        run(&sim, &mut foc, 1000);
+
+        let event = foc.poll_haptic_event().unwrap();
+        assert!(event.index > 0);
+        let angle = total_angle(&foc);
+        let detent = angle / (TAU / 5.);
+        assert!((detent - detent.round()).abs() < 0.1, "{angle}");
+    }
+
+    #[test]
+    fn limit_pos() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 0.).to_limit_pos(-1., 1.);
+
+        // Free inside the range
+        run(&sim, &mut foc, 200);
+        assert_eq!(foc.voltage(), DQ::default());
+
+        // Pushed back past the end stop
+        sim.set_load_torque(-0.01);
+        run(&sim, &mut foc, 2000);
+        let angle = total_angle(&foc);
+        assert!(angle > 1. && angle < 1.3, "{angle}");
+    }
+
+    #[test]
+    fn limit_pos_bounds() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 0.).to_limit_pos(-1., 1.);
+
+        // Pushed along, free until past the high bound, then held back
+        sim.set_load_torque(-0.005);
+        for _ in 0..20_000 {
+            sim.advance(Duration::micros(100));
+            foc.tick().unwrap();
+
+            let angle = total_angle(&foc);
+            if angle < 1. {
+                assert_eq!(foc.voltage(), DQ::default(), "{angle}");
+            } else if angle > 1.05 && sim.velocity() >= 0. {
+                assert!(foc.voltage().q < 0., "{angle}: {:?}", foc.voltage());
+            }
+        }
+        let angle = total_angle(&foc);
+        assert!(angle > 1. && angle < 1.3, "{angle}");
+
+        // Across the range the other way, to the low bound
+        sim.set_load_torque(0.005);
+        run(&sim, &mut foc, 2000);
+        let angle = total_angle(&foc);
+        assert!(angle < -1. && angle > -1.3, "{angle}");
+    }
+
+    #[test]
+    fn limit_pos_keeps_gains() {
+        let sim = Simulator::new(SimParams::default());
+        let mut foc = foc(&sim, 1.)
+            .to_limit_pos(-1., 1.)
+            .to_velocity(5 * Velocity::RPS);
+
+        run(&sim, &mut foc, 2000);
+        assert!(
+            (sim.velocity() - 5. * TAU).abs() < 0.5,
+            "{}",
+            sim.velocity()
+        );
+    }
+
+    #[test]
+    fn sensor_loss() {
+        let sim = Simulator::new(SimParams::default());
+        let failing = Cell::new(false);
+        let encoder = FlakyEncoder {
+            encoder: sim.encoder(),
+            failing: &failing,
+        };
+        let mut foc = BLDC::new::<7>(sim.pwm())
+            .with_clock(sim.clock())
+            .with_sensor(encoder)
+            .aligned(&mut sim.clock())
+            .unwrap()
+            .foc()
+            .to_velocity(5 * Velocity::RPS);
+        let mut tick = || {
+            sim.advance(Duration::micros(100));
+            foc.tick().unwrap();
+        };
+        for _ in 0..5000 {
+            tick();
+        }
+        assert_ne!(sim.state.borrow().duty, [0; 3]);
+
+        // A few failed reads in a row keep the last output, one more trips
+        failing.set(true);
+        for _ in 0..4 {
+            tick();
+        }
+        assert_ne!(sim.state.borrow().duty, [0; 3]);
+        tick();
+        assert_eq!(sim.state.borrow().duty, [0; 3]);
+
+        // Latched even once the sensor reads again
+        failing.set(false);
+        for _ in 0..1000 {
+            tick();
+        }
+        assert_eq!(sim.state.borrow().duty, [0; 3]);
+        assert_eq!(foc.fault(), Some(Fault::SensorLoss));
+
+        foc.clear_fault();
+        for _ in 0..20_000 {
+            sim.advance(Duration::micros(100));
+            foc.tick().unwrap();
+        }
+        assert_eq!(foc.fault(), None);
+        assert!(
+            (sim.velocity() - 5. * TAU).abs() < 0.5,
+            "{}",
+            sim.velocity()
+        );
+    }
+}
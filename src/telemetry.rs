@@ -0,0 +1,470 @@
+//! Binary telemetry of the control loops
+//!
+//! Each [`Sample`] is sent as one frame: the version, a bitmask of the
+//! channels present, a timestamp in μs (wrapping every ~71 minutes), the value
+//! of each channel present as `f32`, and the CRC-32 of all of that, all little
+//! endian. The frame is COBS encoded, so it contains no zero byte, and is
+//! wrapped in zero bytes: the leading one closes whatever was sent before, so
+//! a log line doesn't spoil the next frame.
+//!
+//! On the chip, [`Telemetry`] writes frames to anything implementing
+//! [`embedded_io::Write`], e.g. the USB serial port. On the host, [`Decoder`]
+//! picks them out of the byte stream, skipping anything else on the line
+//! such as log output, and [`Sample::write_csv`] turns them into CSV rows.
+
+use core::fmt;
+
+use heapless::Vec;
+
+use crate::util::{Instant, crc32};
+
+/// Bumped whenever the layout of a frame changes
+pub const VERSION: u8 = 1;
+
+/// Longest encoded frame, with every channel present and both delimiters
+pub const MAX_FRAME: usize = 1 + 1 + MAX_PAYLOAD + 1;
+
+/// Version, mask, timestamp, values and CRC
+const MAX_PAYLOAD: usize = 1 + 2 + 4 + 4 * Channel::ALL.len() + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// Total angle in rad
+    Angle,
+
+    /// Velocity in rad/s
+    Velocity,
+
+    /// Target of the motion control mode, in its unit
+    Target,
+
+    /// Applied q voltage in V
+    VoltageQ,
+
+    /// Applied d voltage in V
+    VoltageD,
+
+    /// Measured Iq in A
+    CurrentQ,
+
+    /// Measured Id in A
+    CurrentD,
+
+    /// Proportional term of the velocity PID
+    PidP,
+
+    /// Integral term of the velocity PID
+    PidI,
+
+    /// Derivative term of the velocity PID
+    PidD,
+
+    /// Time between the last two sensor updates in s
+    Dt,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 11] = [
+        Channel::Angle,
+        Channel::Velocity,
+        Channel::Target,
+        Channel::VoltageQ,
+        Channel::VoltageD,
+        Channel::CurrentQ,
+        Channel::CurrentD,
+        Channel::PidP,
+        Channel::PidI,
+        Channel::PidD,
+        Channel::Dt,
+    ];
+
+    /// Column name in CSV exports
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Angle => "angle",
+            Channel::Velocity => "velocity",
+            Channel::Target => "target",
+            Channel::VoltageQ => "voltage_q",
+            Channel::VoltageD => "voltage_d",
+            Channel::CurrentQ => "current_q",
+            Channel::CurrentD => "current_d",
+            Channel::PidP => "pid_p",
+            Channel::PidI => "pid_i",
+            Channel::PidD => "pid_d",
+            Channel::Dt => "dt",
+        }
+    }
+
+    fn bit(self) -> u16 {
+        1 << self as u8
+    }
+}
+
+/// Values of some channels at one point in time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Timestamp in μs
+    pub timestamp: u32,
+    mask: u16,
+    values: [f32; Channel::ALL.len()],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not valid COBS, or longer than any frame
+    Framing,
+
+    /// Sent by a firmware with a different frame layout
+    Version(u8),
+
+    /// Too short for the channels it claims to carry
+    Length,
+
+    Checksum,
+}
+
+impl Sample {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            timestamp: now.ticks() as u32,
+            mask: 0,
+            values: [0.; Channel::ALL.len()],
+        }
+    }
+
+    pub fn with(mut self, channel: Channel, value: f32) -> Self {
+        self.set(channel, value);
+        self
+    }
+
+    pub fn set(&mut self, channel: Channel, value: f32) {
+        self.mask |= channel.bit();
+        self.values[channel as usize] = value;
+    }
+
+    pub fn get(&self, channel: Channel) -> Option<f32> {
+        (self.mask & channel.bit() != 0).then_some(self.values[channel as usize])
+    }
+
+    /// Encode into a frame, delimiters included
+    pub fn encode(&self) -> Vec<u8, MAX_FRAME> {
+        let mut payload = Vec::<u8, MAX_PAYLOAD>::new();
+        let mut push = |bytes: &[u8]| payload.extend_from_slice(bytes).unwrap();
+
+        push(&[VERSION]);
+        push(&self.mask.to_le_bytes());
+        push(&self.timestamp.to_le_bytes());
+        for channel in Channel::ALL {
+            if let Some(value) = self.get(channel) {
+                push(&value.to_le_bytes());
+            }
+        }
+        let crc = crc32(&payload);
+        payload.extend_from_slice(&crc.to_le_bytes()).unwrap();
+
+        let mut frame = Vec::new();
+        frame.push(0).unwrap();
+        frame
+            .extend_from_slice(&cobs_encode::<{ MAX_PAYLOAD + 1 }>(&payload))
+            .unwrap();
+        frame.push(0).unwrap();
+        frame
+    }
+
+    /// Decode a frame, without its delimiters
+    pub fn decode(frame: &[u8]) -> Result<Self, DecodeError> {
+        let payload = cobs_decode::<MAX_PAYLOAD>(frame).ok_or(DecodeError::Framing)?;
+
+        let (&version, rest) = payload.split_first().ok_or(DecodeError::Length)?;
+        if version != VERSION {
+            return Err(DecodeError::Version(version));
+        }
+
+        if payload.len() < 1 + 2 + 4 + 4 {
+            return Err(DecodeError::Length);
+        }
+        let (body, crc) = payload.split_at(payload.len() - 4);
+        if crc32(body) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            return Err(DecodeError::Checksum);
+        }
+
+        let mask = u16::from_le_bytes([rest[0], rest[1]]);
+        let timestamp = u32::from_le_bytes([rest[2], rest[3], rest[4], rest[5]]);
+        let values = &body[7..];
+        if mask >> Channel::ALL.len() != 0 || values.len() != 4 * mask.count_ones() as usize {
+            return Err(DecodeError::Length);
+        }
+        let mut values = values.chunks_exact(4);
+
+        let mut sample = Sample {
+            timestamp,
+            mask: 0,
+            values: [0.; Channel::ALL.len()],
+        };
+        for channel in Channel::ALL {
+            if mask & channel.bit() != 0 {
+                let value = values.next().unwrap();
+                sample.set(
+                    channel,
+                    f32::from_le_bytes([value[0], value[1], value[2], value[3]]),
+                );
+            }
+        }
+
+        Ok(sample)
+    }
+
+    /// Header row matching [`Sample::write_csv`]
+    pub fn write_csv_header<W: fmt::Write>(w: &mut W) -> fmt::Result {
+        w.write_str("timestamp")?;
+        for channel in Channel::ALL {
+            write!(w, ",{}", channel.name())?;
+        }
+        w.write_char('\n')
+    }
+
+    /// One CSV row, channels not present are left empty
+    pub fn write_csv<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        write!(w, "{}", self.timestamp)?;
+        for channel in Channel::ALL {
+            match self.get(channel) {
+                Some(value) => write!(w, ",{value}")?,
+                None => w.write_char(',')?,
+            }
+        }
+        w.write_char('\n')
+    }
+}
+
+/// Sends samples as frames over `W`
+pub struct Telemetry<W> {
+    writer: W,
+}
+
+impl<W: embedded_io::Write> Telemetry<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn send(&mut self, sample: &Sample) -> Result<(), W::Error> {
+        self.writer.write_all(&sample.encode())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Picks frames out of a byte stream
+#[derive(Debug, Default)]
+pub struct Decoder {
+    frame: Vec<u8, MAX_FRAME>,
+    overflow: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next byte, returns the frame it completes, if any
+    pub fn push(&mut self, byte: u8) -> Option<Result<Sample, DecodeError>> {
+        if byte != 0 {
+            self.overflow |= self.frame.push(byte).is_err();
+            return None;
+        }
+
+        // Back to back delimiters
+        if self.frame.is_empty() && !self.overflow {
+            return None;
+        }
+
+        let result = match self.overflow {
+            true => Err(DecodeError::Framing),
+            false => Sample::decode(&self.frame),
+        };
+        self.frame.clear();
+        self.overflow = false;
+
+        Some(result)
+    }
+}
+
+/// Consistent overhead byte stuffing: replace zeros with the distance to the
+/// next one
+fn cobs_encode<const N: usize>(data: &[u8]) -> Vec<u8, N> {
+    let mut out = Vec::new();
+    let mut code_at = 0;
+    out.push(0).unwrap();
+
+    for &byte in data {
+        if byte == 0 {
+            out[code_at] = (out.len() - code_at) as u8;
+            code_at = out.len();
+            out.push(0).unwrap();
+        } else {
+            out.push(byte).unwrap();
+            if out.len() - code_at == 0xFF {
+                out[code_at] = 0xFF;
+                code_at = out.len();
+                out.push(0).unwrap();
+            }
+        }
+    }
+    out[code_at] = (out.len() - code_at) as u8;
+
+    out
+}
+
+fn cobs_decode<const N: usize>(data: &[u8]) -> Option<Vec<u8, N>> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let code = data[i] as usize;
+        if code == 0 || i + code > data.len() {
+            return None;
+        }
+        out.extend_from_slice(&data[i + 1..i + code]).ok()?;
+        i += code;
+
+        if code != 0xFF && i < data.len() {
+            out.push(0).ok()?;
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn sample() -> Sample {
+        Channel::ALL.into_iter().enumerate().fold(
+            Sample::new(Instant::from_ticks(0x0102_0304)),
+            |sample, (i, channel)| sample.with(channel, i as f32 * 1.5 - 4.),
+        )
+    }
+
+    /// Feed `bytes`, returns every frame they complete
+    fn feed(decoder: &mut Decoder, bytes: &[u8]) -> Vec<Result<Sample, DecodeError>> {
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.push(byte))
+            .collect()
+    }
+
+    /// Frame of a raw payload, with a correct CRC
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let payload = [body, &crc32(body).to_le_bytes()].concat();
+        cobs_encode::<{ MAX_PAYLOAD + 1 }>(&payload).to_vec()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut decoder = Decoder::new();
+
+        let full = sample();
+        assert_eq!(feed(&mut decoder, &full.encode()), [Ok(full)]);
+
+        for channel in Channel::ALL {
+            let sample = Sample::new(Instant::from_ticks(7)).with(channel, -2.5);
+            let decoded = feed(&mut decoder, &sample.encode());
+            assert_eq!(decoded, [Ok(sample)], "{}", channel.name());
+            assert_eq!(decoded[0].unwrap().get(channel), Some(-2.5));
+        }
+
+        let empty = Sample::new(Instant::from_ticks(0));
+        assert_eq!(feed(&mut decoder, &empty.encode()), [Ok(empty)]);
+    }
+
+    #[test]
+    fn zero_bytes() {
+        // Zero timestamp and values, most of the payload is zeros
+        let sample = Channel::ALL
+            .into_iter()
+            .fold(Sample::new(Instant::from_ticks(0)), |sample, channel| {
+                sample.with(channel, 0.)
+            });
+        let encoded = sample.encode();
+        assert!(!encoded[1..encoded.len() - 1].contains(&0));
+        assert_eq!(feed(&mut Decoder::new(), &encoded), [Ok(sample)]);
+    }
+
+    #[test]
+    fn long_runs() {
+        for len in [253, 254, 255, 508, 600] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 255 + 1) as u8).collect();
+            let encoded = cobs_encode::<700>(&data);
+            assert!(!encoded.contains(&0));
+            assert_eq!(encoded[0] as usize, len.min(254) + 1);
+            assert_eq!(cobs_decode::<700>(&encoded).unwrap(), data[..], "{len}");
+        }
+
+        // A full run right before a zero
+        let data: Vec<u8> = (0..254).map(|_| 1).chain([0, 2]).collect();
+        let encoded = cobs_encode::<300>(&data);
+        assert_eq!(cobs_decode::<300>(&encoded).unwrap(), data[..]);
+    }
+
+    #[test]
+    fn flipped_bit() {
+        let encoded = sample().encode();
+        let payload = cobs_decode::<MAX_PAYLOAD>(&encoded[1..encoded.len() - 1]).unwrap();
+
+        for bit in 0..payload.len() * 8 {
+            let mut payload = payload.clone();
+            payload[bit / 8] ^= 1 << (bit % 8);
+            // Flipping the version is caught before the CRC
+            if bit < 8 {
+                continue;
+            }
+            let frame = cobs_encode::<{ MAX_PAYLOAD + 1 }>(&payload);
+            assert_eq!(Sample::decode(&frame), Err(DecodeError::Checksum), "{bit}");
+        }
+    }
+
+    #[test]
+    fn version() {
+        let body = [VERSION + 1, 0, 0, 0, 0, 0, 0];
+        assert_eq!(Sample::decode(&frame(&body)), Err(DecodeError::Version(2)));
+
+        let body = [VERSION, 0, 0, 0, 0, 0, 0];
+        assert!(Sample::decode(&frame(&body)).is_ok());
+    }
+
+    #[test]
+    fn resync() {
+        let sample = sample();
+        let encoded = sample.encode();
+        let mut decoder = Decoder::new();
+
+        // Log output before the frame is closed by its leading delimiter
+        let mut stream = b"INFO motor aligned".to_vec();
+        stream.extend_from_slice(&encoded);
+        let decoded = feed(&mut decoder, &stream);
+        assert!(matches!(decoded[..], [Err(_), Ok(_)]));
+        assert_eq!(decoded[1], Ok(sample));
+
+        // A frame cut short, the next one still decodes
+        let mut stream = encoded[..encoded.len() / 2].to_vec();
+        stream.extend_from_slice(&encoded);
+        let decoded = feed(&mut decoder, &stream);
+        assert!(matches!(decoded[..], [Err(_), Ok(_)]));
+        assert_eq!(decoded[1], Ok(sample));
+
+        // Garbage longer than any frame
+        let mut stream = [0xAA; 3 * MAX_FRAME].to_vec();
+        stream.extend_from_slice(&encoded);
+        let decoded = feed(&mut decoder, &stream);
+        assert_eq!(decoded, [Err(DecodeError::Framing), Ok(sample)]);
+    }
+}
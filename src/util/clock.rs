@@ -0,0 +1,34 @@
+/// Point in time with microsecond resolution
+pub type Instant = fugit::Instant<u64, 1, 1_000_000>;
+
+/// Time span with microsecond resolution
+pub type Duration = fugit::MicrosDurationU64;
+
+/// Source of time for the control loops
+///
+/// Keeps the control math independent from the timer of the target, so that
+/// it can run against a virtual clock on the host.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+impl<K: Clock> Clock for &K {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// The system timer of the chip
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SystemClock;
+
+#[cfg(target_arch = "xtensa")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::from_ticks(
+            esp_hal::time::Instant::now()
+                .duration_since_epoch()
+                .as_micros(),
+        )
+    }
+}
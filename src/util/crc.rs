@@ -0,0 +1,15 @@
+/// CRC-32 (IEEE 802.3) of `bytes`, the same checksum zlib and Ethernet use
+///
+/// Computed bit by bit, records checked with it are small enough that a table
+/// isn't worth the flash.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
@@ -1 +1 @@
-mod_use::mod_use![velocity, sampling];
+mod_use::mod_use![velocity, clock, crc, sine];
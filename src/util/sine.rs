@@ -0,0 +1,95 @@
+use core::f64::consts::FRAC_PI_2;
+
+use fixed::types::I16F16;
+
+/// Table entries per quarter turn
+const QUARTER: usize = 256;
+
+/// Table steps per rad, as `I16F16` bits
+const STEPS_PER_RAD: i64 = (QUARTER as f64 / FRAC_PI_2 * 65536. + 0.5) as i64;
+
+/// sin over a quarter turn, both ends included, as `I16F16` bits
+static SINE: [i32; QUARTER + 1] = sine_table();
+
+const fn sine_table() -> [i32; QUARTER + 1] {
+    let mut table = [0; QUARTER + 1];
+    let mut i = 0;
+    while i <= QUARTER {
+        let x = i as f64 * FRAC_PI_2 / QUARTER as f64;
+
+        // Taylor series, far below the resolution of I16F16 after 10 terms
+        let (mut term, mut sum) = (x, x);
+        let mut n = 1;
+        while n < 10 {
+            term = -term * x * x / ((2 * n) * (2 * n + 1)) as f64;
+            sum += term;
+            n += 1;
+        }
+
+        table[i] = (sum * 65536. + 0.5) as i32;
+        i += 1;
+    }
+    table
+}
+
+/// Sine and cosine of `angle` (in rad, any range) from a lookup table
+///
+/// A drop-in for `cordic::sin_cos` at the cost of 1 kB of flash, linearly
+/// interpolated between 1024 entries per turn, which is within two counts of
+/// `I16F16`. `src/bin/modulation-bench.rs` compares the two on the chip.
+pub fn sin_cos_lut(angle: I16F16) -> (I16F16, I16F16) {
+    let turn = (4 * QUARTER as i64) << 16;
+    let position = ((angle.to_bits() as i64 * STEPS_PER_RAD) >> 16).rem_euclid(turn);
+
+    (
+        I16F16::from_bits(sine(position)),
+        I16F16::from_bits(sine((position + ((QUARTER as i64) << 16)) % turn)),
+    )
+}
+
+/// sin at `position`, in table steps as `I16F16` bits
+fn sine(position: i64) -> i32 {
+    let (index, fraction) = ((position >> 16) as usize, position & 0xFFFF);
+    let (quadrant, i) = (index / QUARTER, index % QUARTER);
+
+    let (from, to) = match quadrant % 2 {
+        0 => (SINE[i], SINE[i + 1]),
+        _ => (SINE[QUARTER - i], SINE[QUARTER - i - 1]),
+    };
+    let value = from + (((to - from) as i64 * fraction) >> 16) as i32;
+
+    if quadrant < 2 { value } else { -value }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f64::consts::PI;
+
+    use num_traits::Float;
+
+    use super::*;
+
+    #[test]
+    fn error() {
+        // Linear interpolation is off by at most step²/8, then rounding
+        let step = FRAC_PI_2 / QUARTER as f64;
+        let bound = step * step / 8. + 2. / 65536.;
+
+        for i in -20_000..20_000 {
+            let angle = I16F16::from_num(i as f64 * 4. * PI / 20_000.);
+            let (sin, cos) = sin_cos_lut(angle);
+            let x = angle.to_num::<f64>();
+            let sin_error = (sin.to_num::<f64>() - Float::sin(x)).abs();
+            let cos_error = (cos.to_num::<f64>() - Float::cos(x)).abs();
+            assert!(sin_error <= bound, "sin {x}: {sin_error}");
+            assert!(cos_error <= bound, "cos {x}: {cos_error}");
+        }
+    }
+
+    #[test]
+    fn quadrants() {
+        let (sin, cos) = sin_cos_lut(I16F16::ZERO);
+        assert_eq!((sin, cos), (I16F16::ZERO, I16F16::ONE));
+        assert_eq!(SINE[QUARTER], 65536);
+    }
+}
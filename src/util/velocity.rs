@@ -4,7 +4,7 @@ use core::{
     ops::{Add, Div, Mul, Neg, Sub},
 };
 
-use esp_hal::time::Duration;
+use crate::util::Duration;
 
 /// Angular velocity in radians per second (rad/s)
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
@@ -129,7 +129,7 @@ pub struct VelocityBuilder(f32);
 
 impl VelocityBuilder {
     pub const fn per(self, duration: Duration) -> Velocity {
-        self.per_micro(duration.as_micros() as f32)
+        self.per_micro(duration.to_micros() as f32)
     }
 
     pub const fn per_sec(self, second: f32) -> Velocity {
@@ -148,3 +148,63 @@ impl VelocityBuilder {
         Velocity(self.0 / nanos * 1e9)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Velocity, b: f32) {
+        assert!(
+            (a.as_secs() - b).abs() < 1e-4 * b.abs().max(1.),
+            "{a} != {b}"
+        );
+    }
+
+    #[test]
+    fn units() {
+        assert_close(60 * Velocity::RPM, 2. * PI);
+        assert_close(Velocity::RPS, 2. * PI);
+        assert_close(Velocity::per_milli(1.), 1e3);
+        assert_close(Velocity::per_micro(1.), 1e6);
+        assert_eq!(Velocity::per_sec(2e3).as_millis(), 2.);
+        assert_eq!(Velocity::per_sec(2e6).as_micros(), 2.);
+    }
+
+    #[test]
+    fn builder() {
+        assert_close(Velocity::rad(1.).per(Duration::millis(10)), 100.);
+        assert_close(Velocity::degree(180.).per_sec(1.), PI);
+        assert_close(Velocity::rad(1.).per_milli(2.), 500.);
+        assert_close(Velocity::rad(1.).per_micro(4.), 250e3);
+        assert_close(Velocity::rad(1.).per_nano(1e3), 1e6);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let v = Velocity::per_sec(2.);
+        assert_close(v * 3u8, 6.);
+        assert_close(-2i32 * v, -4.);
+        assert_close(v * 0.5f64, 1.);
+        assert_close(v / 4., 0.5);
+        assert_close(v + v, 4.);
+        assert_close(v - 3 * v, -4.);
+        assert_close(-v, -2.);
+        assert!(Velocity::ZERO < v);
+    }
+
+    #[test]
+    fn clamp() {
+        let (min, max) = (Velocity::per_sec(-1.), Velocity::per_sec(1.));
+        assert_eq!(Velocity::per_sec(5.).clamp(min, max), max);
+        assert_eq!(Velocity::per_sec(-5.).clamp(min, max), min);
+        assert_eq!(
+            Velocity::per_sec(0.5).clamp(min, max),
+            Velocity::per_sec(0.5)
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", Velocity::RPS), "6.28rad/s");
+    }
+}
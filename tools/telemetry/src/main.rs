@@ -0,0 +1,46 @@
+//! Turns the telemetry stream of the firmware into CSV
+//!
+//! Reads from the file given as argument, e.g. the serial port in raw mode,
+//! or from stdin, and writes one row per sample to stdout. Anything that isn't
+//! a valid frame, like log lines, is skipped.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use playground::telemetry::{Decoder, Sample};
+
+fn main() -> io::Result<()> {
+    let input: Box<dyn Read> = match env::args().nth(1) {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin().lock()),
+    };
+
+    let mut output = BufWriter::new(io::stdout().lock());
+    let mut decoder = Decoder::new();
+    let mut row = String::new();
+    let (mut samples, mut skipped) = (0, 0);
+
+    Sample::write_csv_header(&mut row).unwrap();
+    output.write_all(row.as_bytes())?;
+
+    for byte in BufReader::new(input).bytes() {
+        match decoder.push(byte?) {
+            Some(Ok(sample)) => {
+                row.clear();
+                sample.write_csv(&mut row).unwrap();
+                output.write_all(row.as_bytes())?;
+                samples += 1;
+            }
+            Some(Err(_)) => skipped += 1,
+            None => {}
+        }
+    }
+
+    output.flush()?;
+    eprintln!("{samples} samples, {skipped} frames skipped");
+
+    Ok(())
+}